@@ -0,0 +1,16 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+// The blurred bright parts, at half size
+uniform sampler2D Bloom;
+uniform float Intensity;
+
+out vec4 outColor;
+
+void main()
+{
+    vec4 color = texture(Source, UV);
+    outColor = vec4(color.rgb + Intensity * texture(Bloom, UV).rgb, color.a);
+}
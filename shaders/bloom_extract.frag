@@ -0,0 +1,21 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+// How bright a color has to be to bloom
+uniform float Threshold;
+
+out vec4 outColor;
+
+void main()
+{
+    vec3 color = texture(Source, UV).rgb;
+    float brightness = max(color.r, max(color.g, color.b));
+    // Only what is above the threshold, eased in so there's no hard edge where it starts
+    float knee = 0.5 * Threshold;
+    float soft = clamp(brightness - Threshold + knee, 0., 2. * knee);
+    soft = soft * soft / (4. * knee + 0.0001);
+    float contribution = max(soft, brightness - Threshold) / max(brightness, 0.0001);
+    outColor = vec4(color * contribution, 1.);
+}
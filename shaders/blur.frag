@@ -0,0 +1,23 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+// One texel along the direction to blur in
+uniform vec2 Step;
+
+out vec4 outColor;
+
+// A 9 tap gaussian in 5 lookups, each filtered between two texels
+const float OFFSETS[3] = float[](0., 1.3846153846, 3.2307692308);
+const float WEIGHTS[3] = float[](0.2270270270, 0.3162162162, 0.0702702703);
+
+void main()
+{
+    vec3 color = texture(Source, UV).rgb * WEIGHTS[0];
+    for (int i = 1; i < 3; i++) {
+        color += texture(Source, UV + Step * OFFSETS[i]).rgb * WEIGHTS[i];
+        color += texture(Source, UV - Step * OFFSETS[i]).rgb * WEIGHTS[i];
+    }
+    outColor = vec4(color, 1.);
+}
@@ -0,0 +1,19 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+// Where every color ends up, indexed by red, green and blue, see post.rs
+uniform sampler3D Lut;
+uniform float LutSize;
+
+out vec4 outColor;
+
+void main()
+{
+    vec4 color = texture(Source, UV);
+    // From [0, 1] to between the centers of the first and last texels, so the ends aren't blended
+    // with the border
+    vec3 coords = clamp(color.rgb, 0., 1.) * (LutSize - 1.) / LutSize + 0.5 / LutSize;
+    outColor = vec4(texture(Lut, coords).rgb, color.a);
+}
@@ -0,0 +1,45 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+uniform vec2 TexelSize;
+
+out vec4 outColor;
+
+const float REDUCE_MIN = 1. / 128.;
+const float REDUCE_MUL = 1. / 8.;
+const float SPAN_MAX = 8.;
+
+float luma(vec3 color)
+{
+    return dot(color, vec3(0.299, 0.587, 0.114));
+}
+
+// FXAA in its simplest form: blurs along edges found from the luma of the neighbours
+void main()
+{
+    vec4 center = texture(Source, UV);
+    float luma_nw = luma(texture(Source, UV + vec2(-1., -1.) * TexelSize).rgb);
+    float luma_ne = luma(texture(Source, UV + vec2(1., -1.) * TexelSize).rgb);
+    float luma_sw = luma(texture(Source, UV + vec2(-1., 1.) * TexelSize).rgb);
+    float luma_se = luma(texture(Source, UV + vec2(1., 1.) * TexelSize).rgb);
+    float luma_m = luma(center.rgb);
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Along the edge, perpendicular to the luma's gradient
+    vec2 direction = vec2(-((luma_nw + luma_ne) - (luma_sw + luma_se)), (luma_nw + luma_sw) - (luma_ne + luma_se));
+    float reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    float scale = 1. / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, -SPAN_MAX, SPAN_MAX) * TexelSize;
+
+    vec3 near = 0.5 * (texture(Source, UV + direction * (1. / 3. - 0.5)).rgb
+        + texture(Source, UV + direction * (2. / 3. - 0.5)).rgb);
+    vec3 far = near * 0.5 + 0.25 * (texture(Source, UV - direction * 0.5).rgb
+        + texture(Source, UV + direction * 0.5).rgb);
+    // Reaching too far picks up colors from across the edge
+    float luma_far = luma(far);
+    vec3 color = (luma_far < luma_min || luma_far > luma_max) ? near : far;
+    outColor = vec4(color, center.a);
+}
@@ -0,0 +1,18 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+
+out vec4 outColor;
+
+vec3 linear_to_srgb(vec3 c)
+{
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1. / 2.4)) - 0.055, step(0.0031308, c));
+}
+
+void main()
+{
+    vec4 color = texture(Source, UV);
+    outColor = vec4(linear_to_srgb(clamp(color.rgb, 0., 1.)), color.a);
+}
@@ -0,0 +1,11 @@
+#version 430 core
+
+out vec2 UV;
+
+void main()
+{
+    // A triangle covering the whole screen, made from the vertex index alone
+    vec2 position = vec2(float((gl_VertexID & 1) << 2) - 1., float((gl_VertexID & 2) << 1) - 1.);
+    gl_Position = vec4(position, 0., 1.);
+    UV = position * 0.5 + 0.5;
+}
@@ -0,0 +1,11 @@
+#version 430 core
+
+// Lines and points don't cast shadows
+uniform bool Unlit;
+
+void main()
+{
+    if (Unlit) {
+        discard;
+    }
+}
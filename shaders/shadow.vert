@@ -0,0 +1,14 @@
+#version 430 core
+
+layout(location = 0) in vec3 VertexPosition;
+
+// The light's view and projection for the cascade being drawn
+uniform mat4 ViewProjectionMatrix;
+uniform mat4 SceneTransfrom;
+uniform float PointSize;
+
+void main()
+{
+    gl_Position = ViewProjectionMatrix * SceneTransfrom * vec4(VertexPosition, 1.);
+    gl_PointSize = PointSize;
+}
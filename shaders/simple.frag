@@ -2,27 +2,279 @@
 
 smooth in vec4 theColor;
 smooth in vec3 N;
-smooth in vec3 L;
-smooth in vec3 V;
-smooth in vec3 R;
+smooth in vec2 UV;
+smooth in vec3 T;
+smooth in vec3 B;
+smooth in vec3 WorldPosition;
 
-vec4 tmp;
+uniform bool UseNormalMap;
+uniform sampler2D NormalMap;
+// Set for lines and points, which are drawn in their plain color
+uniform bool Unlit;
+uniform vec3 CameraPosition;
 
-float kd = 0.8;
-float diffuse_gain;
+// Every light that reaches the scene, in world space. Laid out like light_data in light.rs.
+struct Light {
+    vec4 position_kind; // w is 0 for directional, 1 for point and 2 for spot lights
+    vec4 direction_cos_inner; // w is the cosine of the spot's inner angle
+    vec4 color_cos_outer; // w is the cosine of the spot's outer angle
+    vec4 attenuation; // constant, linear and quadratic falloff
+};
+layout(std430, binding = 0) readonly buffer Lights {
+    Light lights[];
+};
+uniform int LightCount;
+uniform vec3 AmbientLight;
+// Index of the light the shadow maps are drawn from, -1 for none
+uniform int ShadowLight;
+
+// Cascaded shadow maps side by side in one texture, the nearest cascade first
+const int MAX_CASCADES = 4;
+uniform bool UseShadows;
+uniform sampler2DShadow ShadowMap;
+uniform int CascadeCount;
+uniform mat4 ShadowMatrices[MAX_CASCADES];
+uniform float ShadowBias;
+
+// How the surface reflects the lights, see material.rs
+uniform float MaterialAmbient;
+uniform float MaterialDiffuse;
+uniform float MaterialSpecular;
+uniform float MaterialShininess;
+uniform bool BlinnPhong;
+// How much of the sky the surface mirrors
+uniform float MaterialReflectivity;
+
+// The glTF metallic-roughness material, used instead of the above when UsePbr is set
+uniform bool UsePbr;
+uniform vec4 BaseColorFactor;
+uniform float MetallicFactor;
+uniform float RoughnessFactor;
+uniform float OcclusionStrength;
+uniform vec3 EmissiveFactor;
+uniform bool UseBaseColorTexture;
+uniform sampler2D BaseColorTexture;
+uniform bool UseMetallicRoughnessTexture;
+uniform sampler2D MetallicRoughnessTexture;
+uniform bool UseOcclusionTexture;
+uniform sampler2D OcclusionTexture;
+uniform bool UseEmissiveTexture;
+uniform sampler2D EmissiveTexture;
+
+// Light from the environment for the physically based path, see environment.rs
+uniform vec3 IrradianceSH[9];
+uniform samplerCube SpecularMap;
+uniform float SpecularMaxLevel;
+uniform sampler2D BrdfLut;
+// The sky at full resolution, for sharp reflections, see skybox.rs
+uniform samplerCube Sky;
+
+// Set when the picture is post-processed, then colors are written linear and unclamped for
+// the post-processing to tone map. Otherwise they are encoded for the screen.
+uniform bool LinearOutput;
 
+const float PI = 3.14159265;
+
+float diffuse_gain;
 float specular_gain;
-float ks = 0.3;
-float alpha = 20.;
 
-float ambient = 0.1;
 out vec4 outColor;
+
+// 1 where the light reaches, 0 in full shadow
+float shadow_factor(vec3 n, vec3 L)
+{
+    if (!UseShadows) {
+        return 1.;
+    }
+    vec2 texel = 1. / vec2(textureSize(ShadowMap, 0));
+    for (int cascade = 0; cascade < CascadeCount; cascade++) {
+        vec4 light_space = ShadowMatrices[cascade] * vec4(WorldPosition, 1.);
+        vec3 coords = light_space.xyz / light_space.w * 0.5 + 0.5;
+        // Stay away from the edges, so the filter doesn't reach into the next cascade
+        float margin = 2. * texel.x * float(CascadeCount);
+        if (any(lessThan(coords.xy, vec2(margin))) || any(greaterThan(coords.xyz, vec3(1. - margin)))) {
+            continue;
+        }
+        // Surfaces facing away from the light need more bias against acne
+        float bias = ShadowBias * float(cascade + 1) * (2. - max(dot(n, L), 0.));
+        vec2 atlas = vec2((float(cascade) + coords.x) / float(CascadeCount), coords.y);
+
+        // 3x3 percentage closer filtering, every lookup is itself filtered between 4 texels
+        float lit = 0.;
+        for (int x = -1; x <= 1; x++) {
+            for (int y = -1; y <= 1; y++) {
+                lit += texture(ShadowMap, vec3(atlas + vec2(x, y) * texel, coords.z - bias));
+            }
+        }
+        return lit / 9.;
+    }
+    // Further away than the shadows reach
+    return 1.;
+}
+
+// The light reaching this fragment from the ith light, and the direction towards it in L
+vec3 incoming_light(int i, vec3 n, out vec3 L)
+{
+    Light l = lights[i];
+    int kind = int(l.position_kind.w);
+    L = -l.direction_cos_inner.xyz;
+    float attenuation = 1.;
+    if (kind != 0) {
+        vec3 to_light = l.position_kind.xyz - WorldPosition;
+        float d = length(to_light);
+        L = to_light / d;
+        attenuation = 1. / dot(l.attenuation.xyz, vec3(1., d, d * d));
+        if (kind == 2) {
+            float cos_angle = dot(-L, l.direction_cos_inner.xyz);
+            attenuation *= smoothstep(l.color_cos_outer.w, l.direction_cos_inner.w, cos_angle);
+        }
+    }
+    if (i == ShadowLight) {
+        attenuation *= shadow_factor(n, L);
+    }
+    return attenuation * l.color_cos_outer.rgb;
+}
+
+vec3 srgb_to_linear(vec3 c)
+{
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(0.04045, c));
+}
+
+vec3 linear_to_srgb(vec3 c)
+{
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1. / 2.4)) - 0.055, step(0.0031308, c));
+}
+
+vec3 shade_phong(vec3 n, vec3 V)
+{
+    vec3 light = MaterialAmbient * AmbientLight;
+    for (int i = 0; i < LightCount; i++) {
+        vec3 L;
+        vec3 radiance = incoming_light(i, n, L);
+        diffuse_gain = max(dot(n, L), 0.);
+        if (BlinnPhong) {
+            specular_gain = pow(max(dot(n, normalize(L + V)), 0.), MaterialShininess);
+        } else {
+            specular_gain = pow(max(dot(reflect(-L, n), V), 0.), MaterialShininess);
+        }
+        // No highlights on the side facing away from the light
+        specular_gain *= float(diffuse_gain > 0.);
+        light += radiance * (MaterialDiffuse * diffuse_gain + MaterialSpecular * specular_gain);
+    }
+    // The sky is linear, the Phong colors are already encoded for the screen
+    vec3 reflected = linear_to_srgb(min(texture(Sky, reflect(-V, n)).rgb, 1.));
+    return mix(theColor.rgb * min(light, 1.), reflected, MaterialReflectivity);
+}
+
+// The environment's diffuse light on a white surface facing n
+vec3 irradiance(vec3 n)
+{
+    return IrradianceSH[0] * 0.282095
+        + IrradianceSH[1] * 0.488603 * n.y
+        + IrradianceSH[2] * 0.488603 * n.z
+        + IrradianceSH[3] * 0.488603 * n.x
+        + IrradianceSH[4] * 1.092548 * n.x * n.y
+        + IrradianceSH[5] * 1.092548 * n.y * n.z
+        + IrradianceSH[6] * 0.315392 * (3. * n.z * n.z - 1.)
+        + IrradianceSH[7] * 1.092548 * n.x * n.z
+        + IrradianceSH[8] * 0.546274 * (n.x * n.x - n.y * n.y);
+}
+
+float distribution_ggx(float n_dot_h, float roughness)
+{
+    float a2 = pow(roughness, 4.);
+    float d = n_dot_h * n_dot_h * (a2 - 1.) + 1.;
+    return a2 / (PI * d * d);
+}
+
+// Smith's geometry term over 4 n.l n.v, with Schlick's approximation
+float visibility_smith(float n_dot_v, float n_dot_l, float roughness)
+{
+    float k = (roughness + 1.) * (roughness + 1.) / 8.;
+    float g_v = n_dot_v / (n_dot_v * (1. - k) + k);
+    float g_l = n_dot_l / (n_dot_l * (1. - k) + k);
+    return g_v * g_l / (4. * n_dot_v * n_dot_l);
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0)
+{
+    return f0 + (1. - f0) * pow(1. - cos_theta, 5.);
+}
+
+// Cook-Torrance with GGX, lit by the lights and the environment. Returns linear color.
+vec4 shade_pbr(vec3 n, vec3 V)
+{
+    vec4 base_color = BaseColorFactor * vec4(srgb_to_linear(theColor.rgb), theColor.a);
+    if (UseBaseColorTexture) {
+        base_color *= texture(BaseColorTexture, UV);
+    }
+    float metallic = MetallicFactor;
+    float roughness = RoughnessFactor;
+    if (UseMetallicRoughnessTexture) {
+        vec4 metallic_roughness = texture(MetallicRoughnessTexture, UV);
+        roughness *= metallic_roughness.g;
+        metallic *= metallic_roughness.b;
+    }
+    // Perfectly smooth surfaces would have infinitely small highlights
+    roughness = clamp(roughness, 0.04, 1.);
+    vec3 f0 = mix(vec3(0.04), base_color.rgb, metallic);
+    vec3 diffuse_color = base_color.rgb * (1. - metallic);
+    float n_dot_v = max(dot(n, V), 0.0001);
+
+    vec3 color = vec3(0.);
+    for (int i = 0; i < LightCount; i++) {
+        vec3 L;
+        // Light colors are how bright they make a white diffuse surface facing them, like in
+        // the Phong path, which is pi times their radiance
+        vec3 radiance = PI * incoming_light(i, n, L);
+        float n_dot_l = dot(n, L);
+        if (n_dot_l <= 0.) {
+            continue;
+        }
+        vec3 H = normalize(L + V);
+        vec3 F = fresnel_schlick(max(dot(H, V), 0.), f0);
+        vec3 specular = distribution_ggx(max(dot(n, H), 0.), roughness) * visibility_smith(n_dot_v, n_dot_l, roughness) * F;
+        color += ((1. - F) * diffuse_color / PI + specular) * radiance * n_dot_l;
+    }
+
+    // The environment, with the split sum approximation for the specular part
+    vec3 F = f0 + (max(vec3(1. - roughness), f0) - f0) * pow(1. - n_dot_v, 5.);
+    vec3 diffuse = (1. - F) * diffuse_color * irradiance(n);
+    vec3 prefiltered = textureLod(SpecularMap, reflect(-V, n), roughness * SpecularMaxLevel).rgb;
+    vec2 brdf = texture(BrdfLut, vec2(n_dot_v, roughness)).rg;
+    vec3 ambient = diffuse + prefiltered * (f0 * brdf.x + brdf.y);
+    if (UseOcclusionTexture) {
+        ambient *= mix(1., texture(OcclusionTexture, UV).r, OcclusionStrength);
+    }
+    color += ambient;
+
+    vec3 emissive = EmissiveFactor;
+    if (UseEmissiveTexture) {
+        emissive *= texture(EmissiveTexture, UV).rgb;
+    }
+    return vec4(color + emissive, base_color.a);
+}
+
 void main()
 {   
-    diffuse_gain = max(dot(N, L), 0.);
-    specular_gain = pow(max(dot(R, V), 0.), alpha);
+    if (Unlit) {
+        outColor = LinearOutput ? vec4(srgb_to_linear(theColor.rgb), theColor.a) : theColor;
+        return;
+    }
+    vec3 n = normalize(N);
+    if (UseNormalMap) {
+        // Tangent space normal from the map, remapped from [0, 1] to [-1, 1]
+        vec3 mapped = texture(NormalMap, UV).xyz * 2. - 1.;
+        n = normalize(mat3(normalize(T), normalize(B), n) * mapped);
+    }
+    vec3 V = normalize(CameraPosition - WorldPosition);
 
-    tmp = theColor * min(ambient + kd * diffuse_gain + ks * specular_gain, 1.);
-    tmp[3] = theColor[3];
-    outColor = tmp;
-}
\ No newline at end of file
+    if (UsePbr) {
+        // Lit in linear color, encoded for the screen like the Phong colors already are
+        vec4 color = shade_pbr(n, V);
+        outColor = vec4(LinearOutput ? color.rgb : linear_to_srgb(min(color.rgb, 1.)), color.a);
+        return;
+    }
+    vec3 color = shade_phong(n, V);
+    outColor = vec4(LinearOutput ? srgb_to_linear(color) : color, theColor.a);
+}
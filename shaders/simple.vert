@@ -3,21 +3,27 @@
 layout(location = 0) in vec3 VertexPosition;
 layout(location = 1) in vec4 vertex_color;
 layout(location = 2) in vec3 vertex_normal;
+layout(location = 3) in vec2 vertex_uv;
+layout(location = 4) in vec4 vertex_tangent; // w is the bitangent sign
 
 
 // uniform mat4 CameraTranslation;
 uniform mat4 ViewProjectionMatrix;
 uniform mat4 SceneTransfrom;
-uniform vec3 CameraPosition;
-uniform vec3 LightSource;
+// Transforms normals to world space, the transpose of the inverse of SceneTransfrom's rotation and scale
+uniform mat3 NormalMatrix;
+uniform float PointSize;
+// Distance at which points are PointSize pixels big, 0 keeps them that size at any distance
+uniform float PointReferenceDistance;
 
 // mat4 ViewProjection = CameraIntrisinc * CameraTranslation;
 
-out vec4 theColor;
-out vec3 N;
-out vec3 L;
-out vec3 V;
-out vec3 R;
+smooth out vec4 theColor;
+smooth out vec3 N;
+smooth out vec2 UV;
+smooth out vec3 T;
+smooth out vec3 B;
+smooth out vec3 WorldPosition;
 
 mat4 matrix = mat4(
    1.0, 0.0, 0.0, 0.0, // first column (not row!)
@@ -28,12 +34,21 @@ mat4 matrix = mat4(
 
 void main()
 {
-    gl_Position = ViewProjectionMatrix *  SceneTransfrom * vec4(VertexPosition, 1.);
+    WorldPosition = vec3(SceneTransfrom * vec4(VertexPosition, 1.));
+    gl_Position = ViewProjectionMatrix * vec4(WorldPosition, 1.);
+    gl_PointSize = PointSize;
+    if (PointReferenceDistance > 0.) {
+        gl_PointSize = clamp(PointSize * PointReferenceDistance / gl_Position.w, 1., 64.);
+    }
 
     theColor = vertex_color;
     // theNormal = vec3(ViewProjection *  vec4(vertex_normal, 0.));
-    N = normalize(vec3(SceneTransfrom * vec4(vertex_normal, 0.)));
-    L =  normalize(LightSource - VertexPosition);
-    V = normalize(CameraPosition - VertexPosition);
-    R = 2 * dot(L, N) * N - L;
+    N = normalize(NormalMatrix * vertex_normal);
+
+    UV = vertex_uv;
+    // Tangents lie in the surface, so they transform like positions. Made perpendicular to the
+    // normal again, which non-uniform scaling undoes.
+    T = mat3(SceneTransfrom) * vertex_tangent.xyz;
+    T = normalize(T - dot(T, N) * N);
+    B = vertex_tangent.w * cross(N, T);
 }
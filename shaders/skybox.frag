@@ -0,0 +1,24 @@
+#version 430 core
+
+in vec3 Direction;
+
+uniform samplerCube Sky;
+// Set when the picture is post-processed, which wants the sky as it is
+uniform bool LinearOutput;
+
+out vec4 outColor;
+
+vec3 linear_to_srgb(vec3 c)
+{
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1. / 2.4)) - 0.055, step(0.0031308, c));
+}
+
+void main()
+{
+    vec3 color = texture(Sky, Direction).rgb;
+    if (LinearOutput) {
+        outColor = vec4(color, 1.);
+        return;
+    }
+    outColor = vec4(linear_to_srgb(min(color, 1.)), 1.);
+}
@@ -0,0 +1,16 @@
+#version 430 core
+
+// Undoes the projection and the camera's rotation, from clip space to a direction in the world
+uniform mat4 InverseViewProjection;
+
+out vec3 Direction;
+
+void main()
+{
+    // A triangle covering the whole screen, made from the vertex index alone
+    vec2 position = vec2(float((gl_VertexID & 1) << 2) - 1., float((gl_VertexID & 2) << 1) - 1.);
+    // On the far plane, behind everything else
+    gl_Position = vec4(position, 1., 1.);
+    vec4 far = InverseViewProjection * vec4(position, 1., 1.);
+    Direction = far.xyz / far.w;
+}
@@ -0,0 +1,21 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+// What the colors are multiplied with first, brighter above 1
+uniform float Exposure;
+
+out vec4 outColor;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve, from [0, inf) to [0, 1]
+vec3 aces(vec3 x)
+{
+    return clamp(x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14), 0., 1.);
+}
+
+void main()
+{
+    vec4 color = texture(Source, UV);
+    outColor = vec4(aces(color.rgb * Exposure), color.a);
+}
@@ -0,0 +1,17 @@
+#version 430 core
+
+in vec2 UV;
+
+uniform sampler2D Source;
+// How dark the corners get, from 0 for not at all to 1 for black
+uniform float Strength;
+
+out vec4 outColor;
+
+void main()
+{
+    vec4 color = texture(Source, UV);
+    // 0 in the middle, 1 in the corners
+    float d = length(UV - 0.5) * sqrt(2.);
+    outColor = vec4(color.rgb * (1. - Strength * smoothstep(0.4, 1., d)), color.a);
+}
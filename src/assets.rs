@@ -0,0 +1,171 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A CPU side asset that is being loaded on a worker thread. The render thread takes it out
+/// once it is ready, and does whatever GL uploading it needs from there.
+pub struct AssetHandle<T> {
+    name: String,
+    slot: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> AssetHandle<T> {
+    #[allow(dead_code)]
+    pub fn is_ready(&self) -> bool {
+        self.slot.lock().map(|slot| slot.is_some()).unwrap_or(false)
+    }
+
+    /// The loaded asset, if it is done. It can only be taken once.
+    pub fn take(&self) -> Option<T> {
+        self.slot.lock().ok()?.take()
+    }
+
+    /// Takes the asset, panicking if it isn't done yet
+    pub fn expect(&self) -> T {
+        self.take().unwrap_or_else(|| panic!("Asset {} has not finished loading", self.name))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoadingProgress {
+    pub total: usize,
+    pub finished: usize,
+    // Names of the assets currently being worked on
+    pub loading: Vec<String>,
+    // Names of the assets whose loader panicked, along with the panic message
+    pub failed: Vec<(String, String)>,
+}
+
+impl LoadingProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 { return 1.0 }
+        self.finished as f32 / self.total as f32
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished + self.failed.len() == self.total
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+/// Runs asset loaders, i.e. OBJ parsing, mesh processing and image decoding, on a small pool
+/// of worker threads, so the render thread stays free to draw a loading screen
+pub struct AssetManager {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    progress: Arc<Mutex<LoadingProgress>>,
+}
+
+impl AssetManager {
+    pub fn new(worker_count: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..worker_count.max(1)).map(|i| {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("asset worker {}", i))
+                .spawn(move || loop {
+                    // The lock is released again before the job runs, so the other workers can pick up jobs
+                    let job = match receiver.lock().map(|r| r.recv()) {
+                        Ok(Ok(job)) => job,
+                        _ => break,
+                    };
+                    job();
+                })
+                .expect("Failed to spawn asset worker thread")
+        }).collect();
+
+        AssetManager { sender: Some(sender), workers, progress: Arc::new(Mutex::new(LoadingProgress::default())) }
+    }
+
+    /// Queues `loader` to run on a worker thread. Panics inside it are caught and reported
+    /// through `progress`, rather than taking down the worker.
+    pub fn load<T, F>(&mut self, name: &str, loader: F) -> AssetHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let handle = AssetHandle { name: name.to_string(), slot: Arc::new(Mutex::new(None)) };
+        let slot = Arc::clone(&handle.slot);
+        let progress = Arc::clone(&self.progress);
+        let name = name.to_string();
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.total += 1;
+        }
+
+        let job: Job = Box::new(move || {
+            if let Ok(mut progress) = progress.lock() {
+                progress.loading.push(name.clone());
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(loader));
+            if let Ok(mut progress) = progress.lock() {
+                progress.loading.retain(|n| *n != name);
+                match result {
+                    Ok(asset) => {
+                        if let Ok(mut slot) = slot.lock() {
+                            *slot = Some(asset);
+                        }
+                        progress.finished += 1;
+                    }
+                    Err(payload) => progress.failed.push((name, panic_message(payload))),
+                }
+            }
+        });
+        self.sender.as_ref()
+            .expect("Asset manager has shut down")
+            .send(job)
+            .expect("All asset workers have stopped");
+        handle
+    }
+
+    pub fn progress(&self) -> LoadingProgress {
+        self.progress.lock().map(|progress| progress.clone()).unwrap_or_default()
+    }
+}
+
+impl Drop for AssetManager {
+    fn drop(&mut self) {
+        // Closing the channel makes the workers stop once the queued jobs are done
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Draws a progress bar in the middle of the screen. Only uses scissored clears, so it works
+/// before any shaders or meshes are set up.
+pub unsafe fn draw_loading_screen(progress: &LoadingProgress, screen_width: u32, screen_height: u32) {
+    let (bar_width, bar_height) = (screen_width as i32 * 3 / 5, (screen_height as i32 / 30).max(4));
+    let (x, y) = ((screen_width as i32 - bar_width) / 2, (screen_height as i32 - bar_height) / 2);
+    let filled = (bar_width as f32 * progress.fraction()) as i32;
+
+    gl::ClearColor(0.05, 0.05, 0.06, 1.0);
+    gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+    gl::Enable(gl::SCISSOR_TEST);
+    gl::Scissor(x - 2, y - 2, bar_width + 4, bar_height + 4);
+    gl::ClearColor(0.5, 0.5, 0.5, 1.0);
+    gl::Clear(gl::COLOR_BUFFER_BIT);
+    gl::Scissor(x, y, bar_width, bar_height);
+    gl::ClearColor(0.1, 0.1, 0.12, 1.0);
+    gl::Clear(gl::COLOR_BUFFER_BIT);
+    if filled > 0 {
+        gl::Scissor(x, y, filled, bar_height);
+        gl::ClearColor(0.85, 0.85, 0.8, 1.0);
+        gl::Clear(gl::COLOR_BUFFER_BIT);
+    }
+    gl::Disable(gl::SCISSOR_TEST);
+}
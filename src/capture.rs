@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::render_target::image_from_gl_pixels;
+
+// Reading back frames without waiting on the GPU. ReadPixels into a pixel buffer object returns
+// right away, and the pixels are mapped a frame or two later once a fence says they are there.
+// PNG encoding is slow, so that happens on a thread of its own.
+
+// How many readbacks can be in flight before capturing has to wait for the oldest one
+const PBO_COUNT: usize = 3;
+// How many read back frames can queue up for the writer before capturing has to wait for it
+const WRITE_QUEUE: usize = 8;
+
+struct PixelsToWrite {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+struct Readback {
+    slot: usize,
+    fence: gl::types::GLsync,
+    path: PathBuf,
+    width: u32,
+    height: u32,
+}
+
+/// Saves what was rendered as PNG files, see `capture`
+pub struct FrameCapture {
+    pbos: [u32; PBO_COUNT],
+    // Bytes allocated for every PBO
+    pbo_sizes: [usize; PBO_COUNT],
+    next_slot: usize,
+    in_flight: VecDeque<Readback>,
+    writer: Option<SyncSender<PixelsToWrite>>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+fn write_images(receiver: Receiver<PixelsToWrite>) {
+    for image in receiver {
+        let result = image
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .map_err(|e| e.to_string())
+            .and_then(|()| {
+                image_from_gl_pixels(image.width, image.height, &image.pixels)
+                    .save(&image.path)
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            println!("Warning: failed to save {}: {}", image.path.display(), e);
+        }
+    }
+}
+
+/// A name that sorts in the order things were made, e.g. for screenshots
+pub fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}_{:03}", now.as_secs(), now.subsec_millis())
+}
+
+impl FrameCapture {
+    pub unsafe fn new() -> Self {
+        let mut pbos = [0; PBO_COUNT];
+        gl::GenBuffers(PBO_COUNT as i32, pbos.as_mut_ptr());
+        let (writer, receiver) = mpsc::sync_channel(WRITE_QUEUE);
+        let writer_thread = thread::Builder::new()
+            .name("image writer".to_string())
+            .spawn(move || write_images(receiver))
+            .expect("failed to spawn the image writer thread");
+        FrameCapture {
+            pbos,
+            pbo_sizes: [0; PBO_COUNT],
+            next_slot: 0,
+            in_flight: VecDeque::new(),
+            writer: Some(writer),
+            writer_thread: Some(writer_thread),
+        }
+    }
+
+    /// Starts reading back `framebuffer`, 0 being the window, to be saved at `path` once the
+    /// pixels arrive. Call this after drawing and before swapping buffers.
+    pub unsafe fn capture(&mut self, framebuffer: u32, width: u32, height: u32, path: &Path) {
+        if self.in_flight.len() == PBO_COUNT {
+            self.finish_oldest(true);
+        }
+        let slot = self.next_slot;
+        self.next_slot = (slot + 1) % PBO_COUNT;
+
+        let size = width as usize * height as usize * 4;
+        gl::BindBuffer(gl::PIXEL_PACK_BUFFER, self.pbos[slot]);
+        if self.pbo_sizes[slot] != size {
+            gl::BufferData(gl::PIXEL_PACK_BUFFER, size as isize, ptr::null(), gl::STREAM_READ);
+            self.pbo_sizes[slot] = size;
+        }
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, framebuffer);
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        // With a pixel pack buffer bound the pointer is an offset into it
+        gl::ReadPixels(0, 0, width as i32, height as i32, gl::RGBA, gl::UNSIGNED_BYTE, ptr::null_mut());
+        gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+
+        let fence = gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+        self.in_flight.push_back(Readback { slot, fence, path: path.to_path_buf(), width, height });
+    }
+
+    /// Hands the readbacks that have arrived over to the writer. Call once a frame.
+    pub unsafe fn poll(&mut self) {
+        while !self.in_flight.is_empty() && self.finish_oldest(false) {}
+    }
+
+    /// Waits for every readback in flight
+    pub unsafe fn finish(&mut self) {
+        while !self.in_flight.is_empty() {
+            self.finish_oldest(true);
+        }
+    }
+
+    // Maps the oldest readback and sends it to the writer, returns false if it wasn't done yet
+    unsafe fn finish_oldest(&mut self, wait: bool) -> bool {
+        let readback = match self.in_flight.front() {
+            Some(readback) => readback,
+            None => return false,
+        };
+        let timeout = if wait { u64::MAX } else { 0 };
+        let status = gl::ClientWaitSync(readback.fence, gl::SYNC_FLUSH_COMMANDS_BIT, timeout);
+        if status == gl::TIMEOUT_EXPIRED {
+            return false;
+        }
+        let readback = self.in_flight.pop_front().expect("checked above");
+        gl::DeleteSync(readback.fence);
+        if status == gl::WAIT_FAILED {
+            println!("Warning: failed to wait for the pixels of {}", readback.path.display());
+            return true;
+        }
+
+        let size = self.pbo_sizes[readback.slot];
+        let mut pixels = vec![0u8; size];
+        gl::BindBuffer(gl::PIXEL_PACK_BUFFER, self.pbos[readback.slot]);
+        let mapped = gl::MapBufferRange(gl::PIXEL_PACK_BUFFER, 0, size as isize, gl::MAP_READ_BIT);
+        if mapped.is_null() {
+            println!("Warning: failed to map the pixels of {}", readback.path.display());
+        } else {
+            ptr::copy_nonoverlapping(mapped as *const u8, pixels.as_mut_ptr(), size);
+            gl::UnmapBuffer(gl::PIXEL_PACK_BUFFER);
+        }
+        gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+
+        if !mapped.is_null() {
+            if let Some(writer) = &self.writer {
+                // Blocks when the writer falls behind, which keeps recordings from eating all memory
+                let _ = writer.send(PixelsToWrite {
+                    path: readback.path,
+                    width: readback.width,
+                    height: readback.height,
+                    pixels,
+                });
+            }
+        }
+        true
+    }
+}
+
+impl Drop for FrameCapture {
+    fn drop(&mut self) {
+        unsafe {
+            self.finish();
+            gl::DeleteBuffers(PBO_COUNT as i32, self.pbos.as_ptr());
+        }
+        // Closing the channel lets the writer finish what is queued and stop
+        self.writer = None;
+        if let Some(thread) = self.writer_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Dumps every frame to a numbered image sequence in a directory of its own
+pub struct Recording {
+    pub directory: PathBuf,
+    pub frames: usize,
+}
+
+impl Recording {
+    pub fn start(directory: PathBuf) -> Self {
+        println!("Recording to {}", directory.display());
+        Recording { directory, frames: 0 }
+    }
+
+    /// Where the next frame goes
+    pub fn next_frame(&mut self) -> PathBuf {
+        let path = self.directory.join(format!("frame_{:05}.png", self.frames));
+        self.frames += 1;
+        path
+    }
+
+    pub fn stop(self) {
+        println!("Recorded {} frames to {}", self.frames, self.directory.display());
+    }
+}
+
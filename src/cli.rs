@@ -0,0 +1,166 @@
+extern crate nalgebra_glm as glm;
+
+use std::path::PathBuf;
+
+use crate::post::{self, PostSettings};
+use crate::world::DEFAULT_MAX_LIGHTS;
+
+pub const USAGE: &str = "\
+Usage: gloom-rs [options] [point cloud.xyz|.ply]
+
+Options:
+    --headless          Render to PNG files without opening a window
+    --size WxH          Size of the rendered images, 800x800 by default
+    --samples N         Samples per pixel for antialiasing, 4 by default
+    --frames N          Number of frames to render, 1 by default
+    --time T            Scene time in seconds of the first frame, 0 by default
+    --frame-step DT     Seconds between rendered or recorded frames, 1/30 by default
+    --output DIR        Directory the frames are written to, renders by default
+    --camera X,Y,Z      Where the camera is, 0,40,90 by default
+    --look-at X,Y,Z     What the camera looks at, 0,0,0 by default
+    --max-lights N      Most lights drawn at once, the brightest are kept, 8 by default
+    --skybox PATH       Sky from an equirectangular image, e.g. a .hdr, or from a directory
+                        of px, nx, py, ny, pz and nz face images. A starfield by default.
+    --post LIST         Post-processing effects in the order they are applied, from bloom,
+                        tonemap, gamma, grading, fxaa and vignette. All of them by default,
+                        the number keys toggle them.
+    --no-post           Draw straight to the screen, without post-processing
+    --exposure E        Brightness before tone mapping, 1 by default
+    --lut PATH          Color table for grading, an image of N*N by N texels. No grading
+                        by default.
+    --golden            Compare reference scenes with the images in golden/, failures go to
+                        the output directory
+    --update-golden     Replace the images in golden/ with new renders of the reference scenes
+    --record            Record every frame to recordings/ from the start, F10 toggles it
+    --help              Print this message";
+
+/// How to render the scene, mostly for stills without a window
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub frames: usize,
+    pub time: f32,
+    pub frame_step: f32,
+    pub output: PathBuf,
+    pub camera: glm::Vec3,
+    pub look_at: glm::Vec3,
+    pub max_lights: usize,
+    pub skybox: Option<PathBuf>,
+    // Ignored when post_processing isn't set
+    pub post: PostSettings,
+    pub post_processing: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: 800,
+            height: 800,
+            samples: 4,
+            frames: 1,
+            time: 0.,
+            frame_step: 1. / 30.,
+            output: PathBuf::from("renders"),
+            camera: glm::vec3(0., 40., 90.),
+            look_at: glm::vec3(0., 0., 0.),
+            max_lights: DEFAULT_MAX_LIGHTS,
+            skybox: None,
+            post: PostSettings::default(),
+            post_processing: true,
+        }
+    }
+}
+
+pub struct Options {
+    pub point_cloud: Option<String>,
+    pub headless: bool,
+    pub render: RenderOptions,
+    pub record: bool,
+    pub golden: bool,
+    pub update_golden: bool,
+    pub help: bool,
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, got {}", flag, value))
+}
+
+fn parse_vec3(flag: &str, value: &str) -> Result<glm::Vec3, String> {
+    let values = value
+        .split(',')
+        .map(|v| parse_number::<f32>(flag, v.trim()))
+        .collect::<Result<Vec<f32>, String>>()?;
+    match values.as_slice() {
+        [x, y, z] => Ok(glm::vec3(*x, *y, *z)),
+        _ => Err(format!("{} expects X,Y,Z, got {}", flag, value)),
+    }
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let mut parts = value.splitn(2, ['x', 'X']);
+    let width = parts.next().and_then(|w| w.parse().ok());
+    let height = parts.next().and_then(|h| h.parse().ok());
+    match (width, height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(format!("--size expects WIDTHxHEIGHT, got {}", value)),
+    }
+}
+
+impl Options {
+    /// Parses the arguments after the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            point_cloud: None,
+            headless: false,
+            render: RenderOptions::default(),
+            record: false,
+            golden: false,
+            update_golden: false,
+            help: false,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                if options.point_cloud.is_some() {
+                    return Err(format!("Unexpected argument {}", arg));
+                }
+                options.point_cloud = Some(arg);
+                continue;
+            }
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--record" => options.record = true,
+                "--golden" => options.golden = true,
+                "--update-golden" => options.update_golden = true,
+                "--no-post" => options.render.post_processing = false,
+                "--help" => options.help = true,
+                flag => {
+                    let value = args.next().ok_or_else(|| format!("{} expects a value", flag))?;
+                    let render = &mut options.render;
+                    match flag {
+                        "--size" => {
+                            let (width, height) = parse_size(&value)?;
+                            render.width = width;
+                            render.height = height;
+                        }
+                        "--samples" => render.samples = parse_number(flag, &value)?,
+                        "--frames" => render.frames = parse_number(flag, &value)?,
+                        "--time" => render.time = parse_number(flag, &value)?,
+                        "--frame-step" => render.frame_step = parse_number(flag, &value)?,
+                        "--output" => render.output = PathBuf::from(value),
+                        "--camera" => render.camera = parse_vec3(flag, &value)?,
+                        "--look-at" => render.look_at = parse_vec3(flag, &value)?,
+                        "--max-lights" => render.max_lights = parse_number(flag, &value)?,
+                        "--skybox" => render.skybox = Some(PathBuf::from(value)),
+                        "--post" => render.post.effects = post::parse_effects(&value)?,
+                        "--exposure" => render.post.exposure = parse_number(flag, &value)?,
+                        "--lut" => render.post.lut = Some(PathBuf::from(value)),
+                        _ => return Err(format!("Unknown option {}", flag)),
+                    }
+                }
+            }
+        }
+        Ok(options)
+    }
+}
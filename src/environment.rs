@@ -0,0 +1,385 @@
+extern crate nalgebra_glm as glm;
+
+use std::f32::consts::PI;
+use std::os::raw::c_void;
+
+use crate::shader::Shader;
+
+// Image based lighting from an environment cube map, for the physically based path. Everything
+// is precomputed on the CPU once: the diffuse light as spherical harmonics, the specular light
+// blurred for every roughness into the mip levels of a cube map, and the scale and bias the
+// split sum approximation applies to the reflectance.
+
+// Texture units of the environment, after the material textures, see material.rs
+pub const SPECULAR_UNIT: u32 = 6;
+pub const BRDF_UNIT: u32 = 7;
+
+// Size of the sharpest level of the specular map, each level is half the one before
+const SPECULAR_SIZE: usize = 64;
+const SPECULAR_LEVELS: usize = 6;
+const SPECULAR_SAMPLES: u32 = 64;
+const BRDF_LUT_SIZE: usize = 32;
+const BRDF_SAMPLES: u32 = 128;
+
+/// A cube map on the CPU in linear RGB, with the faces in GL's order: +X, -X, +Y, -Y, +Z, -Z.
+/// Each face is `size` rows of `size` texels, the first row at t = 0.
+#[derive(Clone)]
+pub struct CubeImage {
+    pub size: usize,
+    pub faces: Vec<Vec<glm::Vec3>>,
+}
+
+/// The direction through the point `s`, `t` in [0, 1] on a face
+fn face_direction(face: usize, s: f32, t: f32) -> glm::Vec3 {
+    let (sc, tc) = (2. * s - 1., 2. * t - 1.);
+    let direction = match face {
+        0 => glm::vec3(1., -tc, -sc),
+        1 => glm::vec3(-1., -tc, sc),
+        2 => glm::vec3(sc, 1., tc),
+        3 => glm::vec3(sc, -1., -tc),
+        4 => glm::vec3(sc, -tc, 1.),
+        _ => glm::vec3(-sc, -tc, -1.),
+    };
+    glm::normalize(&direction)
+}
+
+/// The direction through the center of a texel
+pub fn texel_direction(face: usize, x: usize, y: usize, size: usize) -> glm::Vec3 {
+    face_direction(face, (x as f32 + 0.5) / size as f32, (y as f32 + 0.5) / size as f32)
+}
+
+/// How much of the sphere of directions a texel covers, the corners cover less than the center
+fn texel_solid_angle(x: usize, y: usize, size: usize) -> f32 {
+    let area = |x: f32, y: f32| (x * y).atan2((x * x + y * y + 1.).sqrt());
+    let corner = |i: usize| 2. * i as f32 / size as f32 - 1.;
+    let (x0, x1, y0, y1) = (corner(x), corner(x + 1), corner(y), corner(y + 1));
+    area(x0, y0) - area(x0, y1) - area(x1, y0) + area(x1, y1)
+}
+
+impl CubeImage {
+    /// A cube map with the color `color` gives for the direction through each texel
+    pub fn from_fn(size: usize, color: impl Fn(&glm::Vec3) -> glm::Vec3) -> Self {
+        let faces = (0..6)
+            .map(|face| {
+                (0..size * size)
+                    .map(|i| color(&texel_direction(face, i % size, i / size, size)))
+                    .collect()
+            })
+            .collect();
+        CubeImage { size, faces }
+    }
+
+    /// The face and index in it of the texel `direction` points at
+    pub fn texel_index(&self, direction: &glm::Vec3) -> (usize, usize) {
+        let (x, y, z) = (direction.x, direction.y, direction.z);
+        let (face, sc, tc, major) = if x.abs() >= y.abs() && x.abs() >= z.abs() {
+            if x > 0. { (0, -z, -y, x) } else { (1, z, -y, -x) }
+        } else if y.abs() >= z.abs() {
+            if y > 0. { (2, x, z, y) } else { (3, x, -z, -y) }
+        } else if z > 0. {
+            (4, x, -y, z)
+        } else {
+            (5, -x, -y, -z)
+        };
+        let texel = |c: f32| (((c / major + 1.) / 2. * self.size as f32) as usize).min(self.size - 1);
+        (face, texel(tc) * self.size + texel(sc))
+    }
+
+    /// The texel `direction` points at
+    pub fn lookup(&self, direction: &glm::Vec3) -> glm::Vec3 {
+        let (face, index) = self.texel_index(direction);
+        self.faces[face][index]
+    }
+
+    /// Half the size, every texel the average of the four it covers
+    pub fn downsample(&self) -> Self {
+        let size = (self.size / 2).max(1);
+        if size == self.size {
+            return self.clone();
+        }
+        let faces = self
+            .faces
+            .iter()
+            .map(|face| {
+                (0..size * size)
+                    .map(|i| {
+                        let (x, y) = (2 * (i % size), 2 * (i / size));
+                        let texel = |x: usize, y: usize| face[y * self.size + x];
+                        (texel(x, y) + texel(x + 1, y) + texel(x, y + 1) + texel(x + 1, y + 1)) / 4.
+                    })
+                    .collect()
+            })
+            .collect();
+        CubeImage { size, faces }
+    }
+
+    /// Halved until it is no bigger than `size`
+    pub fn shrunk_to(&self, size: usize) -> Self {
+        let mut image = self.clone();
+        while image.size > size {
+            image = image.downsample();
+        }
+        image
+    }
+
+    /// This image and ever smaller versions of it, down to a single texel per face
+    pub fn mip_chain(&self) -> Vec<CubeImage> {
+        let mut chain = vec![self.clone()];
+        while chain.last().unwrap().size > 1 {
+            let smaller = chain.last().unwrap().downsample();
+            chain.push(smaller);
+        }
+        chain
+    }
+}
+
+/// A sky that fades from `zenith` overhead to `horizon`, with flat `ground` below
+pub fn gradient_sky(size: usize, zenith: glm::Vec3, horizon: glm::Vec3, ground: glm::Vec3) -> CubeImage {
+    CubeImage::from_fn(size, |direction| {
+        if direction.y < 0. {
+            ground
+        } else {
+            glm::lerp(&horizon, &zenith, direction.y.sqrt())
+        }
+    })
+}
+
+fn sh_basis(d: &glm::Vec3) -> [f32; 9] {
+    [
+        0.282_095,
+        0.488_603 * d.y,
+        0.488_603 * d.z,
+        0.488_603 * d.x,
+        1.092_548 * d.x * d.y,
+        1.092_548 * d.y * d.z,
+        0.315_392 * (3. * d.z * d.z - 1.),
+        1.092_548 * d.x * d.z,
+        0.546_274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+/// The light reaching a surface from the whole environment, as the 9 coefficients of second
+/// order spherical harmonics. Scaled so that evaluating them for a normal gives the light a
+/// white diffuse surface facing that way reflects, see `irradiance` in simple.frag.
+pub fn irradiance_sh(environment: &CubeImage) -> [glm::Vec3; 9] {
+    let mut coefficients = [glm::vec3(0., 0., 0.); 9];
+    let size = environment.size;
+    for (face, texels) in environment.faces.iter().enumerate() {
+        for (i, color) in texels.iter().enumerate() {
+            let (x, y) = (i % size, i / size);
+            let weight = texel_solid_angle(x, y, size);
+            let basis = sh_basis(&texel_direction(face, x, y, size));
+            for (coefficient, b) in coefficients.iter_mut().zip(basis.iter()) {
+                *coefficient += color * (*b * weight);
+            }
+        }
+    }
+    // Convolved with the cosine lobe, and divided by pi for a diffuse surface
+    let bands = [1., 2. / 3., 2. / 3., 2. / 3., 0.25, 0.25, 0.25, 0.25, 0.25];
+    for (coefficient, band) in coefficients.iter_mut().zip(bands.iter()) {
+        *coefficient *= *band;
+    }
+    coefficients
+}
+
+fn hammersley(i: u32, count: u32) -> (f32, f32) {
+    (i as f32 / count as f32, i.reverse_bits() as f32 / 4_294_967_296.)
+}
+
+/// A half vector around `n`, distributed like the GGX normal distribution for `roughness`
+fn importance_sample_ggx(xi: (f32, f32), n: &glm::Vec3, roughness: f32) -> glm::Vec3 {
+    let a = roughness * roughness;
+    let phi = 2. * PI * xi.0;
+    let cos_theta = ((1. - xi.1) / (1. + (a * a - 1.) * xi.1)).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+    let up = if n.z.abs() < 0.999 { glm::vec3(0., 0., 1.) } else { glm::vec3(1., 0., 0.) };
+    let tangent = glm::normalize(&glm::cross(&up, n));
+    let bitangent = glm::cross(n, &tangent);
+    glm::normalize(&(tangent * (phi.cos() * sin_theta) + bitangent * (phi.sin() * sin_theta) + n * cos_theta))
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a2 = roughness.powi(4);
+    let d = n_dot_h * n_dot_h * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+/// The environment blurred for ever rougher surfaces, roughness 0 in the first level and 1 in
+/// the last. Each level is half the size of the one before, like mip levels.
+pub fn prefilter_specular(environment: &CubeImage, size: usize, levels: usize) -> Vec<CubeImage> {
+    let source = environment.shrunk_to(size);
+    let mips = source.mip_chain();
+    let texel_solid_angle = 4. * PI / (6. * (source.size * source.size) as f32);
+
+    let mut prefiltered = vec![source.clone()];
+    for level in 1..levels {
+        let roughness = level as f32 / (levels - 1) as f32;
+        let level_size = (source.size >> level).max(1);
+        prefiltered.push(CubeImage::from_fn(level_size, |n| {
+            // Assumes the view is along the normal, which loses the stretched highlights at
+            // grazing angles but lets the result only depend on the reflected direction
+            let mut color = glm::vec3(0., 0., 0.);
+            let mut total_weight = 0.;
+            for i in 0..SPECULAR_SAMPLES {
+                let h = importance_sample_ggx(hammersley(i, SPECULAR_SAMPLES), n, roughness);
+                let n_dot_h = glm::dot(n, &h);
+                let l = h * (2. * n_dot_h) - n;
+                let n_dot_l = glm::dot(n, &l);
+                if n_dot_l <= 0. {
+                    continue;
+                }
+                // Read from a smaller mip where samples are far apart, to avoid aliasing
+                let pdf = distribution_ggx(n_dot_h, roughness) / 4.;
+                let sample_solid_angle = 1. / (SPECULAR_SAMPLES as f32 * pdf + 0.0001);
+                let mip = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.;
+                let mip = (mip.max(0.).round() as usize).min(mips.len() - 1);
+                color += mips[mip].lookup(&l) * n_dot_l;
+                total_weight += n_dot_l;
+            }
+            color / total_weight.max(0.0001)
+        }));
+    }
+    prefiltered
+}
+
+fn geometry_schlick_ggx(n_dot_x: f32, roughness: f32) -> f32 {
+    let k = roughness * roughness / 2.;
+    n_dot_x / (n_dot_x * (1. - k) + k)
+}
+
+/// The scale and bias to the Fresnel reflectance at normal incidence of the split sum
+/// approximation, for the cosine of the view angle along x and the roughness along y.
+/// Stored as `size` rows of `size` pairs.
+pub fn brdf_lut(size: usize) -> Vec<[f32; 2]> {
+    let n = glm::vec3(0., 0., 1.);
+    (0..size * size)
+        .map(|i| {
+            let n_dot_v = ((i % size) as f32 + 0.5) / size as f32;
+            let roughness = ((i / size) as f32 + 0.5) / size as f32;
+            let v = glm::vec3((1. - n_dot_v * n_dot_v).sqrt(), 0., n_dot_v);
+            let (mut scale, mut bias) = (0., 0.);
+            for i in 0..BRDF_SAMPLES {
+                let h = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), &n, roughness);
+                let v_dot_h = glm::dot(&v, &h);
+                let l = h * (2. * v_dot_h) - v;
+                let (n_dot_l, n_dot_h) = (l.z, h.z);
+                if n_dot_l <= 0. {
+                    continue;
+                }
+                let g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+                let visibility = g * v_dot_h.max(0.) / (n_dot_h * n_dot_v);
+                let fresnel = (1. - v_dot_h.max(0.)).powi(5);
+                scale += (1. - fresnel) * visibility;
+                bias += fresnel * visibility;
+            }
+            [scale / BRDF_SAMPLES as f32, bias / BRDF_SAMPLES as f32]
+        })
+        .collect()
+}
+
+/// Everything `Environment` needs, worked out from an environment cube map. Slow, so it is
+/// made on an asset worker.
+pub struct EnvironmentMaps {
+    pub irradiance: [glm::Vec3; 9],
+    pub specular: Vec<CubeImage>,
+    pub brdf_lut: Vec<[f32; 2]>,
+}
+
+impl EnvironmentMaps {
+    pub fn new(environment: &CubeImage) -> Self {
+        // Both blur the environment a lot, so they can start from a small version of it
+        let small = environment.shrunk_to(SPECULAR_SIZE);
+        EnvironmentMaps {
+            irradiance: irradiance_sh(&small),
+            specular: prefilter_specular(&small, SPECULAR_SIZE, SPECULAR_LEVELS),
+            brdf_lut: brdf_lut(BRDF_LUT_SIZE),
+        }
+    }
+}
+
+/// The environment's lighting on the GPU
+pub struct Environment {
+    pub irradiance: [glm::Vec3; 9],
+    pub specular_map: u32,
+    pub specular_levels: usize,
+    pub brdf_lut: u32,
+}
+
+impl Environment {
+    pub unsafe fn new(maps: &EnvironmentMaps) -> Self {
+        let levels = &maps.specular;
+        let mut specular_map = 0;
+        gl::GenTextures(1, &mut specular_map);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, specular_map);
+        for (level, image) in levels.iter().enumerate() {
+            for (face, texels) in image.faces.iter().enumerate() {
+                let data: Vec<f32> = texels.iter().flat_map(|c| vec![c.x, c.y, c.z]).collect();
+                gl::TexImage2D(
+                    gl::TEXTURE_CUBE_MAP_POSITIVE_X + face as u32,
+                    level as i32,
+                    gl::RGB16F as i32,
+                    image.size as i32,
+                    image.size as i32,
+                    0,
+                    gl::RGB,
+                    gl::FLOAT,
+                    data.as_ptr() as *const c_void,
+                );
+            }
+        }
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAX_LEVEL, levels.len() as i32 - 1);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        for &wrap in &[gl::TEXTURE_WRAP_S, gl::TEXTURE_WRAP_T, gl::TEXTURE_WRAP_R] {
+            gl::TexParameteri(gl::TEXTURE_CUBE_MAP, wrap, gl::CLAMP_TO_EDGE as i32);
+        }
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
+
+        let lut: Vec<f32> = maps.brdf_lut.iter().flat_map(|p| p.to_vec()).collect();
+        let mut brdf_lut = 0;
+        gl::GenTextures(1, &mut brdf_lut);
+        gl::BindTexture(gl::TEXTURE_2D, brdf_lut);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RG16F as i32,
+            BRDF_LUT_SIZE as i32,
+            BRDF_LUT_SIZE as i32,
+            0,
+            gl::RG,
+            gl::FLOAT,
+            lut.as_ptr() as *const c_void,
+        );
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+
+        Environment { irradiance: maps.irradiance, specular_map, specular_levels: levels.len(), brdf_lut }
+    }
+
+    /// Binds the environment's textures and sets its uniforms, the program has to be in use
+    pub unsafe fn bind(&self, shader: &Shader) {
+        let location = |name: &str| shader.get_uniform_location(name);
+        let irradiance: Vec<f32> = self.irradiance.iter().flat_map(|c| vec![c.x, c.y, c.z]).collect();
+        gl::Uniform3fv(location("IrradianceSH"), 9, irradiance.as_ptr());
+        gl::ActiveTexture(gl::TEXTURE0 + SPECULAR_UNIT);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, self.specular_map);
+        gl::ActiveTexture(gl::TEXTURE0 + BRDF_UNIT);
+        gl::BindTexture(gl::TEXTURE_2D, self.brdf_lut);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::Uniform1i(location("SpecularMap"), SPECULAR_UNIT as i32);
+        gl::Uniform1f(location("SpecularMaxLevel"), (self.specular_levels - 1) as f32);
+        gl::Uniform1i(location("BrdfLut"), BRDF_UNIT as i32);
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.specular_map);
+            gl::DeleteTextures(1, &self.brdf_lut);
+        }
+    }
+}
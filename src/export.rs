@@ -0,0 +1,302 @@
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::mesh::{Mesh, Primitive};
+use crate::scene_graph::SceneNode;
+
+// Exporters for looking at our generated and processed meshes in external tools.
+// Both formats get positions, normals, UVs and colors, whichever the mesh has.
+
+fn has(attribute: &[f32], mesh: &Mesh, size: usize) -> bool {
+    !attribute.is_empty() && attribute.len() == mesh.vertex_count() * size
+}
+
+fn create(path: &Path) -> io::Result<BufWriter<fs::File>> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    Ok(BufWriter::new(fs::File::create(path)?))
+}
+
+// Object and material names can't contain whitespace in OBJ
+fn obj_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+/// Writes several meshes as named objects of one OBJ file, with a material per object in an
+/// MTL file next to it. OBJ has no per vertex colors, so each material gets the color of
+/// the first vertex, which is all our meshes use anyway.
+pub fn write_obj(path: &Path, objects: &[(&str, &Mesh)]) -> io::Result<()> {
+    let mtl_path = path.with_extension("mtl");
+    let mtl_name = mtl_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut mtl = create(&mtl_path)?;
+    writeln!(mtl, "# Exported by gloom-rs")?;
+    for (name, mesh) in objects {
+        let color = if has(&mesh.colors, mesh, 4) { &mesh.colors[0..4] } else { &[1.0, 1.0, 1.0, 1.0][..] };
+        writeln!(mtl, "\nnewmtl {}_material", obj_name(name))?;
+        writeln!(mtl, "Ka 0 0 0")?;
+        writeln!(mtl, "Kd {} {} {}", color[0], color[1], color[2])?;
+        writeln!(mtl, "Ks 0 0 0")?;
+        writeln!(mtl, "d {}", color[3])?;
+        writeln!(mtl, "illum 1")?;
+    }
+    mtl.flush()?;
+
+    let mut obj = create(path)?;
+    writeln!(obj, "# Exported by gloom-rs")?;
+    writeln!(obj, "mtllib {}", mtl_name)?;
+    // OBJ indices are 1 based and count from the start of the file, not the object
+    let (mut position_offset, mut normal_offset, mut uv_offset) = (1, 1, 1);
+    for (name, mesh) in objects {
+        let (has_normals, has_uvs) = (has(&mesh.normals, mesh, 3), has(&mesh.uvs, mesh, 2));
+        writeln!(obj, "\no {}", obj_name(name))?;
+        writeln!(obj, "usemtl {}_material", obj_name(name))?;
+        for v in mesh.vertices.chunks_exact(3) {
+            writeln!(obj, "v {} {} {}", v[0], v[1], v[2])?;
+        }
+        if has_normals {
+            for n in mesh.normals.chunks_exact(3) {
+                writeln!(obj, "vn {} {} {}", n[0], n[1], n[2])?;
+            }
+        }
+        if has_uvs {
+            for uv in mesh.uvs.chunks_exact(2) {
+                writeln!(obj, "vt {} {}", uv[0], uv[1])?;
+            }
+        }
+        match mesh.primitive {
+            Primitive::Lines => for line in mesh.indices.chunks_exact(2) {
+                writeln!(obj, "l {} {}", line[0] as usize + position_offset, line[1] as usize + position_offset)?;
+            },
+            Primitive::LineStrip | Primitive::Points if !mesh.indices.is_empty() => {
+                write!(obj, "{}", if mesh.primitive == Primitive::Points { "p" } else { "l" })?;
+                for &i in &mesh.indices {
+                    write!(obj, " {}", i as usize + position_offset)?;
+                }
+                writeln!(obj)?;
+            }
+            _ => {}
+        }
+        for t in 0..mesh.triangle_count() {
+            write!(obj, "f")?;
+            for &i in &mesh.triangle(t) {
+                let (p, n, uv) = (i + position_offset, i + normal_offset, i + uv_offset);
+                match (has_uvs, has_normals) {
+                    (true, true) => write!(obj, " {}/{}/{}", p, uv, n)?,
+                    (true, false) => write!(obj, " {}/{}", p, uv)?,
+                    (false, true) => write!(obj, " {}//{}", p, n)?,
+                    (false, false) => write!(obj, " {}", p)?,
+                }
+            }
+            writeln!(obj)?;
+        }
+        position_offset += mesh.vertex_count();
+        if has_normals { normal_offset += mesh.vertex_count() }
+        if has_uvs { uv_offset += mesh.vertex_count() }
+    }
+    obj.flush()
+}
+
+/// Writes a binary little endian PLY file, which unlike OBJ keeps the per vertex colors
+pub fn write_ply(path: &Path, mesh: &Mesh) -> io::Result<()> {
+    let (has_normals, has_uvs, has_colors) = (has(&mesh.normals, mesh, 3), has(&mesh.uvs, mesh, 2), has(&mesh.colors, mesh, 4));
+    let mut ply = create(path)?;
+    writeln!(ply, "ply")?;
+    writeln!(ply, "format binary_little_endian 1.0")?;
+    writeln!(ply, "comment Exported by gloom-rs")?;
+    writeln!(ply, "element vertex {}", mesh.vertex_count())?;
+    writeln!(ply, "property float x\nproperty float y\nproperty float z")?;
+    if has_normals { writeln!(ply, "property float nx\nproperty float ny\nproperty float nz")? }
+    if has_uvs { writeln!(ply, "property float s\nproperty float t")? }
+    if has_colors { writeln!(ply, "property uchar red\nproperty uchar green\nproperty uchar blue\nproperty uchar alpha")? }
+    // Lines become edges, points need nothing past the vertices
+    let edges: Vec<[u32; 2]> = match mesh.primitive {
+        Primitive::Lines => mesh.indices.chunks_exact(2).map(|line| [line[0], line[1]]).collect(),
+        Primitive::LineStrip => mesh.indices.windows(2).map(|line| [line[0], line[1]]).collect(),
+        _ => Vec::new(),
+    };
+    writeln!(ply, "element face {}", mesh.triangle_count())?;
+    writeln!(ply, "property list uchar uint vertex_indices")?;
+    if !edges.is_empty() {
+        writeln!(ply, "element edge {}", edges.len())?;
+        writeln!(ply, "property int vertex1\nproperty int vertex2")?;
+    }
+    writeln!(ply, "end_header")?;
+
+    for i in 0..mesh.vertex_count() {
+        for value in &mesh.vertices[3*i..3*i + 3] {
+            ply.write_all(&value.to_le_bytes())?;
+        }
+        if has_normals {
+            for value in &mesh.normals[3*i..3*i + 3] {
+                ply.write_all(&value.to_le_bytes())?;
+            }
+        }
+        if has_uvs {
+            for value in &mesh.uvs[2*i..2*i + 2] {
+                ply.write_all(&value.to_le_bytes())?;
+            }
+        }
+        if has_colors {
+            let color: Vec<u8> = mesh.colors[4*i..4*i + 4].iter().map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8).collect();
+            ply.write_all(&color)?;
+        }
+    }
+    for t in 0..mesh.triangle_count() {
+        ply.write_all(&[3])?;
+        for &i in &mesh.triangle(t) {
+            ply.write_all(&(i as u32).to_le_bytes())?;
+        }
+    }
+    for edge in &edges {
+        ply.write_all(&(edge[0] as i32).to_le_bytes())?;
+        ply.write_all(&(edge[1] as i32).to_le_bytes())?;
+    }
+    ply.flush()
+}
+
+impl Mesh {
+    /// Writes the mesh as a single object, named after the file
+    #[allow(dead_code)]
+    pub fn write_obj(&self, path: &Path) -> io::Result<()> {
+        let name = path.file_stem().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "mesh".to_string());
+        write_obj(path, &[(&name, self)])
+    }
+
+    #[allow(dead_code)]
+    pub fn write_ply(&self, path: &Path) -> io::Result<()> {
+        write_ply(path, self)
+    }
+}
+
+/// Copies the mesh of every drawable node in the scene graph into world space, using the
+/// transformations from the last `update_node_transformations`. The scene graph only knows
+/// GPU meshes, so `mesh_for_vao` has to map their VAOs back to the meshes they were made from.
+pub unsafe fn bake_scene<'a>(root: &SceneNode, mesh_for_vao: &dyn Fn(u32) -> Option<&'a Mesh>) -> Vec<Mesh> {
+    let mut baked = Vec::new();
+    if let Some(gpu_mesh) = root.mesh.as_ref() {
+        match mesh_for_vao(gpu_mesh.vao) {
+            Some(mesh) => {
+                let mut mesh = mesh.clone();
+                mesh.transform(&root.current_transformation_matrix);
+                baked.push(mesh);
+            }
+            None => println!("Warning: no mesh known for VAO {}, leaving it out of the export", gpu_mesh.vao),
+        }
+    }
+    for &child in &root.children {
+        baked.extend(bake_scene(&*child, mesh_for_vao));
+    }
+    baked
+}
+
+/// Exports a whole scene graph baked to world space, as `<path>.obj` with one object per
+/// node and as a single merged `<path>.ply`. Only the triangle meshes are merged into the PLY.
+pub unsafe fn export_scene<'a>(path: &Path, root: &SceneNode, mesh_for_vao: &dyn Fn(u32) -> Option<&'a Mesh>) -> io::Result<()> {
+    let meshes = bake_scene(root, mesh_for_vao);
+    let names: Vec<String> = (0..meshes.len()).map(|i| format!("node_{}", i)).collect();
+    let objects: Vec<(&str, &Mesh)> = names.iter().map(|n| n.as_str()).zip(meshes.iter()).collect();
+    write_obj(&path.with_extension("obj"), &objects)?;
+
+    let mut merged = Mesh::empty();
+    for mesh in meshes.iter().filter(|mesh| mesh.primitive == Primitive::Triangles) {
+        merged.append(mesh);
+    }
+    write_ply(&path.with_extension("ply"), &merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud;
+
+    // A per-test directory under the system temp dir, removed again when the test ends
+    struct ScratchDir(std::path::PathBuf);
+
+    impl ScratchDir {
+        fn new(test: &str) -> Self {
+            ScratchDir(std::env::temp_dir().join(format!("gloom-export-{}-{}", std::process::id(), test)))
+        }
+
+        fn join(&self, name: &str) -> std::path::PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Every triangle as the bits of its corners' positions, normals and UVs, in a canonical order
+    fn corner_triangles(positions: &[f32], normals: &[f32], uvs: &[f32], indices: &[u32]) -> Vec<Vec<u32>> {
+        let corner = |i: u32| {
+            let i = i as usize;
+            positions[3*i..3*i + 3].iter().chain(&normals[3*i..3*i + 3]).chain(&uvs[2*i..2*i + 2]).map(|x| x.to_bits()).collect::<Vec<u32>>()
+        };
+        let mut triangles: Vec<Vec<u32>> = indices.chunks(3).map(|t| t.iter().flat_map(|&i| corner(i)).collect()).collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn obj_round_trip() {
+        let scratch = ScratchDir::new("obj");
+        let path = scratch.join("scene.obj");
+        let cube = Mesh::cube(glm::vec3(1.0, 2.0, 3.0), [0.25, 0.5, 0.75, 1.0]);
+        let sphere = Mesh::uv_sphere(0.5, 8, 4, [1.0, 0.0, 0.0, 0.5]);
+        write_obj(&path, &[("a cube", &cube), ("sphere", &sphere)]).unwrap();
+
+        let (models, materials) = tobj::load_obj(&path, true).unwrap();
+        assert_eq!(models.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), ["a_cube", "sphere"]);
+        for (model, original) in models.iter().zip(&[&cube, &sphere]) {
+            let loaded = &model.mesh;
+            assert_eq!(
+                corner_triangles(&loaded.positions, &loaded.normals, &loaded.texcoords, &loaded.indices),
+                corner_triangles(&original.vertices, &original.normals, &original.uvs, &original.indices),
+            );
+            let material = &materials[loaded.material_id.expect("every object has a material")];
+            assert_eq!(material.diffuse[..], original.colors[0..3]);
+            assert_eq!(material.dissolve, original.colors[3]);
+        }
+    }
+
+    #[test]
+    fn ply_round_trip() {
+        let scratch = ScratchDir::new("ply");
+        let path = scratch.join("cube.ply");
+        let mut cube = Mesh::cube(glm::vec3(1.0, 2.0, 3.0), [0.0, 0.5, 1.0, 1.0]);
+        cube.colors[0] = 0.2;
+        write_ply(&path, &cube).unwrap();
+
+        // The point cloud loader reads the vertices and their colors
+        let points = point_cloud::load_ply(path.to_str().unwrap()).unwrap();
+        assert_eq!(points.vertices, cube.vertices);
+        for (loaded, original) in points.colors.iter().zip(&cube.colors) {
+            assert!((loaded - original).abs() <= 0.5 / 255.0 + 1e-6);
+        }
+
+        // and the faces follow the vertices, each a count and three indices
+        let data = fs::read(&path).unwrap();
+        let body = data.windows(11).position(|w| w == b"end_header\n").unwrap() + 11;
+        let header = String::from_utf8_lossy(&data[..body]);
+        let vertex_size: usize = header.lines()
+            .skip_while(|line| !line.starts_with("element vertex"))
+            .skip(1)
+            .take_while(|line| line.starts_with("property"))
+            .map(|line| if line.contains("uchar") { 1 } else { 4 })
+            .sum();
+        let faces = &data[body + vertex_size * cube.vertex_count()..];
+        assert_eq!(faces.len(), cube.triangle_count() * 13);
+        let indices: Vec<u32> = faces.chunks(13)
+            .flat_map(|face| {
+                assert_eq!(face[0], 3);
+                face[1..].chunks(4).map(|i| u32::from_le_bytes([i[0], i[1], i[2], i[3]])).collect::<Vec<u32>>()
+            })
+            .collect();
+        assert_eq!(indices, cube.indices);
+    }
+}
@@ -0,0 +1,398 @@
+extern crate nalgebra_glm as glm;
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use image::{Rgba, RgbaImage};
+
+use crate::gpu_mesh::GpuMesh;
+use crate::light::Light;
+use crate::material::{Material, PbrMaterial, SpecularModel};
+use crate::render_target::RenderTarget;
+use crate::headless::{render_image, still_target, HeadlessGl};
+use crate::mesh::Mesh;
+use crate::scene_graph::{update_node_transformations, HelicopterStruct, Node, SceneNode};
+use crate::vertex_layout::VertexLayout;
+use crate::world::{self, SceneAssets, World};
+
+// Golden image regression tests. Reference scenes are rendered headlessly with a fixed camera and
+// time, and compared against the images committed in golden/. Rerun with --update-golden after a
+// change that is meant to alter the look, and commit the new images along with it. The helicopter
+// model isn't part of the repository, so the helicopter scene is built from primitives instead.
+// That helicopter is also rendered at two places and compared with itself, which needs no golden
+// image. tests/golden.rs runs all of this under `cargo test -- --ignored`.
+
+const GOLDEN_DIR: &str = "golden";
+const SIZE: u32 = 256;
+// How far apart two colors can be in YIQ space before the pixel counts as changed, as a fraction
+// of the largest possible distance. Absorbs rounding differences between drivers.
+const PIXEL_THRESHOLD: f32 = 0.1;
+// Fraction of the pixels that can change before the scene fails, for edges that rasterize a
+// little differently between drivers
+const MAX_CHANGED_PIXELS: f32 = 0.001;
+// Squared YIQ distance between black and white
+const MAX_YIQ_DELTA: f32 = 35215.;
+// How far the helicopter is moved for the translation check, far enough that lighting computed
+// in the wrong space would show
+const TRANSLATION_OFFSET: [f32; 3] = [300., 40., -200.];
+// Vertices along each side of the terrain scene's generated surface
+const TERRAIN_RESOLUTION: usize = 128;
+
+struct ReferenceScene {
+    name: &'static str,
+    camera: [f32; 3],
+    look_at: [f32; 3],
+    time: f32,
+}
+
+const SCENES: [ReferenceScene; 4] = [
+    ReferenceScene { name: "terrain", camera: [0., 60., 120.], look_at: [0., 0., 0.], time: 0. },
+    // Across the sunlight, seeing both the lit side of the helicopter and its shadow
+    ReferenceScene { name: "helicopter", camera: [-11., 9., 14.], look_at: [1., 1., 3.], time: 0.3 },
+    ReferenceScene { name: "primitives", camera: [5., 4., 7.], look_at: [0., 0., 0.], time: 1.2 },
+    ReferenceScene { name: "materials", camera: [0., 1.5, 6.], look_at: [0., 0., 0.], time: 0. },
+];
+
+fn color_to_yiq(pixel: &Rgba<u8>) -> [f32; 3] {
+    // Blend with white, so transparent pixels compare the way they look
+    let alpha = pixel[3] as f32 / 255.;
+    let [r, g, b] = [0, 1, 2].map(|c| 255. + (pixel[c] as f32 - 255.) * alpha);
+    [
+        0.2988953 * r + 0.5866225 * g + 0.1144822 * b,
+        0.595978 * r - 0.2741761 * g - 0.3218019 * b,
+        0.2114702 * r - 0.5226171 * g + 0.3111469 * b,
+    ]
+}
+
+/// How differently two colors are perceived, 0 for the same and `MAX_YIQ_DELTA` for black and white
+fn yiq_delta(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+    let (a, b) = (color_to_yiq(a), color_to_yiq(b));
+    let (y, i, q) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q
+}
+
+pub struct Comparison {
+    pub changed_pixels: usize,
+    pub total_pixels: usize,
+    /// The expected image faded out, with the changed pixels in red
+    pub diff: RgbaImage,
+}
+
+impl Comparison {
+    pub fn passed(&self) -> bool {
+        self.changed_pixels as f32 <= self.total_pixels as f32 * MAX_CHANGED_PIXELS
+    }
+}
+
+pub fn compare(expected: &RgbaImage, actual: &RgbaImage) -> Result<Comparison, String> {
+    if expected.dimensions() != actual.dimensions() {
+        return Err(format!(
+            "expected a {}x{} image, got {}x{}",
+            expected.width(),
+            expected.height(),
+            actual.width(),
+            actual.height()
+        ));
+    }
+    let mut diff = RgbaImage::new(expected.width(), expected.height());
+    let mut changed_pixels = 0;
+    for ((e, a), d) in expected.pixels().zip(actual.pixels()).zip(diff.pixels_mut()) {
+        *d = if yiq_delta(e, a) > MAX_YIQ_DELTA * PIXEL_THRESHOLD * PIXEL_THRESHOLD {
+            changed_pixels += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let faded = (255. - 0.1 * (255. - color_to_yiq(e)[0])) as u8;
+            Rgba([faded, faded, faded, 255])
+        };
+    }
+    Ok(Comparison { changed_pixels, total_pixels: diff.pixels().len(), diff })
+}
+
+// Procedural meshes for the reference scenes, in the order they use them
+unsafe fn primitive_meshes() -> Vec<GpuMesh> {
+    let layout = VertexLayout::standard();
+    let icosphere = Mesh::icosphere(0.6, 1, [1., 1., 1., 1.]);
+    // Generated rather than the lunar surface model, which isn't part of the repository
+    let mut surface = world::generated_lunar_surface(TERRAIN_RESOLUTION);
+    surface.tile_uvs(world::DETAIL_TILE_SIZE);
+    let ring: Vec<glm::Vec3> = (0..48)
+        .map(|i| {
+            let angle = i as f32 / 48. * 2. * std::f32::consts::PI;
+            glm::vec3(3. * angle.cos(), 2., 3. * angle.sin())
+        })
+        .collect();
+    // A helicopter made of primitives, laid out like the model with the nose towards -z and the
+    // tail rotor at (0.35, 2.3, 10.4)
+    let mut fuselage = Mesh::uv_sphere(1., 16, 8, [0.5, 0.6, 0.5, 1.]);
+    fuselage.transform(&(glm::translation(&glm::vec3(0., 2., 0.)) * glm::scaling(&glm::vec3(1.8, 1.8, 4.))));
+    let mut tail_boom = Mesh::cylinder(0.3, 7.5, 12, [0.5, 0.6, 0.5, 1.]);
+    let boom_direction = glm::rotation(std::f32::consts::FRAC_PI_2, &glm::vec3(1., 0., 0.));
+    tail_boom.transform(&(glm::translation(&glm::vec3(0., 2.3, 3.)) * boom_direction));
+    fuselage.append(&tail_boom);
+    let mut main_rotor = Mesh::cube(glm::vec3(14., 0.08, 0.5), [0.2, 0.2, 0.2, 1.]);
+    main_rotor.transform(&glm::translation(&glm::vec3(0., 4.1, 0.)));
+    let mut tail_rotor = Mesh::cube(glm::vec3(0.08, 2.4, 0.3), [0.2, 0.2, 0.2, 1.]);
+    tail_rotor.transform(&glm::translation(&glm::vec3(0.35, 2.3, 10.4)));
+    let mut door = Mesh::cube(glm::vec3(0.1, 1.4, 1.6), [0.8, 0.8, 0.75, 1.]);
+    door.transform(&glm::translation(&glm::vec3(-1.65, 1.9, -0.8)));
+    [
+        Mesh::plane_grid(8., 8., 8, 8, [0.5, 0.5, 0.55, 1.]),
+        Mesh::cube(glm::vec3(1.5, 1.5, 1.5), [0.9, 0.3, 0.2, 1.]),
+        Mesh::uv_sphere(0.5, 16, 8, [0.2, 0.6, 0.9, 1.]),
+        Mesh::torus(0.8, 0.25, 24, 12, [0.9, 0.8, 0.2, 1.]),
+        Mesh::cone(0.4, 0.8, 16, [0.3, 0.9, 0.4, 1.]),
+        Mesh::cylinder(0.5, 1.5, 16, [0.7, 0.4, 0.9, 1.]),
+        icosphere.wireframe([0., 1., 1., 1.]),
+        Mesh::points(&ring, [1., 0.5, 0., 1.]),
+        // White, so the materials scene shows the materials' own colors
+        Mesh::uv_sphere(0.5, 32, 16, [1., 1., 1., 1.]),
+        surface.mesh,
+        fuselage,
+        main_rotor,
+        tail_rotor,
+        door,
+    ]
+    .iter()
+    .map(|mesh| GpuMesh::new(mesh, &layout))
+    .collect()
+}
+
+// Every primitive type, nested a few levels deep with reference points, so that changes to how
+// transformations are combined show up
+unsafe fn primitives_scene(meshes: &[GpuMesh], time: f32) -> Node {
+    let mut root = SceneNode::new();
+    let mut ground = SceneNode::from_mesh(&meshes[0], glm::vec3(0., 0., 0.));
+    ground.set_position(glm::vec3(0., -1., 0.));
+
+    let mut cube = SceneNode::from_mesh(&meshes[1], glm::vec3(0., 0., 0.));
+    cube.set_position(glm::vec3(-2., 0., 0.));
+    cube.set_rotation(glm::vec3(0., 0.8 * time, 0.3));
+    // Orbits the cube's center rather than its own
+    let mut moon = SceneNode::from_mesh(&meshes[2], glm::vec3(0., -1.4, 0.));
+    moon.set_position(glm::vec3(0., 1.4, 0.));
+    moon.set_rotation(glm::vec3(time, 0., 0.));
+    // Mirrors the sky
+    moon.material.reflectivity = 0.6;
+
+    let mut torus = SceneNode::from_mesh(&meshes[3], glm::vec3(0., 0., 0.));
+    torus.set_position(glm::vec3(2., 0., 0.));
+    torus.set_rotation(glm::vec3(time, 0., 0.5));
+    torus.material.specular_model = SpecularModel::BlinnPhong;
+    torus.material.shininess = 80.;
+    let mut cone = SceneNode::from_mesh(&meshes[4], glm::vec3(0., 0., 0.));
+    cone.scale = glm::vec3(0.5, 1., 0.5);
+
+    let mut cylinder = SceneNode::from_mesh(&meshes[5], glm::vec3(0., 0., 0.));
+    cylinder.set_position(glm::vec3(0., -0.25, -2.));
+    let mut wireframe = SceneNode::from_mesh(&meshes[6], glm::vec3(0., 0., 0.));
+    wireframe.set_position(glm::vec3(0., 0.5, 2.));
+    wireframe.set_rotation(glm::vec3(0., time, 0.));
+    let mut points = SceneNode::from_mesh(&meshes[7], glm::vec3(0., 0., 0.));
+    points.point_size = 4.;
+    // A colored light circling above the torus
+    let mut lamp = SceneNode::new();
+    lamp.set_position(glm::vec3(0., 1.5, 1.));
+    lamp.light = Some(Light::point(glm::vec3(1., 0.4, 0.2), 6.));
+    let mut lamp_orbit = SceneNode::new();
+    lamp_orbit.set_position(glm::vec3(2., 0., 0.));
+    lamp_orbit.set_rotation(glm::vec3(0., 2. * time, 0.));
+
+    root.add_child(&ground);
+    root.add_child(&cube);
+    cube.add_child(&moon);
+    root.add_child(&torus);
+    torus.add_child(&cone);
+    root.add_child(&cylinder);
+    root.add_child(&wireframe);
+    root.add_child(&points);
+    root.add_child(&lamp_orbit);
+    lamp_orbit.add_child(&lamp);
+    root.add_child(&world::sun());
+    update_node_transformations(&mut root, &glm::identity());
+    root
+}
+
+// Spheres from smooth to rough, plastic in the front row and gold in the back
+unsafe fn materials_scene(meshes: &[GpuMesh]) -> Node {
+    let mut root = SceneNode::new();
+    root.add_child(&world::sun());
+    let rows = [(glm::vec4(0.8, 0.1, 0.1, 1.), 0.), (glm::vec4(1., 0.77, 0.34, 1.), 1.)];
+    for (row, &(base_color, metallic)) in rows.iter().enumerate() {
+        for column in 0..5 {
+            let roughness = column as f32 / 4.;
+            let mut sphere = SceneNode::from_mesh(&meshes[8], glm::vec3(0., 0., 0.));
+            sphere.set_position(glm::vec3(1.2 * (column as f32 - 2.), 0., -1.2 * row as f32));
+            sphere.material = Material::physically_based(PbrMaterial::new(base_color, metallic, roughness));
+            root.add_child(&sphere);
+        }
+    }
+    update_node_transformations(&mut root, &glm::identity());
+    root
+}
+
+unsafe fn build_scene(scene: &ReferenceScene, world: &World, primitive_meshes: &[GpuMesh]) -> Node {
+    match scene.name {
+        "terrain" => {
+            let mut root = SceneNode::new();
+            root.add_child(&world::sun());
+            let mut surface = SceneNode::from_mesh(&primitive_meshes[9], glm::vec3(0., 0., 0.));
+            surface.normal_map = world.lunar_normal_map;
+            root.add_child(&surface);
+            update_node_transformations(&mut root, &glm::identity());
+            root
+        }
+        "helicopter" => {
+            let mut root = SceneNode::new();
+            root.add_child(&world::sun());
+            let mut heli = stand_in_helicopter(primitive_meshes);
+            world::spin_rotors(&mut heli, 0, scene.time);
+            heli.body.set_position(glm::vec3(0., 1.5, 0.));
+            root.add_child(&heli.body);
+            // Ground for the helicopter to cast its shadow on
+            let mut ground = SceneNode::from_mesh(&primitive_meshes[0], glm::vec3(0., 0., 0.));
+            ground.scale = glm::vec3(3., 1., 3.);
+            root.add_child(&ground);
+            update_node_transformations(&mut root, &glm::identity());
+            root
+        }
+        "primitives" => primitives_scene(primitive_meshes, scene.time),
+        "materials" => materials_scene(primitive_meshes),
+        name => panic!("No reference scene called {}", name),
+    }
+}
+
+// The helicopter built from the stand-in parts of `primitive_meshes`, since the model isn't part
+// of the repository
+unsafe fn stand_in_helicopter(meshes: &[GpuMesh]) -> HelicopterStruct {
+    world::assemble_helicopter(
+        SceneNode::from_mesh(&meshes[10], glm::vec3(0., 0., 0.)),
+        SceneNode::from_mesh(&meshes[11], glm::vec3(0., 0., 0.)),
+        SceneNode::from_mesh(&meshes[12], glm::vec3(0.35, 2.3, 10.4)),
+        SceneNode::from_mesh(&meshes[13], glm::vec3(0., 0., 0.)),
+    )
+}
+
+// A helicopter at `offset` lit by the sun and by a lamp that flies along with it
+unsafe fn lit_helicopter(meshes: &[GpuMesh], offset: &glm::Vec3) -> Node {
+    let mut root = SceneNode::new();
+    root.add_child(&world::sun());
+    let mut heli = stand_in_helicopter(meshes);
+    heli.body.set_position(*offset);
+    heli.body.set_rotation(glm::vec3(0., 0.7, 0.));
+    world::spin_rotors(&mut heli, 0, 0.3);
+    let mut lamp = SceneNode::new();
+    lamp.set_position(glm::vec3(4., 5., 6.));
+    lamp.light = Some(Light::point(glm::vec3(0.3, 0.5, 1.), 20.));
+    heli.body.add_child(&lamp);
+    root.add_child(&heli.body);
+    update_node_transformations(&mut root, &glm::identity());
+    root
+}
+
+/// Renders a helicopter at the origin and one moved far away, each seen from the same place
+/// relative to it. Lighting must not depend on where things are, so the two have to match.
+unsafe fn translation_check(
+    world: &World,
+    primitive_meshes: &[GpuMesh],
+    target: &RenderTarget,
+) -> Result<Comparison, String> {
+    let (camera, look_at) = (glm::vec3(14., 9., 16.), glm::vec3(0., 2., 2.));
+    let offset = glm::make_vec3(&TRANSLATION_OFFSET);
+    let at_origin = lit_helicopter(primitive_meshes, &glm::zero());
+    let moved = lit_helicopter(primitive_meshes, &offset);
+    let at_origin = render_image(world, &at_origin, target, &camera, &look_at);
+    let moved = render_image(world, &moved, target, &(camera + offset), &(look_at + offset));
+    compare(&at_origin, &moved)
+}
+
+/// Renders every reference scene and compares it with its golden image, or replaces the golden
+/// images if `update` is set. The renders of failing scenes and their diffs go to `output`.
+/// Returns whether every scene passed.
+pub fn run(update: bool, output: &Path) -> Result<bool, String> {
+    let _gl = HeadlessGl::new(SIZE, SIZE)?;
+    let assets = SceneAssets::load(None, None, |_| thread::sleep(Duration::from_millis(10)));
+    let world = unsafe { World::upload(assets) };
+    let primitive_meshes = unsafe { primitive_meshes() };
+    // Without multisampling, which resolves differently between drivers
+    let target = unsafe { still_target(SIZE, SIZE, 1)? };
+
+    let mut failures = 0;
+    for scene in &SCENES {
+        let image = unsafe {
+            let root = build_scene(scene, &world, &primitive_meshes);
+            render_image(&world, &root, &target, &scene.camera.into(), &scene.look_at.into())
+        };
+        let golden_path = Path::new(GOLDEN_DIR).join(format!("{}.png", scene.name));
+        if update {
+            fs::create_dir_all(GOLDEN_DIR).map_err(|e| format!("Failed to create {}: {}", GOLDEN_DIR, e))?;
+            image
+                .save(&golden_path)
+                .map_err(|e| format!("Failed to write {}: {}", golden_path.display(), e))?;
+            println!("{}: updated {}", scene.name, golden_path.display());
+            continue;
+        }
+
+        let result = image::open(&golden_path)
+            .map_err(|e| format!("can't read {} ({}), make it with --update-golden", golden_path.display(), e))
+            .and_then(|golden| compare(&golden.into_rgba8(), &image));
+        let comparison = match result {
+            Ok(comparison) if comparison.passed() => {
+                println!("{}: ok, {} pixels changed", scene.name, comparison.changed_pixels);
+                continue;
+            }
+            Ok(comparison) => {
+                println!(
+                    "{}: FAILED, {} of {} pixels changed",
+                    scene.name, comparison.changed_pixels, comparison.total_pixels
+                );
+                Some(comparison)
+            }
+            Err(e) => {
+                println!("{}: FAILED, {}", scene.name, e);
+                None
+            }
+        };
+        failures += 1;
+
+        fs::create_dir_all(output).map_err(|e| format!("Failed to create {}: {}", output.display(), e))?;
+        let actual_path = output.join(format!("{}.png", scene.name));
+        image
+            .save(&actual_path)
+            .map_err(|e| format!("Failed to write {}: {}", actual_path.display(), e))?;
+        if let Some(comparison) = comparison {
+            let diff_path = output.join(format!("{}.diff.png", scene.name));
+            comparison
+                .diff
+                .save(&diff_path)
+                .map_err(|e| format!("Failed to write {}: {}", diff_path.display(), e))?;
+            println!("    see {} and {}", actual_path.display(), diff_path.display());
+        } else {
+            println!("    see {}", actual_path.display());
+        }
+    }
+    if update {
+        return Ok(true);
+    }
+    println!("{} of {} reference scenes passed", SCENES.len() - failures, SCENES.len());
+
+    let comparison = unsafe { translation_check(&world, &primitive_meshes, &target)? };
+    if comparison.passed() {
+        println!("translation: ok, {} pixels changed", comparison.changed_pixels);
+    } else {
+        failures += 1;
+        println!(
+            "translation: FAILED, a moved helicopter lights differently, {} of {} pixels changed",
+            comparison.changed_pixels, comparison.total_pixels
+        );
+        fs::create_dir_all(output).map_err(|e| format!("Failed to create {}: {}", output.display(), e))?;
+        let diff_path = output.join("translation.diff.png");
+        comparison
+            .diff
+            .save(&diff_path)
+            .map_err(|e| format!("Failed to write {}: {}", diff_path.display(), e))?;
+        println!("    see {}", diff_path.display());
+    }
+    Ok(failures == 0)
+}
@@ -0,0 +1,165 @@
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+use std::ptr;
+
+use crate::mesh::{Mesh, Primitive};
+use crate::vertex_layout::VertexLayout;
+
+fn gl_mode(primitive: Primitive) -> gl::types::GLenum {
+    match primitive {
+        Primitive::Triangles => gl::TRIANGLES,
+        Primitive::Lines => gl::LINES,
+        Primitive::LineStrip => gl::LINE_STRIP,
+        Primitive::Points => gl::POINTS,
+    }
+}
+
+// Index data in the smallest type that can address every vertex, along with its GL type
+fn index_bytes(indices: &[u32], index_type: gl::types::GLenum) -> Vec<u8> {
+    if index_type == gl::UNSIGNED_SHORT {
+        indices.iter().flat_map(|&i| (i as u16).to_ne_bytes().to_vec()).collect()
+    } else {
+        indices.iter().flat_map(|&i| i.to_ne_bytes().to_vec()).collect()
+    }
+}
+
+fn index_size(index_type: gl::types::GLenum) -> usize {
+    if index_type == gl::UNSIGNED_SHORT { 2 } else { 4 }
+}
+
+/// A mesh uploaded to the GPU. Owns its vertex array and buffers, and deletes them when
+/// dropped. GL objects belong to the context's thread, so this is deliberately not `Send`.
+pub struct GpuMesh {
+    pub vao: u32,
+    pub vertex_buffers: Vec<u32>,
+    // 0 if the mesh has no indices
+    pub index_buffer: u32,
+    pub index_count: i32,
+    // gl::UNSIGNED_SHORT when every vertex can be reached with 16 bits, otherwise gl::UNSIGNED_INT
+    pub index_type: gl::types::GLenum,
+    // What gets drawn, e.g. gl::TRIANGLES
+    pub mode: gl::types::GLenum,
+    pub vertex_count: usize,
+    // How many indices the index buffer has room for
+    index_capacity: usize,
+    layout: VertexLayout,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl GpuMesh {
+    /// Uploads a mesh that is never going to change
+    pub unsafe fn new(mesh: &Mesh, layout: &VertexLayout) -> Self {
+        GpuMesh::upload(mesh, layout, gl::STATIC_DRAW)
+    }
+
+    /// Uploads a mesh whose vertices or indices are going to be updated, see `update_vertices`
+    #[allow(dead_code)]
+    pub unsafe fn new_dynamic(mesh: &Mesh, layout: &VertexLayout) -> Self {
+        GpuMesh::upload(mesh, layout, gl::DYNAMIC_DRAW)
+    }
+
+    unsafe fn upload(mesh: &Mesh, layout: &VertexLayout, usage: gl::types::GLenum) -> Self {
+        let mut vao: u32 = 0;
+        gl::GenVertexArrays(1, &mut vao);
+        gl::BindVertexArray(vao);
+
+        let vertex_buffers = layout.upload(mesh, usage);
+
+        // The element array binding is part of the VAO state, so it has to be bound while the VAO is
+        let index_type = if mesh.vertex_count() <= u16::MAX as usize + 1 { gl::UNSIGNED_SHORT } else { gl::UNSIGNED_INT };
+        // Meshes without indices, like point clouds, are drawn straight from the vertex buffers
+        let mut index_buffer: u32 = 0;
+        if !mesh.indices.is_empty() {
+            let indices = index_bytes(&mesh.indices, index_type);
+            gl::GenBuffers(1, &mut index_buffer);
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_buffer);
+            gl::BufferData(
+                gl::ELEMENT_ARRAY_BUFFER,
+                indices.len() as isize,
+                indices.as_ptr() as *const c_void,
+                usage,
+            );
+        }
+        gl::BindVertexArray(0);
+
+        GpuMesh {
+            vao,
+            vertex_buffers,
+            index_buffer,
+            index_count: mesh.index_count,
+            index_type,
+            mode: gl_mode(mesh.primitive),
+            vertex_count: mesh.vertex_count(),
+            index_capacity: mesh.indices.len(),
+            layout: layout.clone(),
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Rewrites `count` vertices starting at `first` with the mesh's current values. The
+    /// vertex buffers keep their size, so the mesh can't have gained vertices past the end.
+    #[allow(dead_code)]
+    pub unsafe fn update_vertices(&self, mesh: &Mesh, first: usize, count: usize) {
+        if first + count > self.vertex_count || first + count > mesh.vertex_count() {
+            panic!(
+                "Vertex update {}..{} is out of range for a GPU mesh with {} vertices",
+                first, first + count, self.vertex_count
+            );
+        }
+        let buffers = self.layout.build_range(mesh, first..first + count);
+        for (&vbo, buffer) in self.vertex_buffers.iter().zip(&buffers) {
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferSubData(
+                gl::ARRAY_BUFFER,
+                (first * buffer.stride) as isize,
+                buffer.bytes.len() as isize,
+                buffer.bytes.as_ptr() as *const c_void,
+            );
+        }
+        gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+    }
+
+    /// Replaces indices starting at `first`, and draws the first `index_count` afterwards
+    #[allow(dead_code)]
+    pub unsafe fn update_indices(&mut self, indices: &[u32], first: usize, index_count: usize) {
+        if self.index_buffer == 0 {
+            panic!("Can't update the indices of a GPU mesh that was made without any");
+        }
+        if first + indices.len() > self.index_capacity || index_count > self.index_capacity {
+            panic!("Index update doesn't fit a GPU mesh with room for {} indices", self.index_capacity);
+        }
+        if let Some(&index) = indices.iter().find(|&&i| i as usize >= self.vertex_count) {
+            panic!("Index {} is out of range for a GPU mesh with {} vertices", index, self.vertex_count);
+        }
+        // The index buffer binding is VAO state, binding it on its own would attach it to whatever VAO is bound
+        let bytes = index_bytes(indices, self.index_type);
+        gl::BindVertexArray(self.vao);
+        gl::BufferSubData(
+            gl::ELEMENT_ARRAY_BUFFER,
+            (first * index_size(self.index_type)) as isize,
+            bytes.len() as isize,
+            bytes.as_ptr() as *const c_void,
+        );
+        gl::BindVertexArray(0);
+        self.index_count = index_count as i32;
+    }
+
+    pub unsafe fn draw(&self) {
+        gl::BindVertexArray(self.vao);
+        if self.index_buffer == 0 {
+            gl::DrawArrays(self.mode, 0, self.vertex_count as i32);
+        } else {
+            gl::DrawElements(self.mode, self.index_count, self.index_type, ptr::null());
+        }
+    }
+}
+
+impl Drop for GpuMesh {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteBuffers(self.vertex_buffers.len() as i32, self.vertex_buffers.as_ptr());
+            gl::DeleteBuffers(1, &self.index_buffer);
+        }
+    }
+}
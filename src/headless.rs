@@ -0,0 +1,359 @@
+extern crate nalgebra_glm as glm;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use glutin::event_loop::EventLoop;
+use glutin::{Api, ContextBuilder, GlProfile, GlRequest, NotCurrent, PossiblyCurrent};
+use image::RgbaImage;
+
+use crate::cli::RenderOptions;
+use crate::post::PostProcessing;
+use crate::render_target::{ColorFormat, DepthFormat, RenderTarget, RenderTargetBuilder};
+use crate::scene_graph::SceneNode;
+use crate::world::{self, Camera, SceneAssets, World};
+
+// Rendering stills without a window, for CI and for servers without a screen
+
+fn context_builder() -> ContextBuilder<'static, NotCurrent> {
+    ContextBuilder::new()
+        .with_gl(GlRequest::Specific(Api::OpenGl, (4, 3)))
+        .with_gl_profile(GlProfile::Core)
+}
+
+// Only held on to, dropping it destroys the context. There is just the one, so its size
+// doesn't matter.
+#[allow(dead_code, clippy::large_enum_variant)]
+enum HeadlessContext {
+    // The event loop, if any, has to outlive the context made from it
+    Glutin(glutin::Context<PossiblyCurrent>, Option<EventLoop<()>>),
+    Egl(egl::EglContext),
+}
+
+/// Makes a glutin context current and loads the functions from it
+unsafe fn make_current(
+    context: glutin::Context<NotCurrent>,
+    event_loop: Option<EventLoop<()>>,
+) -> Result<HeadlessContext, String> {
+    let context = context
+        .make_current()
+        .map_err(|(_, e)| format!("Failed to make the headless context current: {}", e))?;
+    gl::load_with(|symbol| context.get_proc_address(symbol) as *const _);
+    Ok(HeadlessContext::Glutin(context, event_loop))
+}
+
+// The platforms where Mesa and the GPU drivers come with libEGL, which can make a context
+// without any display
+#[cfg(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+mod egl {
+    extern crate khronos_egl as egl;
+
+    use glutin::dpi::PhysicalSize;
+    use glutin::event_loop::EventLoop;
+
+    use super::{context_builder, make_current, HeadlessContext};
+
+    // Platforms for eglGetPlatformDisplay, from EGL_MESA_platform_surfaceless and
+    // EGL_EXT_platform_device
+    const PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+    const PLATFORM_DEVICE_EXT: egl::Enum = 0x313F;
+
+    /// A context made straight through libEGL, drawing to a pbuffer. Unlike winit's, it needs no
+    /// display server, only a GPU or Mesa's software renderer.
+    pub struct EglContext {
+        egl: egl::DynamicInstance<egl::EGL1_5>,
+        display: egl::Display,
+        surface: egl::Surface,
+        context: egl::Context,
+    }
+
+    impl EglContext {
+        /// Tries Mesa's surfaceless platform, then every EGL device, and makes the first context
+        /// that works current
+        unsafe fn new(width: u32, height: u32) -> Result<Self, String> {
+            let egl = egl::DynamicInstance::<egl::EGL1_5>::load_required()
+                .map_err(|e| format!("Failed to load libEGL: {}", e))?;
+            let extensions = egl
+                .query_string(None, egl::EXTENSIONS)
+                .map(|e| e.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            let mut displays = vec![];
+            if extensions.contains("EGL_MESA_platform_surfaceless") {
+                displays.push((PLATFORM_SURFACELESS_MESA, egl::DEFAULT_DISPLAY));
+            }
+            if extensions.contains("EGL_EXT_platform_device") {
+                displays.extend(query_devices(&egl).into_iter().map(|device| (PLATFORM_DEVICE_EXT, device)));
+            }
+            if displays.is_empty() {
+                return Err("libEGL supports neither surfaceless nor device platforms".to_string());
+            }
+            let mut errors = vec![];
+            for (platform, native_display) in displays {
+                let display = match egl.get_platform_display(platform, native_display, &[egl::ATTRIB_NONE]) {
+                    Ok(display) => display,
+                    Err(e) => {
+                        errors.push(e.to_string());
+                        continue;
+                    }
+                };
+                match create_egl_context(&egl, display, width, height) {
+                    Ok((surface, context)) => return Ok(EglContext { egl, display, surface, context }),
+                    Err(e) => {
+                        errors.push(e.to_string());
+                        let _ = egl.terminate(display);
+                    }
+                }
+            }
+            Err(format!("Failed to create an EGL context ({})", errors.join(", ")))
+        }
+
+        unsafe fn load_functions(&self) {
+            gl::load_with(|symbol| self.egl.get_proc_address(symbol).map_or(std::ptr::null(), |f| f as *const _));
+        }
+    }
+
+    impl Drop for EglContext {
+        fn drop(&mut self) {
+            let egl = &self.egl;
+            let _ = egl.make_current(self.display, None, None, None);
+            let _ = egl.destroy_surface(self.display, self.surface);
+            let _ = egl.destroy_context(self.display, self.context);
+            let _ = egl.terminate(self.display);
+        }
+    }
+
+    /// The GPUs EGL knows of, from EGL_EXT_device_enumeration
+    unsafe fn query_devices(egl: &egl::DynamicInstance<egl::EGL1_5>) -> Vec<egl::NativeDisplayType> {
+        type QueryDevices = unsafe extern "system" fn(i32, *mut egl::NativeDisplayType, *mut i32) -> egl::Boolean;
+        let query = match egl.get_proc_address("eglQueryDevicesEXT") {
+            Some(f) => std::mem::transmute::<extern "system" fn(), QueryDevices>(f),
+            None => return vec![],
+        };
+        let mut devices = vec![std::ptr::null_mut(); 16];
+        let mut count = 0;
+        if query(devices.len() as i32, devices.as_mut_ptr(), &mut count) != egl::TRUE {
+            return vec![];
+        }
+        devices.truncate(count.max(0) as usize);
+        devices
+    }
+
+    /// A desktop OpenGL 4.3 core context on `display`, current with a pbuffer of the given size
+    unsafe fn create_egl_context(
+        egl: &egl::DynamicInstance<egl::EGL1_5>,
+        display: egl::Display,
+        width: u32,
+        height: u32,
+    ) -> Result<(egl::Surface, egl::Context), egl::Error> {
+        egl.initialize(display)?;
+        egl.bind_api(egl::OPENGL_API)?;
+        #[rustfmt::skip]
+        let config_attributes = [
+            egl::SURFACE_TYPE, egl::PBUFFER_BIT,
+            egl::RENDERABLE_TYPE, egl::OPENGL_BIT,
+            egl::RED_SIZE, 8,
+            egl::GREEN_SIZE, 8,
+            egl::BLUE_SIZE, 8,
+            egl::ALPHA_SIZE, 8,
+            egl::DEPTH_SIZE, 24,
+            egl::NONE,
+        ];
+        let config = egl
+            .choose_first_config(display, &config_attributes)?
+            .ok_or(egl::Error::BadConfig)?;
+        #[rustfmt::skip]
+        let context_attributes = [
+            egl::CONTEXT_MAJOR_VERSION, 4,
+            egl::CONTEXT_MINOR_VERSION, 3,
+            egl::CONTEXT_OPENGL_PROFILE_MASK, egl::CONTEXT_OPENGL_CORE_PROFILE_BIT,
+            egl::NONE,
+        ];
+        let context = egl.create_context(display, config, None, &context_attributes)?;
+        let surface_attributes = [egl::WIDTH, width as i32, egl::HEIGHT, height as i32, egl::NONE];
+        let surface = match egl.create_pbuffer_surface(display, config, &surface_attributes) {
+            Ok(surface) => surface,
+            Err(e) => {
+                let _ = egl.destroy_context(display, context);
+                return Err(e);
+            }
+        };
+        if let Err(e) = egl.make_current(display, Some(surface), Some(surface), Some(context)) {
+            let _ = egl.destroy_surface(display, surface);
+            let _ = egl.destroy_context(display, context);
+            return Err(e);
+        }
+        Ok((surface, context))
+    }
+
+    /// Makes a context current and loads the functions from it. Tries an EGL context made
+    /// straight through libEGL first, on Mesa's surfaceless platform or on a GPU found with
+    /// EGL's device enumeration, which needs no display. Then OSMesa, which renders with
+    /// llvmpipe but is gone from current Mesa releases. Last a context through winit, which
+    /// needs a display, e.g. one from `xvfb-run`.
+    pub unsafe fn create_context(width: u32, height: u32) -> Result<HeadlessContext, String> {
+        use glutin::platform::unix::{EventLoopExtUnix, HeadlessContextExt};
+
+        let egl_error = match EglContext::new(width, height) {
+            Ok(context) => {
+                context.load_functions();
+                return Ok(HeadlessContext::Egl(context));
+            }
+            Err(e) => e,
+        };
+        let osmesa_error = match context_builder().build_osmesa(PhysicalSize::new(width, height)) {
+            Ok(context) => return make_current(context, None),
+            Err(e) => e,
+        };
+        // Making an event loop without a display panics
+        if std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+            return Err(format!(
+                "{}, failed to create an OSMesa context ({}), and there is no display to create one with",
+                egl_error, osmesa_error
+            ));
+        }
+        let event_loop = EventLoop::new_any_thread();
+        let context = context_builder()
+            .build_surfaceless(&event_loop)
+            .or_else(|_| context_builder().build_headless(&event_loop, PhysicalSize::new(width, height)))
+            .map_err(|e| format!("{}, and failed to create a headless context: {}", egl_error, e))?;
+        make_current(context, Some(event_loop))
+    }
+}
+
+// Elsewhere there is no EGL path, only winit's headless context
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+mod egl {
+    use glutin::dpi::PhysicalSize;
+    use glutin::event_loop::EventLoop;
+
+    use super::{context_builder, make_current, HeadlessContext};
+
+    // Never made
+    pub enum EglContext {}
+
+    pub unsafe fn create_context(width: u32, height: u32) -> Result<HeadlessContext, String> {
+        let event_loop = EventLoop::new();
+        let context = context_builder()
+            .build_headless(&event_loop, PhysicalSize::new(width, height))
+            .map_err(|e| format!("Failed to create a headless context: {}", e))?;
+        make_current(context, Some(event_loop))
+    }
+}
+
+/// An OpenGL context without a window, current on this thread with the functions loaded
+pub struct HeadlessGl {
+    _context: HeadlessContext,
+}
+
+impl HeadlessGl {
+    pub fn new(width: u32, height: u32) -> Result<Self, String> {
+        let context = unsafe { egl::create_context(width, height)? };
+        unsafe { world::setup_gl() };
+        Ok(HeadlessGl { _context: context })
+    }
+}
+
+/// A render target to draw stills into, with as many samples per pixel as asked for
+pub unsafe fn still_target(width: u32, height: u32, samples: u32) -> Result<RenderTarget, String> {
+    RenderTargetBuilder::new(width, height)
+        .color(ColorFormat::Rgba8)
+        .depth(DepthFormat::Depth24Stencil8)
+        .samples(samples)
+        .build()
+}
+
+/// Draws a scene into `target` as seen from `camera`, and reads it back
+pub unsafe fn render_image(
+    world: &World,
+    root: &SceneNode,
+    target: &RenderTarget,
+    camera: &glm::Vec3,
+    look_at: &glm::Vec3,
+) -> RgbaImage {
+    render_post_processed(world, root, target, None, camera, look_at)
+}
+
+/// Like `render_image`, drawing the scene through `post` first if there is one
+pub unsafe fn render_post_processed(
+    world: &World,
+    root: &SceneNode,
+    target: &RenderTarget,
+    post: Option<&PostProcessing>,
+    camera: &glm::Vec3,
+    look_at: &glm::Vec3,
+) -> RgbaImage {
+    let aspect = target.width as f32 / target.height as f32;
+    let camera = Camera::looking_at(*camera, *look_at, aspect);
+    match post {
+        Some(post) => {
+            post.begin();
+            world.draw(root, &camera);
+            post.finish(target.fbo, target.width, target.height);
+        }
+        None => {
+            target.bind();
+            world.draw(root, &camera);
+        }
+    }
+    target.read_pixels()
+}
+
+/// Renders `options.frames` frames of the scene into an offscreen framebuffer, and writes
+/// them to `options.output` as numbered PNG files
+pub fn render(options: &RenderOptions, point_cloud_path: Option<String>) -> Result<(), String> {
+    if options.camera == options.look_at {
+        return Err("The camera can't look at the point it is at".to_string());
+    }
+    let _gl = HeadlessGl::new(options.width, options.height)?;
+
+    // Nothing to show while loading, so just wait for the workers
+    let assets = SceneAssets::load(point_cloud_path, options.skybox.clone(), |_| thread::sleep(Duration::from_millis(10)));
+    let mut world = unsafe { World::upload(assets) };
+    world.max_lights = options.max_lights;
+    let post = if options.post_processing {
+        let post = unsafe { PostProcessing::new(options.post.clone(), options.width, options.height, options.samples)? };
+        world.linear_output = true;
+        Some(post)
+    } else {
+        None
+    };
+    // With post-processing the scene is antialiased before it gets to the target
+    let samples = if post.is_some() { 1 } else { options.samples };
+    let target = unsafe { still_target(options.width, options.height, samples)? };
+    fs::create_dir_all(&options.output)
+        .map_err(|e| format!("Failed to create {}: {}", options.output.display(), e))?;
+
+    // Keep the camera from sinking into the ground, like the windowed camera
+    let mut camera = options.camera;
+    if let Some(ground) = world.assets.lunar_surface.height_at(camera.x, camera.z) {
+        camera.y = camera.y.max(ground + 2.);
+    }
+
+    for frame in 0..options.frames {
+        let elapsed = options.time + frame as f32 * options.frame_step;
+        let image = unsafe {
+            let root_scene = world.build_scene(elapsed, &camera);
+            render_post_processed(&world, &root_scene, &target, post.as_ref(), &camera, &options.look_at)
+        };
+        let path = options.output.join(format!("frame_{:04}.png", frame));
+        image
+            .save(&path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,177 @@
+extern crate nalgebra_glm as glm;
+
+use std::os::raw::c_void;
+
+use crate::scene_graph::SceneNode;
+
+// Lights are attached to scene nodes and move with them. Every frame they are gathered from the
+// scene graph in world space and uploaded to a shader storage buffer, so the shader has no
+// fixed limit, only the one `select` applies.
+
+// Binding point of the light buffer, has to match simple.frag
+const LIGHT_BUFFER_BINDING: u32 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    /// Shines along its direction everywhere, like the sun
+    Directional,
+    /// Shines in every direction from its position, fading with distance
+    Point,
+    /// A point light limited to a cone around its direction. Full brightness inside the inner
+    /// angle, fading to nothing at the outer one. Angles are from the center, in radians.
+    Spot { inner_angle: f32, outer_angle: f32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: glm::Vec3,
+    pub intensity: f32,
+    // Which way the light shines in its node's space, for directional and spot lights
+    pub direction: glm::Vec3,
+    // Constant, linear and quadratic falloff with distance, for point and spot lights
+    pub attenuation: glm::Vec3,
+    // Only directional lights have shadow maps, see shadows.rs
+    pub casts_shadows: bool,
+}
+
+// Falloff that is down to a few percent of the brightness at `range`
+fn attenuation_for_range(range: f32) -> glm::Vec3 {
+    glm::vec3(1., 4.5 / range, 75. / (range * range))
+}
+
+impl Light {
+    pub fn directional(direction: glm::Vec3, color: glm::Vec3) -> Self {
+        Light {
+            kind: LightKind::Directional,
+            color,
+            intensity: 1.,
+            direction: glm::normalize(&direction),
+            attenuation: glm::vec3(1., 0., 0.),
+            casts_shadows: false,
+        }
+    }
+
+    pub fn point(color: glm::Vec3, range: f32) -> Self {
+        Light {
+            kind: LightKind::Point,
+            color,
+            intensity: 1.,
+            direction: glm::vec3(0., -1., 0.),
+            attenuation: attenuation_for_range(range),
+            casts_shadows: false,
+        }
+    }
+
+    pub fn spot(direction: glm::Vec3, color: glm::Vec3, range: f32, inner_angle: f32, outer_angle: f32) -> Self {
+        Light {
+            kind: LightKind::Spot { inner_angle, outer_angle: outer_angle.max(inner_angle) },
+            color,
+            intensity: 1.,
+            direction: glm::normalize(&direction),
+            attenuation: attenuation_for_range(range),
+            casts_shadows: false,
+        }
+    }
+}
+
+/// A light where its node put it
+#[derive(Clone, Copy, Debug)]
+pub struct WorldLight {
+    pub light: Light,
+    pub position: glm::Vec3,
+    pub direction: glm::Vec3,
+}
+
+impl WorldLight {
+    /// How bright the light is at `point`, roughly, to pick the lights that matter most
+    pub fn brightness_at(&self, point: &glm::Vec3) -> f32 {
+        let brightness = self.light.intensity * glm::comp_max(&self.light.color);
+        if self.light.kind == LightKind::Directional {
+            return brightness;
+        }
+        let d = glm::distance(&self.position, point);
+        let a = self.light.attenuation;
+        brightness / (a.x + a.y * d + a.z * d * d)
+    }
+}
+
+/// Every light in the scene graph below `root`, with its transformations already updated
+pub unsafe fn collect_lights(root: &SceneNode, lights: &mut Vec<WorldLight>) {
+    if let Some(light) = root.light {
+        let transformation = root.current_transformation_matrix;
+        let position = transformation * glm::vec4(0., 0., 0., 1.);
+        let direction = glm::mat4_to_mat3(&transformation) * light.direction;
+        lights.push(WorldLight { light, position: position.xyz(), direction: glm::normalize(&direction) });
+    }
+    for &child in &root.children {
+        collect_lights(&*child, lights);
+    }
+}
+
+/// Keeps the `max_lights` lights that are brightest at `point`, directional lights first
+pub fn select(lights: &mut Vec<WorldLight>, point: &glm::Vec3, max_lights: usize) {
+    lights.sort_by(|a, b| {
+        let directional = |l: &WorldLight| l.light.kind == LightKind::Directional;
+        directional(b)
+            .cmp(&directional(a))
+            .then(b.brightness_at(point).partial_cmp(&a.brightness_at(point)).unwrap_or(std::cmp::Ordering::Equal))
+    });
+    lights.truncate(max_lights);
+}
+
+// Four vec4s per light, laid out like the Light struct in simple.frag
+fn light_data(lights: &[WorldLight]) -> Vec<f32> {
+    let mut data = Vec::with_capacity(lights.len() * 16);
+    for l in lights {
+        let (kind, cos_inner, cos_outer) = match l.light.kind {
+            LightKind::Directional => (0., 1., 1.),
+            LightKind::Point => (1., -1., -1.),
+            LightKind::Spot { inner_angle, outer_angle } => (2., inner_angle.cos(), outer_angle.cos()),
+        };
+        let color = l.light.color * l.light.intensity;
+        let a = l.light.attenuation;
+        data.extend_from_slice(&[l.position.x, l.position.y, l.position.z, kind]);
+        data.extend_from_slice(&[l.direction.x, l.direction.y, l.direction.z, cos_inner]);
+        data.extend_from_slice(&[color.x, color.y, color.z, cos_outer]);
+        data.extend_from_slice(&[a.x, a.y, a.z, 0.]);
+    }
+    data
+}
+
+/// The shader storage buffer the lights are uploaded to
+pub struct LightBuffer {
+    pub ssbo: u32,
+}
+
+impl LightBuffer {
+    pub unsafe fn new() -> Self {
+        let mut ssbo = 0;
+        gl::GenBuffers(1, &mut ssbo);
+        LightBuffer { ssbo }
+    }
+
+    /// Replaces the lights and binds the buffer for the next draws. The program has to be in
+    /// use, since the light count is a uniform.
+    pub unsafe fn upload(&self, lights: &[WorldLight], light_count_location: i32) {
+        // An empty buffer can't be bound, so there is always room for at least one light
+        let mut data = light_data(lights);
+        data.resize(data.len().max(16), 0.);
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, self.ssbo);
+        gl::BufferData(
+            gl::SHADER_STORAGE_BUFFER,
+            (data.len() * std::mem::size_of::<f32>()) as isize,
+            data.as_ptr() as *const c_void,
+            gl::STREAM_DRAW,
+        );
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, 0);
+        gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, LIGHT_BUFFER_BINDING, self.ssbo);
+        gl::Uniform1i(light_count_location, lights.len() as i32);
+    }
+}
+
+impl Drop for LightBuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.ssbo) };
+    }
+}
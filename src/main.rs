@@ -1,15 +1,39 @@
 extern crate nalgebra_glm as glm;
 // use gl::types::*;
 
-use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::{mem, os::raw::c_void, ptr};
-
+use std::{mem, os::raw::c_void};
+
+mod assets;
+mod capture;
+mod cli;
+mod environment;
+mod export;
+mod golden;
+mod gpu_mesh;
+mod headless;
+mod light;
+mod material;
 mod mesh;
+mod mesh_cache;
+mod optimize;
+mod point_cloud;
+mod post;
+mod primitives;
+mod render_target;
 mod scene_graph;
 mod shader;
+mod shadows;
+mod simplify;
+mod skybox;
+mod terrain;
+mod texture;
 mod util;
+mod vertex_layout;
+mod world;
 
 use glutin::event::{
     DeviceEvent,
@@ -20,16 +44,16 @@ use glutin::event::{
 };
 use glutin::event_loop::ControlFlow;
 
-use mesh::{Helicopter, Terrain};
-use scene_graph::{draw_scene, update_node_transformations, HelicopterStruct, SceneNode};
+use world::{Camera, SceneAssets, World};
 
 const SCREEN_W: u32 = 800;
 const SCREEN_H: u32 = 800;
 
 // == // Helper functions to make interacting with OpenGL a little bit prettier. You *WILL* need these! // == //
 // The names should be pretty self explanatory
+#[allow(dead_code)]
 fn byte_size_of_array<T>(val: &[T]) -> isize {
-    std::mem::size_of_val(&val[..]) as isize
+    std::mem::size_of_val(val) as isize
 }
 
 // Get the OpenGL-compatible pointer to an arbitrary array of numbers
@@ -53,82 +77,34 @@ fn offset<T>(n: u32) -> *const c_void {
 // Get a null pointer (equivalent to an offset of 0)
 // ptr::null()
 
-// == // Modify and complete the function below for the first task
-unsafe fn create_vao(
-    vertices: &Vec<f32>,
-    colors: &Vec<f32>,
-    normals: &Vec<f32>,
-    indices: &Vec<u32>,
-    mut vao: gl::types::GLuint,
-) -> u32 {
-    // gl::BindBuffer(gl::ARRAY_BUFFER, 0);
-    gl::GenVertexArrays(1, &mut vao);
-    gl::BindVertexArray(vao);
-    let mut data: Vec<f32> = Vec::new();
-    for i in 0..vertices.len() / 3 {
-        for j in 0..3 {
-            data.push(vertices[3 * i + j])
-        }
-
-        for j in 0..4 {
-            data.push(colors[4 * i + j])
+fn main() {
+    let options = cli::Options::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if options.golden || options.update_golden {
+        let output = options.render.output.join("golden");
+        match golden::run(options.update_golden, &output) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Golden image tests failed to run: {}", e);
+                std::process::exit(1);
+            }
         }
-        for j in 0..3 {
-            data.push(normals[3 * i + j])
+    }
+    if options.headless {
+        if let Err(e) = headless::render(&options.render, options.point_cloud) {
+            eprintln!("Headless rendering failed: {}", e);
+            std::process::exit(1);
         }
+        return;
     }
 
-    let mut vbo: gl::types::GLuint = 0;
-    gl::GenBuffers(1, &mut vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
-    gl::BufferData(
-        gl::ARRAY_BUFFER,                          // target
-        byte_size_of_array(&data),                 // size of data in bytes
-        data.as_ptr() as *const gl::types::GLvoid, // pointer to data
-        gl::STATIC_DRAW,                           // usage
-    );
-
-    gl::EnableVertexAttribArray(0); // this is "layout (location = 0)" in vertex shader
-    gl::VertexAttribPointer(
-        0,         // index of the generic vertex attribute ("layout (location = 0)")
-        3,         // the number of components per generic vertex attribute
-        gl::FLOAT, // data type
-        gl::FALSE, // normalized (int-to-float conversion)
-        (10 * std::mem::size_of::<f32>()) as gl::types::GLint, // stride (byte offset between consecutive attributes)
-        std::ptr::null(),                                      // offset of the first component
-    );
-    gl::EnableVertexAttribArray(1); // this is "layout (location = 1)" in vertex shader
-    gl::VertexAttribPointer(
-        1,         // index of the generic vertex attribute ("layout (location = 0)")
-        4,         // the number of components per generic vertex attribute
-        gl::FLOAT, // data type
-        gl::FALSE, // normalized (int-to-float conversion)
-        (10 * std::mem::size_of::<f32>()) as gl::types::GLint, // stride (byte offset between consecutive attributes)
-        offset::<f32>(3),                                      // offset of the first component
-    );
-
-    gl::EnableVertexAttribArray(2); // this is "layout (location = 1)" in vertex shader
-    gl::VertexAttribPointer(
-        2,         // index of the generic vertex attribute ("layout (location = 0)")
-        4,         // the number of components per generic vertex attribute
-        gl::FLOAT, // data type
-        gl::FALSE, // normalized (int-to-float conversion)
-        (10 * std::mem::size_of::<f32>()) as gl::types::GLint, // stride (byte offset between consecutive attributes)
-        offset::<f32>(7),                                      // offset of the first component
-    );
-    let mut ibo: gl::types::GLuint = vao;
-    gl::GenBuffers(1, &mut ibo);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ibo);
-    gl::BufferData(
-        gl::ELEMENT_ARRAY_BUFFER,                     // target
-        byte_size_of_array(indices),                  // size of data in bytes
-        indices.as_ptr() as *const gl::types::GLvoid, // pointer to data
-        gl::STATIC_DRAW,                              // usage
-    );
-    return vao;
-}
-
-fn main() {
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
     let wb = glutin::window::WindowBuilder::new()
@@ -153,6 +129,10 @@ fn main() {
     // Make a reference of this tuple to send to the render thread
     let mouse_delta = Arc::clone(&arc_mouse_delta);
 
+    // Set when the window closes, so the render thread can save what it is still capturing
+    let arc_shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown = Arc::clone(&arc_shutdown);
+
     // Spawn a separate thread for rendering, so event handling doesn't block rendering
     let render_thread = thread::spawn(move || {
         // Acquire the OpenGL Context and load the function pointers. This has to be done inside of the rendering thread, because
@@ -163,94 +143,46 @@ fn main() {
             c
         };
         // Set up openGL
-        unsafe {
-            gl::Enable(gl::DEPTH_TEST);
-            gl::DepthFunc(gl::LESS);
-            gl::Enable(gl::CULL_FACE);
-            gl::Disable(gl::MULTISAMPLE);
-            gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-            gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
-            gl::DebugMessageCallback(Some(util::debug_callback), ptr::null());
-
-            // Print some diagnostics
-            println!(
-                "{}: {}",
-                util::get_gl_string(gl::VENDOR),
-                util::get_gl_string(gl::RENDERER)
-            );
-            println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
-            println!(
-                "GLSL\t: {}",
-                util::get_gl_string(gl::SHADING_LANGUAGE_VERSION)
-            );
-        }
+        unsafe { world::setup_gl() };
 
-        //set up verticies
-
-        let program_id: gl::types::GLuint;
-        let lunar_vao: gl::types::GLuint = 1;
-        let heli_vao: gl::types::GLuint = 2;
-        let main_rotor_vao: gl::types::GLuint = 3;
-        let tail_rotor_vao: gl::types::GLuint = 4;
-        let door_vao: gl::types::GLuint = 5;
-        let lunar_surface = Terrain::load("resources\\lunarsurface.obj");
-        let helicopter = Helicopter::load("resources\\helicopter.obj");
-        unsafe {
-            //create vaos
-            //reate the vao
-            create_vao(
-                &lunar_surface.vertices,
-                &lunar_surface.colors,
-                &lunar_surface.normals,
-                &lunar_surface.indices,
-                lunar_vao,
-            );
-            create_vao(
-                &helicopter.body.vertices,
-                &helicopter.body.colors,
-                &helicopter.body.normals,
-                &helicopter.body.indices,
-                heli_vao,
-            );
-            create_vao(
-                &helicopter.main_rotor.vertices,
-                &helicopter.main_rotor.colors,
-                &helicopter.main_rotor.normals,
-                &helicopter.main_rotor.indices,
-                main_rotor_vao,
-            );
-            create_vao(
-                &helicopter.tail_rotor.vertices,
-                &helicopter.tail_rotor.colors,
-                &helicopter.tail_rotor.normals,
-                &helicopter.tail_rotor.indices,
-                tail_rotor_vao,
-            );
-            create_vao(
-                &helicopter.door.vertices,
-                &helicopter.door.colors,
-                &helicopter.door.normals,
-                &helicopter.door.indices,
-                door_vao,
-            );
-            gl::BindVertexArray(lunar_vao);
-            //I personally think this was way to difficult to figure out...
-            let shader_builder = shader::ShaderBuilder::new();
-            let shader_builder = shader_builder.attach_file("shaders\\simple.vert");
-            let shader_builder = shader_builder.attach_file("shaders\\simple.frag");
-            let shader_builder = shader_builder.link();
-            program_id = shader_builder.program_id;
-            gl::UseProgram(program_id);
-        }
+        let assets = SceneAssets::load(options.point_cloud, options.render.skybox.clone(), |progress| {
+            let size = context.window().inner_size();
+            unsafe { assets::draw_loading_screen(progress, size.width, size.height) };
+            context.swap_buffers().unwrap();
+        });
+        let mut world = unsafe { World::upload(assets) };
+        world.max_lights = options.render.max_lights;
+        // Without post-processing the scene is drawn straight to the window
+        let mut post = if options.render.post_processing {
+            let size = context.window().inner_size();
+            let render = &options.render;
+            match unsafe { post::PostProcessing::new(render.post.clone(), size.width, size.height, render.samples) } {
+                Ok(post) => Some(post),
+                Err(e) => {
+                    println!("Warning: drawing without post-processing: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        world.linear_output = post.is_some();
+        let lunar_surface = &world.assets.lunar_surface;
+        let mesh_for_vao = world.mesh_for_vao();
+        // The keys held last frame, for acting once per key press
+        let mut previous_keys: Vec<VirtualKeyCode> = Vec::new();
+        let mut capture = unsafe { capture::FrameCapture::new() };
+        let new_recording = || capture::Recording::start(Path::new("recordings").join(capture::timestamp()));
+        let mut recording = if options.record { Some(new_recording()) } else { None };
+        // Recordings advance the scene by a fixed step every frame, however long frames take
+        let recording_step = options.render.frame_step;
 
         // Used to demonstrate keyboard handling -- feel free to remove
         let movement_spd = 100.;
         let camera_spd = 1.;
+        let camera_ground_clearance = 2.;
         let mut last_frame_time = std::time::Instant::now();
-        let first_frame_time = std::time::Instant::now();
-        //The perspective matrix of the camera
-        let camer_intrinsic_matrix: glm::Mat4 = glm::perspective(1., PI / 2., 0.1, 50000.);
+        let mut elapsed = 0.;
         //The Translation matrix, used to store the current translation of the camera
         let mut camera_translation_matrix: glm::Mat4 = glm::translation(&glm::vec3(0.0, 0.0, 0.0));
         //The Rotation matrix, used to store the current translation of the camera
@@ -258,14 +190,44 @@ fn main() {
 
         //The final camera matrix, used to combine the other matricies
         // The main rendering loop
-        loop {
+        while !shutdown.load(Ordering::Relaxed) {
             let now = std::time::Instant::now();
-            let delta_time = now.duration_since(last_frame_time).as_secs_f32();
-            let elapsed = now.duration_since(first_frame_time).as_secs_f32();
+            let mut delta_time = now.duration_since(last_frame_time).as_secs_f32();
             last_frame_time = now;
+            if recording.is_some() {
+                delta_time = recording_step;
+            }
+            elapsed += delta_time;
 
             // Handle keyboard input
+            let mut export_requested = false;
+            let mut screenshot_requested = false;
             if let Ok(keys) = pressed_keys.lock() {
+                // Act once per press of these, not every frame they are held
+                let just_pressed = |key| keys.contains(&key) && !previous_keys.contains(&key);
+                export_requested = just_pressed(VirtualKeyCode::F9);
+                screenshot_requested = just_pressed(VirtualKeyCode::F12);
+                if just_pressed(VirtualKeyCode::F10) {
+                    recording = match recording.take() {
+                        Some(finished) => {
+                            finished.stop();
+                            None
+                        }
+                        None => Some(new_recording()),
+                    };
+                }
+                // The number keys turn the post-processing effects on and off, in their order
+                if let Some(post) = &mut post {
+                    for (i, &key) in [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9].iter().enumerate() {
+                        if just_pressed(key) {
+                            if let Some((effect, enabled)) = post.toggle(i) {
+                                println!("{} {}", effect.name(), if enabled { "on" } else { "off" });
+                            }
+                        }
+                    }
+                }
+                previous_keys = keys.clone();
+
                 let step = delta_time * movement_spd;
 
                 // Used to get a more natural movement of the camera
@@ -308,10 +270,19 @@ fn main() {
                     }
                 }
             }
+            // Keep the camera from sinking into the ground
+            let camera_point = glm::inverse(&camera_translation_matrix) * glm::vec4(0., 0., 0., 1.);
+            if let Some(ground) = lunar_surface.height_at(camera_point.x, camera_point.z) {
+                let sink = ground + camera_ground_clearance - camera_point.y;
+                if sink > 0. {
+                    camera_translation_matrix =
+                        glm::translation(&glm::vec3(0., -sink, 0.)) * camera_translation_matrix;
+                }
+            }
             // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels
             if let Ok(mut delta) = mouse_delta.lock() {
-                let yaw_delta = (*delta).0 * 0.001 * camera_spd;
-                let pitch_dleta = -(*delta).1 * 0.001 * camera_spd;
+                let yaw_delta = delta.0 * 0.001 * camera_spd;
+                let pitch_dleta = -delta.1 * 0.001 * camera_spd;
 
                 // this one was wrong in the previous assignment
                 camera_rotation_matrix = glm::rotation(pitch_dleta, &glm::vec3(-1.0, 0.0, 0.0))
@@ -321,111 +292,71 @@ fn main() {
                 *delta = (0.0, 0.0);
                 // println!["{:?}", glm::rotation(0., &glm::vec3(1.0, 0.0, 0.0))]
             }
-            let mut root_scene = SceneNode::new();
-
-            let lunar_scene =
-                SceneNode::from_vao(lunar_vao, lunar_surface.index_count, glm::vec3(0., 0., 0.));
-
-            let mut helicopters: Vec<HelicopterStruct> = Vec::new();
-            let heli_n = 5;
-            for _ in 0..heli_n {
-                let mut heli_scene = SceneNode::from_vao(
-                    heli_vao,
-                    helicopter.body.index_count,
-                    glm::vec3(0., 0., 0.),
-                );
-
-                let main_rotor_scene = SceneNode::from_vao(
-                    main_rotor_vao,
-                    helicopter.main_rotor.index_count,
-                    glm::vec3(0., 0., 0.),
-                );
-                let tail_rotor_scene = SceneNode::from_vao(
-                    tail_rotor_vao,
-                    helicopter.tail_rotor.index_count,
-                    glm::vec3(0.35, 2.3, 10.4),
-                );
-
-                let door_scene = SceneNode::from_vao(
-                    door_vao,
-                    helicopter.door.index_count,
-                    glm::vec3(0., 0., 0.),
-                );
-
-                root_scene.add_child(&lunar_scene);
-                root_scene.add_child(&heli_scene);
-                heli_scene.add_child(&main_rotor_scene);
-                heli_scene.add_child(&tail_rotor_scene);
-                heli_scene.add_child(&door_scene);
-
-                let heli_struct = HelicopterStruct {
-                    body: heli_scene,
-                    main_rotor: main_rotor_scene,
-                    tail_rotor: tail_rotor_scene,
-                    door: door_scene,
-                };
-
-                helicopters.push(heli_struct);
-            }
-            let lightsource = glm::vec3::<f32>(-800., 500., -600.);
-            let view_projection_matrix =
-                camer_intrinsic_matrix * camera_rotation_matrix * camera_translation_matrix;
+            let camera_position = glm::vec4_to_vec3(
+                &(glm::inverse(&camera_translation_matrix) * glm::vec4(0., 0., 0., 1.)),
+            );
+            let camera = Camera {
+                view: camera_rotation_matrix * camera_translation_matrix,
+                position: camera_position,
+                aspect: SCREEN_W as f32 / SCREEN_H as f32,
+            };
             unsafe {
-                gl::ClearColor(0.163, 0.163, 0.163, 1.0);
-                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-                gl::Clear(gl::COLOR_BUFFER_BIT);
-
-                let camera_position = glm::vec4_to_vec3(
-                    &(glm::inverse(&camera_translation_matrix) * glm::vec4(0., 0., 0., 1.)),
-                );
-                for i in 0..heli_n {
-                    let pos_var = 0.6 * elapsed + (2. * PI / (heli_n as f32)) * (i as f32);
-                    helicopters[i].body.set_position(glm::vec3(
-                        40. * pos_var.cos(),
-                        10. + 4. * (2. * pos_var + 0.841 * elapsed).sin(),
-                        -40. * pos_var.sin(),
-                    ));
-                    helicopters[i].body.set_rotation(glm::vec3(0., pos_var, 0.));
-                    helicopters[i].main_rotor.set_rotation(glm::vec3(
-                        0.,
-                        elapsed * (7. + (i as f32) * 0.5),
-                        0.,
-                    ));
-                    helicopters[i].tail_rotor.set_rotation(glm::vec3(
-                        elapsed * (5. - (i as f32) * 0.2),
-                        0.,
-                        0.,
-                    ));
+                let root_scene = world.build_scene(elapsed, &camera_position);
+                let size = context.window().inner_size();
+                match &mut post {
+                    Some(post) => {
+                        if let Err(e) = post.resize(size.width, size.height) {
+                            println!("Warning: failed to resize the post-processing targets: {}", e);
+                        }
+                        post.begin();
+                        world.draw(&root_scene, &camera);
+                        post.finish(0, size.width, size.height);
+                    }
+                    None => world.draw(&root_scene, &camera),
+                }
+
+                if export_requested {
+                    let path = Path::new("exports").join("scene");
+                    match export::export_scene(&path, &root_scene, &|vao| mesh_for_vao.get(&vao).copied()) {
+                        Ok(()) => println!("Exported the scene to {}.obj and .ply", path.display()),
+                        Err(e) => println!("Warning: failed to export the scene: {}", e),
+                    }
+                }
+
+                // Read back the frame before it is swapped away
+                if screenshot_requested {
+                    let path = Path::new("screenshots").join(format!("screenshot_{}.png", capture::timestamp()));
+                    capture.capture(0, size.width, size.height, &path);
+                    println!("Saving a screenshot to {}", path.display());
                 }
-                update_node_transformations(
-                    &mut root_scene,
-                    &(glm::translation(&glm::vec3(0., 0., 0.))),
-                );
-
-                draw_scene(
-                    &root_scene,
-                    &view_projection_matrix,
-                    &camera_position,
-                    &lightsource,
-                    &program_id,
-                );
+                if let Some(recording) = &mut recording {
+                    capture.capture(0, size.width, size.height, &recording.next_frame());
+                }
+                capture.poll();
             }
             // Issue the necessary commands to draw your scene here
             context.swap_buffers().unwrap();
         }
+        // Wait for the readbacks in flight and the images queued for writing, before the
+        // program ends and cuts them off
+        drop(capture);
+        if let Some(recording) = recording.take() {
+            recording.stop();
+        }
     });
 
     // Keep track of the health of the rendering thread
     let render_thread_healthy = Arc::new(RwLock::new(true));
     let render_thread_watchdog = Arc::clone(&render_thread_healthy);
-    thread::spawn(move || {
-        if !render_thread.join().is_ok() {
+    // Joined on exit, which waits for the render thread
+    let mut watchdog = Some(thread::spawn(move || {
+        if render_thread.join().is_err() {
             if let Ok(mut health) = render_thread_watchdog.write() {
                 println!("Render thread panicked!");
                 *health = false;
             }
         }
-    });
+    }));
 
     // Start the event loop -- This is where window events get handled
     el.run(move |event, _, control_flow| {
@@ -433,12 +364,19 @@ fn main() {
 
         // Terminate program if render thread panics
         if let Ok(health) = render_thread_healthy.read() {
-            if *health == false {
+            if !*health {
                 *control_flow = ControlFlow::Exit;
             }
         }
 
         match event {
+            // The last event before the program ends, let the render thread wrap up first
+            Event::LoopDestroyed => {
+                arc_shutdown.store(true, Ordering::Relaxed);
+                if let Some(watchdog) = watchdog.take() {
+                    let _ = watchdog.join();
+                }
+            }
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 ..
@@ -476,11 +414,8 @@ fn main() {
                 }
 
                 // Handle escape separately
-                match keycode {
-                    Escape => {
-                        *control_flow = ControlFlow::Exit;
-                    }
-                    _ => {}
+                if keycode == Escape {
+                    *control_flow = ControlFlow::Exit;
                 }
             }
             Event::DeviceEvent {
@@ -0,0 +1,304 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+
+use crate::texture;
+
+// How a surface reflects the lights, set per scene node. Either the Phong parameters, or the
+// metallic-roughness parameters of glTF for physically based shading.
+
+// Texture units of the material's textures, the normal map uses 0 and the shadow maps 1
+pub const BASE_COLOR_UNIT: u32 = 2;
+pub const METALLIC_ROUGHNESS_UNIT: u32 = 3;
+pub const OCCLUSION_UNIT: u32 = 4;
+pub const EMISSIVE_UNIT: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpecularModel {
+    /// Highlights from the angle between the reflected light and the view
+    Phong,
+    /// Highlights from the angle between the normal and the half vector of light and view.
+    /// Needs about four times the shininess of Phong for a highlight of the same size.
+    BlinnPhong,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Material {
+    // How much of the ambient light, and of the lights' diffuse and specular light, is reflected
+    pub ambient: f32,
+    pub diffuse: f32,
+    pub specular: f32,
+    // The specular exponent, higher for smaller and sharper highlights
+    pub shininess: f32,
+    pub specular_model: SpecularModel,
+    // How much of the sky the surface mirrors, from 0 to 1
+    pub reflectivity: f32,
+    // Shaded physically based if set, the Phong parameters are then unused
+    pub pbr: Option<PbrMaterial>,
+}
+
+/// The metallic-roughness material of glTF 2.0. Textures are GL texture ids, 0 for none, and are
+/// multiplied with their factors. The normal texture is the scene node's `normal_map`.
+#[derive(Clone, Copy, Debug)]
+pub struct PbrMaterial {
+    // Linear RGBA, multiplied with the vertex colors, which are taken to be sRGB like in the
+    // Phong path
+    pub base_color_factor: glm::Vec4,
+    // sRGB encoded, see `texture::upload_color_texture`
+    pub base_color_texture: u32,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    // Roughness in the green channel and metallic in the blue one
+    pub metallic_roughness_texture: u32,
+    // Ambient occlusion in the red channel, applied to the environment's light
+    pub occlusion_texture: u32,
+    pub occlusion_strength: f32,
+    pub emissive_factor: glm::Vec3,
+    // sRGB encoded
+    pub emissive_texture: u32,
+}
+
+impl Default for PbrMaterial {
+    fn default() -> Self {
+        PbrMaterial {
+            base_color_factor: glm::vec4(1., 1., 1., 1.),
+            base_color_texture: 0,
+            metallic_factor: 1.,
+            roughness_factor: 1.,
+            metallic_roughness_texture: 0,
+            occlusion_texture: 0,
+            occlusion_strength: 1.,
+            emissive_factor: glm::vec3(0., 0., 0.),
+            emissive_texture: 0,
+        }
+    }
+}
+
+impl PbrMaterial {
+    pub fn new(base_color: glm::Vec4, metallic: f32, roughness: f32) -> Self {
+        PbrMaterial {
+            base_color_factor: base_color,
+            metallic_factor: metallic,
+            roughness_factor: roughness,
+            ..PbrMaterial::default()
+        }
+    }
+}
+
+/// A `PbrMaterial` read from an MTL file with the PBR extension, as e.g. Blender exports it.
+/// The textures are decoded but not uploaded yet, so it can be read on an asset worker thread.
+/// MTL has no statement for occlusion, so there is no occlusion texture.
+#[derive(Clone)]
+pub struct MtlPbrMaterial {
+    // The factors, the textures are all 0 until uploaded
+    pub factors: PbrMaterial,
+    pub base_color: Option<image::RgbaImage>,
+    // map_Pr and map_Pm packed into the green and blue channels like glTF does it
+    pub metallic_roughness: Option<image::RgbaImage>,
+    pub emissive: Option<image::RgbaImage>,
+    // From `norm`, drawn as the scene node's normal map
+    pub normal: Option<image::RgbaImage>,
+}
+
+// Decodes a texture from an MTL statement, relative to the MTL file. Options in front of the
+// file name, like `-bm 0.5`, are skipped.
+fn decode_mtl_texture(directory: &Path, statement: &str) -> Option<image::RgbaImage> {
+    let path = directory.join(statement.split_whitespace().last()?);
+    image::open(&path)
+        .map(|image| image.to_rgba8())
+        .map_err(|e| println!("Warning: failed to load texture {}: {}", path.display(), e))
+        .ok()
+}
+
+// Roughness from the red channel of one greyscale map and metallic from that of the other,
+// scaled to the size of the first one there is
+fn pack_metallic_roughness(
+    roughness: Option<image::RgbaImage>,
+    metallic: Option<image::RgbaImage>,
+) -> Option<image::RgbaImage> {
+    let (width, height) = roughness.as_ref().or(metallic.as_ref())?.dimensions();
+    let scaled = |map: Option<image::RgbaImage>| {
+        map.map(|map| image::imageops::resize(&map, width, height, image::imageops::FilterType::Triangle))
+    };
+    let (roughness, metallic) = (scaled(roughness), scaled(metallic));
+    // A missing map leaves its factor alone
+    let sample = |map: &Option<image::RgbaImage>, x, y| map.as_ref().map_or(255, |map| map.get_pixel(x, y)[0]);
+    Some(image::RgbaImage::from_fn(width, height, |x, y| {
+        image::Rgba([255, sample(&roughness, x, y), sample(&metallic, x, y), 255])
+    }))
+}
+
+impl MtlPbrMaterial {
+    /// The PBR part of an MTL material, None if it has none of `Pr`, `Pm`, `map_Pr` and
+    /// `map_Pm`. Texture names are relative to `directory`, where the MTL file is.
+    pub fn from_mtl(material: &tobj::Material, directory: &Path) -> Option<Self> {
+        let statement = |key: &str| material.unknown_param.get(key).map(String::as_str);
+        let number = |key: &str| statement(key).and_then(|value| value.trim().parse::<f32>().ok());
+        if ["Pr", "Pm", "map_Pr", "map_Pm"].iter().all(|key| statement(key).is_none()) {
+            return None;
+        }
+
+        // Like glTF the factors multiply the textures, an unset one is 1 when there is a texture
+        let base_color_texture = decode_mtl_texture(directory, &material.diffuse_texture);
+        let diffuse = if material.diffuse == [0.; 3] && base_color_texture.is_some() {
+            [1.; 3]
+        } else {
+            material.diffuse
+        };
+        let emissive_texture = statement("map_Ke").and_then(|map| decode_mtl_texture(directory, map));
+        let emissive: Vec<f32> = statement("Ke")
+            .map(|value| value.split_whitespace().filter_map(|v| v.parse().ok()).collect())
+            .unwrap_or_default();
+        let emissive_factor = match emissive[..] {
+            [r, g, b] => glm::vec3(r, g, b),
+            [grey] => glm::vec3(grey, grey, grey),
+            _ if emissive_texture.is_some() => glm::vec3(1., 1., 1.),
+            _ => glm::vec3(0., 0., 0.),
+        };
+        let has_map = |key: &str| statement(key).is_some();
+        let factors = PbrMaterial {
+            base_color_factor: glm::vec4(diffuse[0], diffuse[1], diffuse[2], material.dissolve),
+            metallic_factor: number("Pm").unwrap_or(if has_map("map_Pm") { 1. } else { 0. }),
+            roughness_factor: number("Pr").unwrap_or(1.),
+            emissive_factor,
+            ..PbrMaterial::default()
+        };
+        let metallic_roughness = pack_metallic_roughness(
+            statement("map_Pr").and_then(|map| decode_mtl_texture(directory, map)),
+            statement("map_Pm").and_then(|map| decode_mtl_texture(directory, map)),
+        );
+        Some(MtlPbrMaterial {
+            factors,
+            base_color: base_color_texture,
+            metallic_roughness,
+            emissive: emissive_texture,
+            normal: statement("norm").and_then(|map| decode_mtl_texture(directory, map)),
+        })
+    }
+
+    /// Uploads the textures, returns the material and the normal map, 0 if there is none
+    pub unsafe fn upload(&self) -> (PbrMaterial, u32) {
+        let upload = |image: &Option<image::RgbaImage>, color: bool| match image {
+            Some(image) if color => texture::upload_color_texture(image),
+            Some(image) => texture::upload_texture(image),
+            None => 0,
+        };
+        let material = PbrMaterial {
+            base_color_texture: upload(&self.base_color, true),
+            metallic_roughness_texture: upload(&self.metallic_roughness, false),
+            emissive_texture: upload(&self.emissive, true),
+            ..self.factors
+        };
+        (material, upload(&self.normal, false))
+    }
+}
+
+/// The PBR materials of the models in an OBJ file, by model name, from the MTL files it uses.
+/// A model made of several materials gets the one its first face uses.
+pub fn load_obj_pbr_materials(obj_path: &str) -> HashMap<String, MtlPbrMaterial> {
+    let source = match fs::read_to_string(obj_path) {
+        Ok(source) => source,
+        Err(_) => return HashMap::new(),
+    };
+    let directory = Path::new(obj_path).parent().unwrap_or_else(|| Path::new(""));
+    let mut materials: HashMap<String, MtlPbrMaterial> = HashMap::new();
+    let mut model_materials = HashMap::new();
+    let mut model = String::new();
+    let mut current = None;
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("mtllib") => {
+                for file in words {
+                    match tobj::load_mtl(directory.join(file)) {
+                        Ok((loaded, _)) => materials.extend(loaded.iter().filter_map(|material| {
+                            MtlPbrMaterial::from_mtl(material, directory).map(|pbr| (material.name.clone(), pbr))
+                        })),
+                        Err(e) => println!("Warning: failed to load materials {}: {}", file, e),
+                    }
+                }
+            }
+            // Named like tobj names its models
+            Some("o") | Some("g") => model = line[1..].trim().to_string(),
+            Some("usemtl") => current = Some(line[6..].trim().to_string()),
+            Some("f") => {
+                if let Some(current) = &current {
+                    model_materials.entry(model.clone()).or_insert_with(|| current.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    model_materials
+        .into_iter()
+        .filter_map(|(model, material)| Some((model, materials.get(&material)?.clone())))
+        .collect()
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            ambient: 1.,
+            diffuse: 0.8,
+            specular: 0.3,
+            shininess: 20.,
+            specular_model: SpecularModel::Phong,
+            reflectivity: 0.,
+            pbr: None,
+        }
+    }
+}
+
+impl Material {
+    pub fn physically_based(pbr: PbrMaterial) -> Self {
+        Material { pbr: Some(pbr), ..Material::default() }
+    }
+
+    /// Sets the material uniforms of the lit shader, which has to be in use
+    pub unsafe fn apply(&self, program_id: u32) {
+        let location = |name: &str| {
+            let cname = CString::new(name).expect("expected uniform name to have no nul bytes");
+            gl::GetUniformLocation(program_id, cname.as_ptr())
+        };
+        gl::Uniform1f(location("MaterialAmbient"), self.ambient);
+        gl::Uniform1f(location("MaterialDiffuse"), self.diffuse);
+        gl::Uniform1f(location("MaterialSpecular"), self.specular);
+        gl::Uniform1f(location("MaterialShininess"), self.shininess);
+        gl::Uniform1i(location("BlinnPhong"), (self.specular_model == SpecularModel::BlinnPhong) as i32);
+        gl::Uniform1f(location("MaterialReflectivity"), self.reflectivity);
+
+        let pbr = self.pbr.unwrap_or_default();
+        gl::Uniform1i(location("UsePbr"), self.pbr.is_some() as i32);
+        gl::Uniform4fv(location("BaseColorFactor"), 1, pbr.base_color_factor.as_ptr());
+        gl::Uniform1f(location("MetallicFactor"), pbr.metallic_factor);
+        gl::Uniform1f(location("RoughnessFactor"), pbr.roughness_factor);
+        gl::Uniform1f(location("OcclusionStrength"), pbr.occlusion_strength);
+        gl::Uniform3fv(location("EmissiveFactor"), 1, pbr.emissive_factor.as_ptr());
+        // Every sampler gets its own unit even when unused, samplers of different types must
+        // never share one
+        let textures = [
+            ("BaseColorTexture", BASE_COLOR_UNIT, pbr.base_color_texture),
+            ("MetallicRoughnessTexture", METALLIC_ROUGHNESS_UNIT, pbr.metallic_roughness_texture),
+            ("OcclusionTexture", OCCLUSION_UNIT, pbr.occlusion_texture),
+            ("EmissiveTexture", EMISSIVE_UNIT, pbr.emissive_texture),
+        ];
+        for &(name, unit, texture) in &textures {
+            gl::Uniform1i(location(name), unit as i32);
+            gl::Uniform1i(location(&format!("Use{}", name)), (texture != 0) as i32);
+            if texture != 0 {
+                gl::ActiveTexture(gl::TEXTURE0 + unit);
+                gl::BindTexture(gl::TEXTURE_2D, texture);
+            }
+        }
+        gl::ActiveTexture(gl::TEXTURE0);
+    }
+}
+
+/// Transforms normals along with the surfaces `transformation` transforms, keeping them
+/// perpendicular to the surface under non-uniform scaling
+pub fn normal_matrix(transformation: &glm::Mat4) -> glm::Mat3 {
+    glm::transpose(&glm::inverse(&glm::mat4_to_mat3(transformation)))
+}
@@ -1,60 +1,541 @@
-use tobj;
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bevy_mikktspace as mikktspace;
+
+use crate::optimize::OptimizationStats;
+
+// Crease angle used when a model is loaded without normals
+pub const DEFAULT_CREASE_ANGLE: f32 = std::f32::consts::PI / 3.0;
 
 fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
     color.iter().cloned().cycle().take(num*4).collect()
 }
 
+// Copies `size` floats per vertex from `attribute` in the order given by `sources`.
+// Attributes that don't cover every vertex are dropped, since there is nothing sensible to copy.
+fn gather(attribute: &[f32], size: usize, vertex_count: usize, sources: &[u32]) -> Vec<f32> {
+    if attribute.len() != vertex_count * size { return Vec::new() }
+    sources.iter().flat_map(|&i| attribute[i as usize * size..(i as usize + 1) * size].iter().cloned()).collect()
+}
+
+fn normalize_or_zero(v: glm::Vec3) -> glm::Vec3 {
+    let length = glm::length(&v);
+    if length > 0.0 { v / length } else { glm::zero() }
+}
+
+// The triangles of a mesh as MikkTSpace reads them, with a tangent for every corner to write to
+struct TangentSpaceInput<'a> {
+    mesh: &'a Mesh,
+    corner_tangents: Vec<[f32; 4]>,
+}
+
+impl<'a> TangentSpaceInput<'a> {
+    fn vertex(&self, face: usize, vert: usize) -> usize {
+        self.mesh.indices[3*face + vert] as usize
+    }
+}
+
+impl<'a> mikktspace::Geometry for TangentSpaceInput<'a> {
+    fn num_faces(&self) -> usize {
+        self.mesh.triangle_count()
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.mesh.position(self.vertex(face, vert)).into()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.mesh.normal(self.vertex(face, vert)).into()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.mesh.uv(self.vertex(face, vert)).into()
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.corner_tangents[3*face + vert] = tangent;
+    }
+}
+
+// Bitwise key so that vertices at exactly the same position can be grouped in a HashMap
+fn position_key(p: &glm::Vec3) -> [u32; 3] {
+    [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]
+}
+
+/// How the indices of a mesh are put together when drawing it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Primitive {
+    Triangles,
+    // Every two indices make a separate line
+    Lines,
+    // Every index is joined to the one before it
+    LineStrip,
+    Points,
+}
+
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<f32>,
     pub normals: Vec<f32>,
     pub colors: Vec<f32>,
+    pub uvs: Vec<f32>,
+    // xyz is the tangent, w is the bitangent sign so that B = w * cross(N, T)
+    pub tangents: Vec<f32>,
     pub indices: Vec<u32>,
     pub index_count: i32,
+    pub primitive: Primitive,
 }
 
 impl Mesh {
-    pub fn from(mesh: tobj::Mesh, color: [f32; 4]) -> Self {
+    /// Converts a loaded model and optimizes it for drawing. The optimization is skipped for
+    /// models with broken indices, otherwise its statistics are returned for the loader to report.
+    pub fn from(mesh: tobj::Mesh, color: [f32; 4]) -> (Self, Option<OptimizationStats>) {
         let num_verts = mesh.positions.len() / 3;
         let index_count = mesh.indices.len() as i32;
-        Mesh {
+        let mut mesh = Mesh {
             vertices: mesh.positions,
             normals: mesh.normals,
+            uvs: mesh.texcoords,
+            tangents: vec![],
             indices: mesh.indices,
             colors: generate_color_vec(color, num_verts),
             index_count,
+            primitive: Primitive::Triangles,
+        };
+
+        let report = mesh.validate();
+        if !report.is_clean() {
+            println!("Warning: {}", report);
+        }
+        if mesh.normals.len() != mesh.vertices.len() && report.out_of_range_indices.is_empty() {
+            println!("Model has no usable normals, generating smooth normals.");
+            mesh.generate_smooth_normals(DEFAULT_CREASE_ANGLE);
+        }
+        if !mesh.uvs.is_empty() && report.is_valid() {
+            mesh.generate_tangents();
+        }
+        let stats = if report.is_valid() { Some(mesh.optimize()) } else { None };
+        (mesh, stats)
+    }
+
+    pub fn empty() -> Self {
+        Mesh {
+            vertices: vec![],
+            normals: vec![],
+            colors: vec![],
+            uvs: vec![],
+            tangents: vec![],
+            indices: vec![],
+            index_count: 0,
+            primitive: Primitive::Triangles,
         }
     }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len() / 3
+    }
+
+    // Meshes made of lines or points have no triangles
+    pub fn triangle_count(&self) -> usize {
+        if self.primitive != Primitive::Triangles { return 0 }
+        self.indices.len() / 3
+    }
+
+    pub fn position(&self, i: usize) -> glm::Vec3 {
+        glm::vec3(self.vertices[3*i], self.vertices[3*i + 1], self.vertices[3*i + 2])
+    }
+
+    pub fn normal(&self, i: usize) -> glm::Vec3 {
+        glm::vec3(self.normals[3*i], self.normals[3*i + 1], self.normals[3*i + 2])
+    }
+
+    pub fn uv(&self, i: usize) -> glm::Vec2 {
+        glm::vec2(self.uvs[2*i], self.uvs[2*i + 1])
+    }
+
+    pub fn triangle(&self, t: usize) -> [usize; 3] {
+        [self.indices[3*t] as usize, self.indices[3*t + 1] as usize, self.indices[3*t + 2] as usize]
+    }
+
+    // Unnormalized face normal, its length is twice the area of the triangle
+    pub fn face_cross(&self, t: usize) -> glm::Vec3 {
+        let [a, b, c] = self.triangle(t);
+        let (a, b, c) = (self.position(a), self.position(b), self.position(c));
+        glm::cross(&(b - a), &(c - a))
+    }
+
+    // Interior angle of the triangle at the given position in the index buffer
+    fn corner_angle(&self, corner: usize) -> f32 {
+        let t = corner / 3;
+        let k = corner % 3;
+        let p = self.position(self.indices[corner] as usize);
+        let e1 = normalize_or_zero(self.position(self.indices[3*t + (k + 1) % 3] as usize) - p);
+        let e2 = normalize_or_zero(self.position(self.indices[3*t + (k + 2) % 3] as usize) - p);
+        glm::dot(&e1, &e2).clamp(-1.0, 1.0).acos()
+    }
+
+    // New mesh without any triangles, where vertex i is a copy of vertex sources[i] of this one
+    pub fn gathered(&self, sources: &[u32]) -> Mesh {
+        let vertex_count = self.vertex_count();
+        Mesh {
+            vertices: gather(&self.vertices, 3, vertex_count, sources),
+            normals: gather(&self.normals, 3, vertex_count, sources),
+            colors: gather(&self.colors, 4, vertex_count, sources),
+            uvs: gather(&self.uvs, 2, vertex_count, sources),
+            tangents: gather(&self.tangents, 4, vertex_count, sources),
+            indices: vec![],
+            index_count: 0,
+            primitive: self.primitive,
+        }
+    }
+
+    // Rebuilds every per-vertex attribute so that new vertex i is a copy of old vertex sources[i].
+    // Indices are left alone, the caller is responsible for remapping them.
+    pub fn gather_vertices(&mut self, sources: &[u32]) {
+        let indices = std::mem::take(&mut self.indices);
+        let index_count = self.index_count;
+        *self = self.gathered(sources);
+        self.indices = indices;
+        self.index_count = index_count;
+    }
+
+    // New mesh made of the given indices into this one, with only the vertices they use
+    pub fn with_indices(&self, indices: &[u32]) -> Mesh {
+        let mut remap: HashMap<u32, u32> = HashMap::new();
+        let mut sources: Vec<u32> = Vec::new();
+        let remapped = indices.iter().map(|&index| {
+            *remap.entry(index).or_insert_with(|| {
+                sources.push(index);
+                (sources.len() - 1) as u32
+            })
+        }).collect();
+        let mut mesh = self.gathered(&sources);
+        mesh.set_indices(remapped);
+        mesh
+    }
+
+    // Copies the given triangles, and only the vertices they use, into a new mesh
+    pub fn submesh(&self, triangles: &[usize]) -> Mesh {
+        let indices: Vec<u32> = triangles.iter().flat_map(|&t| self.indices[3*t..3*t + 3].iter().cloned()).collect();
+        self.with_indices(&indices)
+    }
+
+    // Appends the vertices and triangles of another mesh. Optional attributes are only kept
+    // if both meshes have them.
+    pub fn append(&mut self, other: &Mesh) {
+        let (count, other_count) = (self.vertex_count(), other.vertex_count());
+        if count == 0 {
+            self.primitive = other.primitive;
+        } else if self.primitive != other.primitive || self.primitive == Primitive::LineStrip {
+            // Appended line strips would get joined up into one
+            panic!("Can't append a {:?} mesh to a {:?} mesh", other.primitive, self.primitive);
+        }
+        let keep = |a: &[f32], b: &[f32], size: usize| {
+            (a.len() == count * size || count == 0) && b.len() == other_count * size
+        };
+        let keep_normals = keep(&self.normals, &other.normals, 3);
+        let keep_colors = keep(&self.colors, &other.colors, 4);
+        let keep_uvs = keep(&self.uvs, &other.uvs, 2);
+        let keep_tangents = keep(&self.tangents, &other.tangents, 4);
+
+        self.vertices.extend_from_slice(&other.vertices);
+        if keep_normals { self.normals.extend_from_slice(&other.normals) } else { self.normals.clear() }
+        if keep_colors { self.colors.extend_from_slice(&other.colors) } else { self.colors.clear() }
+        if keep_uvs { self.uvs.extend_from_slice(&other.uvs) } else { self.uvs.clear() }
+        if keep_tangents { self.tangents.extend_from_slice(&other.tangents) } else { self.tangents.clear() }
+        self.indices.extend(other.indices.iter().map(|&i| i + count as u32));
+        self.index_count = self.indices.len() as i32;
+    }
+
+    // Moves the mesh by an affine transformation, normals and tangents are transformed by
+    // the inverse transpose so they stay perpendicular to the surface
+    pub fn transform(&mut self, transformation: &glm::Mat4) {
+        let normal_matrix = glm::transpose(&glm::inverse(&glm::mat4_to_mat3(transformation)));
+        for p in self.vertices.chunks_exact_mut(3) {
+            let moved = transformation * glm::vec4(p[0], p[1], p[2], 1.0);
+            p.copy_from_slice(&[moved.x, moved.y, moved.z]);
+        }
+        for n in self.normals.chunks_exact_mut(3) {
+            let moved = glm::normalize(&(normal_matrix * glm::vec3(n[0], n[1], n[2])));
+            n.copy_from_slice(moved.as_slice());
+        }
+        for t in self.tangents.chunks_exact_mut(4) {
+            let moved = glm::normalize(&(glm::mat4_to_mat3(transformation) * glm::vec3(t[0], t[1], t[2])));
+            t[..3].copy_from_slice(moved.as_slice());
+            // Mirroring transforms flip the handedness of the tangent frame
+            if glm::determinant(&glm::mat4_to_mat3(transformation)) < 0.0 { t[3] = -t[3] }
+        }
+    }
+
+    pub fn set_indices(&mut self, indices: Vec<u32>) {
+        self.index_count = indices.len() as i32;
+        self.indices = indices;
+    }
+
+    /// Replaces the normals with angle-weighted vertex normals. Faces meeting at an angle
+    /// larger than `crease_angle` (in radians) are not smoothed together, and vertices on
+    /// such creases are split so that hard edges stay hard. Pass PI or more to smooth everything.
+    pub fn generate_smooth_normals(&mut self, crease_angle: f32) {
+        let corner_count = self.triangle_count() * 3;
+        let face_normals: Vec<glm::Vec3> = (0..self.triangle_count())
+            .map(|t| normalize_or_zero(self.face_cross(t)))
+            .collect();
+        let corner_angles: Vec<f32> = (0..corner_count).map(|c| self.corner_angle(c)).collect();
+
+        // Corners are grouped by position rather than by index, so that vertices which are
+        // only split because of a color or UV seam still end up with the same normal
+        let mut corners_at: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+        for corner in 0..corner_count {
+            let p = self.position(self.indices[corner] as usize);
+            corners_at.entry(position_key(&p)).or_default().push(corner);
+        }
+
+        let cos_crease = crease_angle.cos();
+        let mut sources: Vec<u32> = Vec::new();
+        let mut normals: Vec<f32> = Vec::new();
+        let mut indices: Vec<u32> = Vec::with_capacity(corner_count);
+        let mut split_vertices: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+        for corner in 0..corner_count {
+            let index = self.indices[corner];
+            let face_normal = face_normals[corner / 3];
+            let mut normal: glm::Vec3 = glm::zero();
+            for &other in &corners_at[&position_key(&self.position(index as usize))] {
+                let other_normal = face_normals[other / 3];
+                if other / 3 == corner / 3 || glm::dot(&face_normal, &other_normal) >= cos_crease {
+                    normal += corner_angles[other] * other_normal;
+                }
+            }
+            let normal = normalize_or_zero(normal);
+
+            let new_index = *split_vertices.entry((index, position_key(&normal))).or_insert_with(|| {
+                sources.push(index);
+                normals.extend_from_slice(normal.as_slice());
+                (sources.len() - 1) as u32
+            });
+            indices.push(new_index);
+        }
+
+        self.gather_vertices(&sources);
+        self.normals = normals;
+        self.set_indices(indices);
+    }
+
+    /// Gives every triangle its own three vertices, all with the face normal.
+    #[allow(dead_code)]
+    pub fn generate_flat_normals(&mut self) {
+        let triangle_count = self.triangle_count();
+        let mut normals = Vec::with_capacity(triangle_count * 9);
+        for t in 0..triangle_count {
+            let normal = normalize_or_zero(self.face_cross(t));
+            for _ in 0..3 {
+                normals.extend_from_slice(normal.as_slice());
+            }
+        }
+
+        let sources = self.indices[..triangle_count * 3].to_vec();
+        self.gather_vertices(&sources);
+        self.normals = normals;
+        self.set_indices((0..sources.len() as u32).collect());
+    }
+
+    /// Computes per-vertex tangents for normal mapping with MikkTSpace, the tangent space
+    /// Blender, Substance and most bakers use, so their normal maps shade right here too.
+    /// MikkTSpace gives every triangle corner a tangent, and vertices whose corners got
+    /// different ones, like those on UV seams and the border of mirrored UV islands, are
+    /// split. Requires UVs and normals, does nothing without them.
+    pub fn generate_tangents(&mut self) {
+        let vertex_count = self.vertex_count();
+        if self.uvs.len() != vertex_count * 2 || self.normals.len() != vertex_count * 3 {
+            println!("Warning: can't generate tangents for a mesh without UVs and normals");
+            return;
+        }
+
+        let corner_count = self.triangle_count() * 3;
+        let mut input = TangentSpaceInput { mesh: self, corner_tangents: vec![[1.0, 0.0, 0.0, 1.0]; corner_count] };
+        if corner_count > 0 && !mikktspace::generate_tangents(&mut input) {
+            println!("Warning: MikkTSpace failed to generate tangents");
+            return;
+        }
+        let corner_tangents = input.corner_tangents;
+
+        // (vertex, tangent) -> new vertex index. The first tangent seen for a vertex keeps the
+        // original index, so meshes without seams aren't reordered.
+        let mut split_vertices: HashMap<(u32, [u32; 4]), u32> = HashMap::new();
+        let mut seen = vec![false; vertex_count];
+        let mut sources: Vec<u32> = (0..vertex_count as u32).collect();
+        let mut tangents: Vec<[f32; 4]> = vec![[1.0, 0.0, 0.0, 1.0]; vertex_count];
+        for (corner, tangent) in corner_tangents.iter().enumerate() {
+            let index = self.indices[corner];
+            let key = (index, [tangent[0].to_bits(), tangent[1].to_bits(), tangent[2].to_bits(), tangent[3].to_bits()]);
+            let new_index = *split_vertices.entry(key).or_insert_with(|| {
+                if !seen[index as usize] {
+                    seen[index as usize] = true;
+                    tangents[index as usize] = *tangent;
+                    index
+                } else {
+                    sources.push(index);
+                    tangents.push(*tangent);
+                    (sources.len() - 1) as u32
+                }
+            });
+            self.indices[corner] = new_index;
+        }
+
+        self.gather_vertices(&sources);
+        self.tangents = tangents.concat();
+    }
+
+    /// Checks the mesh for anything that would make it unsafe or ugly to draw.
+    pub fn validate(&self) -> MeshReport {
+        let vertex_count = self.vertex_count();
+        let mut report = MeshReport {
+            vertex_count,
+            triangle_count: self.triangle_count(),
+            trailing_indices: match self.primitive {
+                Primitive::Triangles => self.indices.len() % 3,
+                Primitive::Lines => self.indices.len() % 2,
+                Primitive::LineStrip | Primitive::Points => 0,
+            },
+            index_count_mismatch: self.index_count as usize != self.indices.len(),
+            ..Default::default()
+        };
+
+        // Lines and points are unlit, so they can do without normals
+        let (required, optional) = if self.primitive == Primitive::Triangles { (3, 2) } else { (2, 3) };
+        let attributes = [
+            ("positions", self.vertices.len(), 3),
+            ("colors",    self.colors.len(),   4),
+            ("normals",   self.normals.len(),  3),
+            ("uvs",       self.uvs.len(),      2),
+            ("tangents",  self.tangents.len(), 4),
+        ];
+        let optional_attributes = &attributes[required..required + optional];
+        let attributes = &attributes[..required];
+        for &(attribute, found, size) in optional_attributes.iter() {
+            if found != 0 && found != vertex_count * size {
+                report.attribute_mismatches.push(AttributeMismatch { attribute, expected: vertex_count * size, found });
+            }
+        }
+        for &(attribute, found, size) in attributes.iter() {
+            if found != vertex_count * size {
+                report.attribute_mismatches.push(AttributeMismatch { attribute, expected: vertex_count * size, found });
+            }
+        }
+
+        for (i, &index) in self.indices.iter().enumerate() {
+            if index as usize >= vertex_count {
+                report.out_of_range_indices.push(i);
+            }
+        }
+
+        for t in 0..self.triangle_count() {
+            let [a, b, c] = self.triangle(t);
+            if a >= vertex_count || b >= vertex_count || c >= vertex_count { continue }
+            if a == b || b == c || a == c {
+                report.degenerate_triangles.push(t);
+                continue;
+            }
+            let longest_edge = glm::length2(&(self.position(b) - self.position(a)))
+                .max(glm::length2(&(self.position(c) - self.position(b))))
+                .max(glm::length2(&(self.position(a) - self.position(c))));
+            if glm::length(&self.face_cross(t)) <= f32::EPSILON * longest_edge {
+                report.degenerate_triangles.push(t);
+            }
+        }
+
+        report
+    }
 }
 
-pub struct Terrain;
-impl Terrain {
-    pub fn load(path: &str) -> Mesh {
-        println!("Loading terrain model...");
-        let before = std::time::Instant::now();
-        let (models, _materials) = tobj::load_obj(path, true).expect("Failed to load terrain model");
-        let after = std::time::Instant::now();
-        println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
-        if models.len() > 1 || models.len() == 0 { panic!("Please use a model with a single mesh") }
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeMismatch {
+    pub attribute: &'static str,
+    pub expected: usize,
+    pub found: usize,
+}
+
+/// Result of `Mesh::validate`. Index-valued fields refer to positions in the index buffer,
+/// triangle-valued fields to triangle numbers.
+#[derive(Debug, Clone, Default)]
+pub struct MeshReport {
+    pub vertex_count: usize,
+    pub triangle_count: usize,
+    pub attribute_mismatches: Vec<AttributeMismatch>,
+    pub out_of_range_indices: Vec<usize>,
+    pub degenerate_triangles: Vec<usize>,
+    pub trailing_indices: usize,
+    pub index_count_mismatch: bool,
+}
 
-        let terrain = models[0].to_owned();
-        println!("Loaded {} with {} points and {} triangles.", terrain.name, terrain.mesh.positions.len() /3, terrain.mesh.indices.len() / 3);
+impl MeshReport {
+    /// The mesh can be uploaded and drawn without reading out of bounds
+    pub fn is_valid(&self) -> bool {
+        self.attribute_mismatches.is_empty()
+            && self.out_of_range_indices.is_empty()
+            && self.trailing_indices == 0
+            && !self.index_count_mismatch
+    }
 
-        Mesh::from(terrain.mesh, [1.0, 1.0, 1.0, 1.0])
+    /// Valid, and without any degenerate triangles
+    pub fn is_clean(&self) -> bool {
+        self.is_valid() && self.degenerate_triangles.is_empty()
+    }
+}
+
+impl fmt::Display for MeshReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mesh with {} vertices and {} triangles", self.vertex_count, self.triangle_count)?;
+        if self.is_clean() {
+            return write!(f, " is valid.");
+        }
+        write!(f, " has problems:")?;
+        for mismatch in &self.attribute_mismatches {
+            write!(f, "\n  {} has {} values, expected {}", mismatch.attribute, mismatch.found, mismatch.expected)?;
+        }
+        if !self.out_of_range_indices.is_empty() {
+            write!(f, "\n  {} indices are out of range", self.out_of_range_indices.len())?;
+        }
+        if self.trailing_indices > 0 {
+            write!(f, "\n  {} trailing indices don't form a whole primitive", self.trailing_indices)?;
+        }
+        if self.index_count_mismatch {
+            write!(f, "\n  index_count doesn't match the length of the index buffer")?;
+        }
+        if !self.degenerate_triangles.is_empty() {
+            write!(f, "\n  {} triangles are degenerate", self.degenerate_triangles.len())?;
+        }
+        Ok(())
     }
 }
 
 use std::ops::Index;
+
+use crate::material::{self, MtlPbrMaterial};
+use crate::mesh_cache;
+
+// Model names of the helicopter parts in the OBJ file, in index order
+const HELICOPTER_PARTS: [&str; 4] = ["Body_body", "Main_Rotor_main_rotor", "Tail_Rotor_tail_rotor", "Door_door"];
+
 pub struct Helicopter {
     pub body: Mesh,
     pub main_rotor: Mesh,
     pub tail_rotor: Mesh,
     pub door: Mesh,
+    // The PBR materials of the parts from the model's MTL files, in index order
+    pub materials: Vec<Option<MtlPbrMaterial>>,
 }
 
 // You can use square brackets to access the components of the helicopter, if you want to use loops!
 impl Index<usize> for Helicopter {
     type Output = Mesh;
-    fn index<'a>(&'a self, i: usize) -> &'a Mesh {
+    fn index(&self, i: usize) -> &Mesh {
         match i {
             0 => &self.body,
             1 => &self.main_rotor,
@@ -69,6 +550,15 @@ impl Helicopter {
     pub fn load(path: &str) -> Self {
         println!("Loading helicopter model...");
         let before = std::time::Instant::now();
+        let cached: Option<Vec<Mesh>> = HELICOPTER_PARTS.iter().map(|name| mesh_cache::load(path, name)).collect();
+        if let Some(mut parts) = cached {
+            println!("Done in {:.3}ms, from the mesh cache.", before.elapsed().as_micros() as f32 / 1e3);
+            let door = parts.pop().unwrap();
+            let tail_rotor = parts.pop().unwrap();
+            let main_rotor = parts.pop().unwrap();
+            let body = parts.pop().unwrap();
+            return Helicopter { body, main_rotor, tail_rotor, door, materials: vec![] }.with_materials(path);
+        }
         let (models, _materials) = tobj::load_obj(path, true).expect("Failed to load helicopter model");
         let after = std::time::Instant::now();
         println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
@@ -77,16 +567,173 @@ impl Helicopter {
             println!("Loaded {} with {} points and {} triangles.", model.name, model.mesh.positions.len() / 3, model.mesh.indices.len() / 3);
         }
 
-        let body_model = models.iter().find(|m| m.name == "Body_body").expect("Incorrect model file!").to_owned();
-        let main_rotor_model = models.iter().find(|m| m.name == "Main_Rotor_main_rotor").expect("Incorrect model file!").to_owned();
-        let tail_rotor_model = models.iter().find(|m| m.name == "Tail_Rotor_tail_rotor").expect("Incorrect model file!").to_owned();
-        let door_model = models.iter().find(|m| m.name == "Door_door").expect("Incorrect model file!").to_owned();
+        let body_model = models.iter().find(|m| m.name == HELICOPTER_PARTS[0]).expect("Incorrect model file!").to_owned();
+        let main_rotor_model = models.iter().find(|m| m.name == HELICOPTER_PARTS[1]).expect("Incorrect model file!").to_owned();
+        let tail_rotor_model = models.iter().find(|m| m.name == HELICOPTER_PARTS[2]).expect("Incorrect model file!").to_owned();
+        let door_model = models.iter().find(|m| m.name == HELICOPTER_PARTS[3]).expect("Incorrect model file!").to_owned();
+
+        let (body, body_stats)             = Mesh::from(body_model.mesh,         [0.3, 0.3, 0.3, 1.0]);
+        let (main_rotor, main_rotor_stats) = Mesh::from(main_rotor_model.mesh,   [0.3, 0.1, 0.1, 1.0]);
+        let (tail_rotor, tail_rotor_stats) = Mesh::from(tail_rotor_model.mesh,   [0.1, 0.3, 0.1, 1.0]);
+        let (door, door_stats)             = Mesh::from(door_model.mesh,         [0.1, 0.1, 0.3, 1.0]);
+        for (name, stats) in HELICOPTER_PARTS.iter().zip(&[body_stats, main_rotor_stats, tail_rotor_stats, door_stats]) {
+            if let Some(stats) = stats {
+                println!("{}: {}", name, stats);
+            }
+        }
+
+        let helicopter = Helicopter { body, main_rotor, tail_rotor, door, materials: vec![] };
+        for (i, name) in HELICOPTER_PARTS.iter().enumerate() {
+            mesh_cache::store(path, name, &helicopter[i]);
+        }
+        helicopter.with_materials(path)
+    }
+
+    // Looks up the PBR materials of the parts. Parts that have one are colored white, as the
+    // base color multiplies the vertex colors. The cache keeps the colors from before this.
+    fn with_materials(mut self, path: &str) -> Self {
+        let mut materials = material::load_obj_pbr_materials(path);
+        self.materials = HELICOPTER_PARTS.iter().map(|name| materials.remove(*name)).collect();
+        let mut parts = [&mut self.body, &mut self.main_rotor, &mut self.tail_rotor, &mut self.door];
+        for (part, material) in parts.iter_mut().zip(&self.materials) {
+            if material.is_some() {
+                part.colors = generate_color_vec([1., 1., 1., 1.], part.vertex_count());
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_tangent(mesh: &Mesh, vertex: usize, expected: [f32; 4]) {
+        let tangent = &mesh.tangents[4*vertex..4*vertex + 4];
+        assert!(
+            tangent.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-5),
+            "vertex {} has tangent {:?}, expected {:?}", vertex, tangent, expected
+        );
+    }
+
+    // Two quads side by side facing +z. The right one's UVs mirror the left one's across the
+    // shared edge, like a symmetric model whose halves share a texture.
+    fn mirrored_quads() -> Mesh {
+        let mut mesh = Mesh::empty();
+        mesh.vertices = vec![0., 0., 0., 1., 0., 0., 2., 0., 0., 0., 1., 0., 1., 1., 0., 2., 1., 0.];
+        mesh.normals = [0., 0., 1.].repeat(6);
+        mesh.uvs = vec![0., 0., 1., 0., 0., 0., 0., 1., 1., 1., 0., 1.];
+        mesh.set_indices(vec![0, 1, 4, 0, 4, 3, 1, 2, 5, 1, 5, 4]);
+        mesh
+    }
+
+    #[test]
+    fn tangents_of_a_mirrored_uv_island() {
+        let mut mesh = mirrored_quads();
+        mesh.generate_tangents();
+
+        // The vertices on the mirror line get a copy for each side
+        assert_eq!(mesh.vertex_count(), 8);
+        // On the left u grows along +x, and the bitangent, +v, is +y = cross(N, T)
+        for &corner in &[0, 1, 2, 3, 4, 5] {
+            assert_tangent(&mesh, mesh.indices[corner] as usize, [1., 0., 0., 1.]);
+        }
+        // On the right u grows along -x, and +v is still +y = -cross(N, T)
+        for &corner in &[6, 7, 8, 9, 10, 11] {
+            assert_tangent(&mesh, mesh.indices[corner] as usize, [-1., 0., 0., -1.]);
+        }
+        for corner in 0..12 {
+            let (original, split) = (mirrored_quads(), mesh.indices[corner] as usize);
+            assert_eq!(mesh.position(split), original.position(original.indices[corner] as usize));
+        }
+    }
+
+    #[test]
+    fn tangents_without_seams_split_nothing() {
+        let grid = Mesh::plane_grid(2., 2., 3, 3, [1.; 4]);
+        assert_eq!(grid.vertex_count(), 16);
+        for vertex in 0..grid.vertex_count() {
+            // u grows along +x and v along -z, which is cross(+y, +x)
+            assert_tangent(&grid, vertex, [1., 0., 0., 1.]);
+        }
+    }
+
+    // A cube with one vertex per corner and no normals, like a model exported without them
+    fn welded_cube() -> Mesh {
+        let mut cube = Mesh::cube(glm::vec3(2., 2., 2.), [1.; 4]);
+        cube.normals.clear();
+        cube.uvs.clear();
+        cube.tangents.clear();
+        cube.weld();
+        cube
+    }
+
+    #[test]
+    fn validate_accepts_a_cube() {
+        let report = Mesh::cube(glm::vec3(1., 2., 3.), [1.; 4]).validate();
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!((report.vertex_count, report.triangle_count), (24, 12));
+    }
+
+    #[test]
+    fn validate_reports_bad_indices_and_attributes() {
+        let mut mesh = Mesh::cube(glm::vec3(1., 1., 1.), [1.; 4]);
+        mesh.indices[4] = 99;
+        mesh.indices[7] = mesh.indices[6];
+        mesh.colors.pop();
+        let report = mesh.validate();
+        assert!(!report.is_valid());
+        assert_eq!(report.out_of_range_indices, vec![4]);
+        assert_eq!(report.degenerate_triangles, vec![2]);
+        assert_eq!(
+            report.attribute_mismatches,
+            vec![AttributeMismatch { attribute: "colors", expected: 96, found: 95 }]
+        );
+        assert!(!report.index_count_mismatch);
+
+        mesh.indices.push(0);
+        let report = mesh.validate();
+        assert_eq!(report.trailing_indices, 1);
+        assert!(report.index_count_mismatch);
+    }
+
+    #[test]
+    fn smooth_normals_split_the_cube_above_the_crease_angle() {
+        let mut hard = welded_cube();
+        assert_eq!(hard.vertex_count(), 8);
+        hard.generate_smooth_normals(DEFAULT_CREASE_ANGLE);
+        // The faces meet at right angles, so every corner gets a copy per face
+        assert_eq!(hard.vertex_count(), 24);
+        for t in 0..hard.triangle_count() {
+            let face_normal = glm::normalize(&hard.face_cross(t));
+            for &i in &hard.triangle(t) {
+                assert!(glm::distance(&hard.normal(i), &face_normal) < 1e-5);
+            }
+        }
+
+        let mut smooth = welded_cube();
+        smooth.generate_smooth_normals(std::f32::consts::PI);
+        assert_eq!(smooth.vertex_count(), 8);
+        for i in 0..smooth.vertex_count() {
+            // Pointing straight out of the corner
+            let outwards = glm::normalize(&smooth.position(i));
+            assert!(glm::distance(&smooth.normal(i), &outwards) < 1e-5);
+        }
+    }
 
-        Helicopter {
-            body:       Mesh::from(body_model.mesh,         [0.3, 0.3, 0.3, 1.0]),
-            main_rotor: Mesh::from(main_rotor_model.mesh,   [0.3, 0.1, 0.1, 1.0]),
-            tail_rotor: Mesh::from(tail_rotor_model.mesh,   [0.1, 0.3, 0.1, 1.0]),
-            door:       Mesh::from(door_model.mesh,         [0.1, 0.1, 0.3, 1.0]),
+    #[test]
+    fn flat_normals_give_every_triangle_its_own_vertices() {
+        let mut mesh = welded_cube();
+        let original = mesh.clone();
+        mesh.generate_flat_normals();
+        assert_eq!(mesh.vertex_count(), 36);
+        for t in 0..mesh.triangle_count() {
+            let face_normal = glm::normalize(&mesh.face_cross(t));
+            let ([a, b, c], [oa, ob, oc]) = (mesh.triangle(t), original.triangle(t));
+            for &(i, o) in &[(a, oa), (b, ob), (c, oc)] {
+                assert_eq!(mesh.normal(i), face_normal);
+                assert_eq!(mesh.position(i), original.position(o));
+            }
         }
     }
-}
\ No newline at end of file
+}
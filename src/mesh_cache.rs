@@ -0,0 +1,345 @@
+extern crate nalgebra_glm as glm;
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::mesh::{Mesh, Primitive};
+
+// Binary cache of processed meshes, so big OBJ files only have to be parsed once. Cache files
+// are read in full with fs::read rather than memory mapped, on purpose: every value is copied
+// into the mesh's own Vecs anyway, and mapping would need unsafe code and another dependency.
+//
+// Layout, all little endian:
+//   magic "GLMC", format version u32
+//   source modification time (seconds u64, nanoseconds u32), source size u64, source hash u64
+//   vertex count u32, index count u32, attribute flags u32, primitive u32
+//   bounds min [f32; 3], max [f32; 3]
+//   positions, then normals, colors, uvs and tangents if flagged, as f32, then indices as u32
+
+const MAGIC: &[u8; 4] = b"GLMC";
+// Bump whenever the layout, or the processing done to meshes before they are cached, changes
+pub const FORMAT_VERSION: u32 = 3;
+
+const HAS_NORMALS: u32 = 1;
+const HAS_COLORS: u32 = 2;
+const HAS_UVS: u32 = 4;
+const HAS_TANGENTS: u32 = 8;
+
+const PRIMITIVES: [Primitive; 4] = [Primitive::Triangles, Primitive::Lines, Primitive::LineStrip, Primitive::Points];
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// 64 bit FNV-1a, plenty to notice that a source file has changed
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// What a cached mesh was built from. The cheap modification time and size are checked first,
+/// the hash is only computed when those differ, e.g. after a fresh checkout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceStamp {
+    pub modified_secs: u64,
+    pub modified_nanos: u32,
+    pub size: u64,
+    pub hash: u64,
+}
+
+impl SourceStamp {
+    fn metadata_of(path: &Path) -> io::Result<(u64, u32, u64)> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok((modified.as_secs(), modified.subsec_nanos(), metadata.len()))
+    }
+
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let (modified_secs, modified_nanos, size) = SourceStamp::metadata_of(path)?;
+        Ok(SourceStamp { modified_secs, modified_nanos, size, hash: fnv1a(&fs::read(path)?) })
+    }
+
+    /// The stamp of the file at `path` if it still has the contents this one was made from,
+    /// None if it changed. The stamp returned has the file's current modification time, so
+    /// when only that changed the cache can be stamped again and skip the hash next time.
+    pub fn check(&self, path: &Path) -> io::Result<Option<SourceStamp>> {
+        let (modified_secs, modified_nanos, size) = SourceStamp::metadata_of(path)?;
+        if (modified_secs, modified_nanos, size) == (self.modified_secs, self.modified_nanos, self.size) {
+            return Ok(Some(*self));
+        }
+        if fnv1a(&fs::read(path)?) != self.hash {
+            return Ok(None);
+        }
+        Ok(Some(SourceStamp { modified_secs, modified_nanos, size, hash: self.hash }))
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let end = self.position.checked_add(count).filter(|&end| end <= self.data.len())
+            .ok_or_else(|| invalid("mesh cache is truncated"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().expect("slice of 4 bytes")))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().expect("slice of 8 bytes")))
+    }
+
+    fn f32s(&mut self, count: usize) -> io::Result<Vec<f32>> {
+        Ok(self.bytes(count * 4)?.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().expect("chunk of 4 bytes"))).collect())
+    }
+
+    fn u32s(&mut self, count: usize) -> io::Result<Vec<u32>> {
+        Ok(self.bytes(count * 4)?.chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().expect("chunk of 4 bytes"))).collect())
+    }
+}
+
+/// A mesh read back from the cache, with the header information stored alongside it
+pub struct CachedMesh {
+    pub mesh: Mesh,
+    pub source: SourceStamp,
+    #[allow(dead_code)]
+    pub bounds: (glm::Vec3, glm::Vec3),
+}
+
+impl Mesh {
+    /// Smallest axis aligned box containing every vertex
+    pub fn bounds(&self) -> (glm::Vec3, glm::Vec3) {
+        if self.vertex_count() == 0 { return (glm::zero(), glm::zero()) }
+        (0..self.vertex_count()).fold(
+            (glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY), glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY)),
+            |(min, max), i| (glm::min2(&min, &self.position(i)), glm::max2(&max, &self.position(i))),
+        )
+    }
+
+    pub fn save_binary(&self, path: &Path, source: &SourceStamp) -> io::Result<()> {
+        let vertex_count = self.vertex_count();
+        let optional: [(&Vec<f32>, usize, u32); 4] = [
+            (&self.normals, 3, HAS_NORMALS),
+            (&self.colors, 4, HAS_COLORS),
+            (&self.uvs, 2, HAS_UVS),
+            (&self.tangents, 4, HAS_TANGENTS),
+        ];
+        let flags = optional.iter()
+            .filter(|(attribute, size, _)| attribute.len() == vertex_count * size)
+            .fold(0, |flags, (_, _, flag)| flags | flag);
+        let (min, max) = self.bounds();
+
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&source.modified_secs.to_le_bytes());
+        data.extend_from_slice(&source.modified_nanos.to_le_bytes());
+        data.extend_from_slice(&source.size.to_le_bytes());
+        data.extend_from_slice(&source.hash.to_le_bytes());
+        data.extend_from_slice(&(vertex_count as u32).to_le_bytes());
+        data.extend_from_slice(&(self.indices.len() as u32).to_le_bytes());
+        data.extend_from_slice(&flags.to_le_bytes());
+        let primitive = PRIMITIVES.iter().position(|&p| p == self.primitive).expect("every primitive has a code") as u32;
+        data.extend_from_slice(&primitive.to_le_bytes());
+        for value in min.iter().chain(max.iter()).chain(self.vertices.iter()) {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for (attribute, _, flag) in optional.iter() {
+            if flags & flag == 0 { continue }
+            for value in attribute.iter() {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        for index in &self.indices {
+            data.extend_from_slice(&index.to_le_bytes());
+        }
+
+        // Write to a temporary file first, so a crash never leaves a half written cache behind
+        let temporary = path.with_extension("meshcache.tmp");
+        fs::File::create(&temporary)?.write_all(&data)?;
+        fs::rename(&temporary, path)
+    }
+
+    pub fn load_binary(path: &Path) -> io::Result<CachedMesh> {
+        let data = fs::read(path)?;
+        let mut reader = Reader { data: &data, position: 0 };
+        if reader.bytes(4)? != MAGIC {
+            return Err(invalid("not a mesh cache file"));
+        }
+        if reader.u32()? != FORMAT_VERSION {
+            return Err(invalid("mesh cache has an old format version"));
+        }
+        let source = SourceStamp {
+            modified_secs: reader.u64()?,
+            modified_nanos: reader.u32()?,
+            size: reader.u64()?,
+            hash: reader.u64()?,
+        };
+        let vertex_count = reader.u32()? as usize;
+        let index_count = reader.u32()? as usize;
+        let flags = reader.u32()?;
+        let primitive = *PRIMITIVES.get(reader.u32()? as usize).ok_or_else(|| invalid("mesh cache has an unknown primitive"))?;
+        let bounds = reader.f32s(6)?;
+
+        let mut mesh = Mesh::empty();
+        mesh.primitive = primitive;
+        mesh.vertices = reader.f32s(vertex_count * 3)?;
+        if flags & HAS_NORMALS != 0 { mesh.normals = reader.f32s(vertex_count * 3)? }
+        if flags & HAS_COLORS != 0 { mesh.colors = reader.f32s(vertex_count * 4)? }
+        if flags & HAS_UVS != 0 { mesh.uvs = reader.f32s(vertex_count * 2)? }
+        if flags & HAS_TANGENTS != 0 { mesh.tangents = reader.f32s(vertex_count * 4)? }
+        mesh.set_indices(reader.u32s(index_count)?);
+        if mesh.indices.iter().any(|&i| i as usize >= vertex_count) {
+            return Err(invalid("mesh cache has out of range indices"));
+        }
+
+        Ok(CachedMesh {
+            mesh,
+            source,
+            bounds: (glm::vec3(bounds[0], bounds[1], bounds[2]), glm::vec3(bounds[3], bounds[4], bounds[5])),
+        })
+    }
+}
+
+/// Where the cached version of the named model in an OBJ file is kept, next to the OBJ itself
+pub fn cache_path(source: &str, model_name: &str) -> PathBuf {
+    let file_name = Path::new(source).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    Path::new(source).with_file_name(format!("{}.{}.meshcache", file_name, model_name))
+}
+
+/// The cached mesh for a model, if there is one and its OBJ hasn't changed since it was made
+pub fn load(source: &str, model_name: &str) -> Option<Mesh> {
+    let path = cache_path(source, model_name);
+    let cached = Mesh::load_binary(&path).ok()?;
+    let stamp = cached.source.check(Path::new(source)).ok()??;
+    if stamp != cached.source {
+        // Only touched, e.g. by a fresh checkout, so the hash doesn't have to be checked again
+        if let Err(e) = cached.mesh.save_binary(&path, &stamp) {
+            println!("Warning: failed to restamp {} from {}: {}", model_name, source, e);
+        }
+    }
+    Some(cached.mesh)
+}
+
+/// Caches a processed mesh. Failing to write the cache is not fatal, it just means the OBJ
+/// gets parsed again next time.
+pub fn store(source: &str, model_name: &str, mesh: &Mesh) {
+    let result = SourceStamp::of(Path::new(source))
+        .and_then(|stamp| mesh.save_binary(&cache_path(source, model_name), &stamp));
+    if let Err(e) = result {
+        println!("Warning: failed to cache {} from {}: {}", model_name, source, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scratch directory of its own for every test, as they run in parallel. Removed again
+    // when dropped, whether the test passed or not.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new(test: &str) -> Self {
+            let directory = std::env::temp_dir().join(format!("gloom-mesh-cache-{}-{}", std::process::id(), test));
+            fs::create_dir_all(&directory).unwrap();
+            ScratchDir(directory)
+        }
+
+        fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn stamp() -> SourceStamp {
+        SourceStamp { modified_secs: 1, modified_nanos: 2, size: 3, hash: 4 }
+    }
+
+    #[test]
+    fn round_trip_keeps_every_attribute() {
+        let scratch = ScratchDir::new("round-trip");
+        let path = scratch.join("cube.meshcache");
+        let mesh = Mesh::cube(glm::vec3(1., 2., 3.), [0.1, 0.2, 0.3, 1.]);
+        mesh.save_binary(&path, &stamp()).unwrap();
+
+        let cached = Mesh::load_binary(&path).unwrap();
+        assert_eq!(cached.source, stamp());
+        assert_eq!(cached.mesh.vertices, mesh.vertices);
+        assert_eq!(cached.mesh.normals, mesh.normals);
+        assert_eq!(cached.mesh.colors, mesh.colors);
+        assert_eq!(cached.mesh.uvs, mesh.uvs);
+        assert_eq!(cached.mesh.tangents, mesh.tangents);
+        assert_eq!(cached.mesh.indices, mesh.indices);
+        assert_eq!(cached.mesh.primitive, mesh.primitive);
+        assert_eq!(cached.bounds, mesh.bounds());
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let scratch = ScratchDir::new("truncated");
+        let path = scratch.join("cube.meshcache");
+        Mesh::cube(glm::vec3(1., 1., 1.), [1.; 4]).save_binary(&path, &stamp()).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+        let error = Mesh::load_binary(&path).err().expect("a truncated cache must not load");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_format_version_is_rejected() {
+        let scratch = ScratchDir::new("version");
+        let path = scratch.join("cube.meshcache");
+        Mesh::cube(glm::vec3(1., 1., 1.), [1.; 4]).save_binary(&path, &stamp()).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        let error = Mesh::load_binary(&path).err().expect("a cache of another version must not load");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn touched_source_is_restamped() {
+        let scratch = ScratchDir::new("restamp");
+        let source = scratch.join("model.obj");
+        fs::write(&source, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let source = source.to_str().unwrap();
+        let current = SourceStamp::of(Path::new(source)).unwrap();
+        // Same contents, different modification time, like after a fresh checkout
+        let touched = SourceStamp { modified_secs: current.modified_secs + 1, ..current };
+        Mesh::cube(glm::vec3(1., 1., 1.), [1.; 4]).save_binary(&cache_path(source, "cube"), &touched).unwrap();
+
+        assert!(load(source, "cube").is_some());
+        assert_eq!(Mesh::load_binary(&cache_path(source, "cube")).unwrap().source, current);
+    }
+
+    #[test]
+    fn changed_source_is_not_loaded() {
+        let scratch = ScratchDir::new("changed");
+        let source = scratch.join("model.obj");
+        fs::write(&source, "v 0 0 0\n").unwrap();
+        let source = source.to_str().unwrap();
+        store(source, "cube", &Mesh::cube(glm::vec3(1., 1., 1.), [1.; 4]));
+        assert!(load(source, "cube").is_some());
+
+        // A different size, so this doesn't depend on how fine the modification times are
+        fs::write(source, "v 1 1 1\nv 2 2 2\n").unwrap();
+        assert!(load(source, "cube").is_none());
+    }
+}
@@ -0,0 +1,361 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::mesh::{Mesh, Primitive};
+
+// Size of the FIFO cache used for ACMR statistics, about what real hardware does
+const STATS_CACHE_SIZE: usize = 16;
+// Size of the LRU cache modelled by the triangle reordering
+const ORDER_CACHE_SIZE: usize = 32;
+
+/// Average cache miss ratio: transformed vertices per triangle for a FIFO post-transform
+/// cache. 3 is the worst case, around 0.6 is very good.
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32 {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 { return 0.0 }
+    let mut cache: VecDeque<u32> = VecDeque::with_capacity(cache_size);
+    let mut misses = 0;
+    for &index in &indices[..triangle_count * 3] {
+        if !cache.contains(&index) {
+            misses += 1;
+            if cache.len() == cache_size {
+                cache.pop_front();
+            }
+            cache.push_back(index);
+        }
+    }
+    misses as f32 / triangle_count as f32
+}
+
+// Vertex score from Tom Forsyth's "Linear-Speed Vertex Cache Optimisation"
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 { return -1.0 }
+    let cache_score = match cache_position {
+        // The last triangle's vertices get a fixed score, so the next triangle doesn't just reuse them
+        Some(position) if position < 3 => 0.75,
+        Some(position) => (1.0 - (position - 3) as f32 / (ORDER_CACHE_SIZE - 3) as f32).powf(1.5),
+        None => 0.0,
+    };
+    // Favour vertices with few triangles left, to finish them off and not leave them stranded
+    cache_score + 2.0 * (remaining_triangles as f32).powf(-0.5)
+}
+
+// Greedy triangle ordering that keeps recently used vertices in the cache as long as possible
+fn forsyth_order(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    let mut triangles_of: Vec<Vec<usize>> = vec![Vec::new(); vertex_count];
+    for t in 0..triangle_count {
+        for &index in &indices[3*t..3*t + 3] {
+            triangles_of[index as usize].push(t);
+        }
+    }
+
+    let mut cache_position: Vec<Option<usize>> = vec![None; vertex_count];
+    let mut scores: Vec<f32> = triangles_of.iter().map(|t| vertex_score(None, t.len())).collect();
+    let mut triangle_scores: Vec<f32> = (0..triangle_count)
+        .map(|t| indices[3*t..3*t + 3].iter().map(|&i| scores[i as usize]).sum())
+        .collect();
+    let mut emitted = vec![false; triangle_count];
+    let mut cache: Vec<u32> = Vec::with_capacity(ORDER_CACHE_SIZE + 3);
+    let mut order = Vec::with_capacity(triangle_count * 3);
+    let mut next_unemitted = 0;
+    let mut best = (0..triangle_count).max_by(|&a, &b| triangle_scores[a].partial_cmp(&triangle_scores[b]).unwrap_or(std::cmp::Ordering::Equal));
+
+    while order.len() < triangle_count * 3 {
+        let t = match best {
+            Some(t) => t,
+            None => {
+                // Nothing left around the cache, start over with any remaining triangle
+                while emitted[next_unemitted] { next_unemitted += 1 }
+                next_unemitted
+            }
+        };
+        emitted[t] = true;
+        let corners = [indices[3*t], indices[3*t + 1], indices[3*t + 2]];
+        order.extend_from_slice(&corners);
+        for &index in &corners {
+            triangles_of[index as usize].retain(|&other| other != t);
+        }
+
+        // Move the triangle's vertices to the front of the cache, everything past the end falls out
+        let mut new_cache: Vec<u32> = corners.to_vec();
+        new_cache.extend(cache.iter().filter(|i| !corners.contains(i)));
+        for &evicted in new_cache.iter().skip(ORDER_CACHE_SIZE) {
+            cache_position[evicted as usize] = None;
+        }
+        new_cache.truncate(ORDER_CACHE_SIZE);
+        let mut touched = new_cache.clone();
+        touched.extend(cache.iter().filter(|&&i| cache_position[i as usize].is_none()));
+        cache = new_cache;
+        for (position, &index) in cache.iter().enumerate() {
+            cache_position[index as usize] = Some(position);
+        }
+
+        for &index in &touched {
+            let index = index as usize;
+            let score = vertex_score(cache_position[index], triangles_of[index].len());
+            let delta = score - scores[index];
+            scores[index] = score;
+            for &other in &triangles_of[index] {
+                triangle_scores[other] += delta;
+            }
+        }
+
+        best = None;
+        let mut best_score = f32::NEG_INFINITY;
+        for &index in &cache {
+            for &other in &triangles_of[index as usize] {
+                if triangle_scores[other] > best_score {
+                    best = Some(other);
+                    best_score = triangle_scores[other];
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Vertex counts and cache efficiency before and after `Mesh::optimize`
+#[derive(Clone, Copy, Debug)]
+pub struct OptimizationStats {
+    pub vertices_before: usize,
+    pub vertices_after: usize,
+    pub acmr_before: f32,
+    pub acmr_after: f32,
+}
+
+impl fmt::Display for OptimizationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Optimized mesh: {} -> {} vertices, ACMR {:.3} -> {:.3}",
+            self.vertices_before, self.vertices_after, self.acmr_before, self.acmr_after
+        )
+    }
+}
+
+impl Mesh {
+    /// Merges vertices whose attributes are all exactly equal
+    pub fn weld(&mut self) {
+        let vertex_count = self.vertex_count();
+        let attributes: Vec<(&[f32], usize)> = [(&self.vertices, 3), (&self.normals, 3), (&self.colors, 4), (&self.uvs, 2), (&self.tangents, 4)]
+            .iter()
+            .filter(|(attribute, size)| attribute.len() == vertex_count * size)
+            .map(|&(attribute, size)| (attribute.as_slice(), size))
+            .collect();
+
+        let mut unique: HashMap<Vec<u32>, u32> = HashMap::new();
+        let mut sources: Vec<u32> = Vec::new();
+        let remap: Vec<u32> = (0..vertex_count).map(|i| {
+            let key: Vec<u32> = attributes.iter()
+                .flat_map(|(attribute, size)| attribute[i * size..(i + 1) * size].iter().map(|x| x.to_bits()))
+                .collect();
+            *unique.entry(key).or_insert_with(|| {
+                sources.push(i as u32);
+                (sources.len() - 1) as u32
+            })
+        }).collect();
+
+        self.gather_vertices(&sources);
+        let indices = self.indices.iter().map(|&i| remap[i as usize]).collect();
+        self.set_indices(indices);
+    }
+
+    /// Reorders triangles so vertices are reused while they are still in the post-transform
+    /// cache. The order is kept if it already does better. Only triangle meshes are reordered.
+    pub fn optimize_vertex_cache(&mut self) {
+        if self.primitive != Primitive::Triangles { return }
+        let triangle_count = self.triangle_count();
+        let order = forsyth_order(&self.indices[..triangle_count * 3], self.vertex_count());
+        // The greedy ordering usually wins, but isn't guaranteed to
+        if acmr(&order, STATS_CACHE_SIZE) <= acmr(&self.indices, STATS_CACHE_SIZE) {
+            self.set_indices(order);
+        }
+    }
+
+    /// Groups the cache optimized triangles into clusters and draws the clusters facing away
+    /// from the middle of the mesh first, as they are the most likely to hide the rest
+    pub fn optimize_overdraw(&mut self) {
+        if self.primitive != Primitive::Triangles { return }
+        let triangle_count = self.triangle_count();
+        if triangle_count == 0 { return }
+
+        // A cluster starts wherever the cache order had to start over, i.e. all three vertices missed
+        let mut cache: VecDeque<u32> = VecDeque::new();
+        let mut cluster_starts = vec![0];
+        for t in 0..triangle_count {
+            let mut misses = 0;
+            for &index in &self.indices[3*t..3*t + 3] {
+                if !cache.contains(&index) {
+                    misses += 1;
+                    if cache.len() == STATS_CACHE_SIZE {
+                        cache.pop_front();
+                    }
+                    cache.push_back(index);
+                }
+            }
+            if misses == 3 && t > 0 {
+                cluster_starts.push(t);
+            }
+        }
+        cluster_starts.push(triangle_count);
+
+        let centroid = (0..self.vertex_count()).fold(glm::zero::<glm::Vec3>(), |sum, i| sum + self.position(i))
+            / self.vertex_count().max(1) as f32;
+        let mut clusters: Vec<(f32, usize, usize)> = cluster_starts.windows(2).map(|w| {
+            let (mut center, mut normal, mut area) = (glm::zero::<glm::Vec3>(), glm::zero::<glm::Vec3>(), 0.0);
+            for t in w[0]..w[1] {
+                let cross = self.face_cross(t);
+                let [a, b, c] = self.triangle(t);
+                let weight = glm::length(&cross);
+                center += (self.position(a) + self.position(b) + self.position(c)) / 3.0 * weight;
+                normal += cross;
+                area += weight;
+            }
+            let facing = if area > 0.0 && glm::length(&normal) > 0.0 {
+                glm::dot(&(center / area - centroid), &glm::normalize(&normal))
+            } else {
+                0.0
+            };
+            (facing, w[0], w[1])
+        }).collect();
+        clusters.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let indices = clusters.iter().flat_map(|&(_, start, end)| self.indices[3*start..3*end].to_vec()).collect();
+        self.set_indices(indices);
+    }
+
+    /// Renumbers vertices in the order the triangles first use them, so vertex fetches
+    /// walk through memory linearly. Unused vertices are dropped.
+    pub fn optimize_vertex_fetch(&mut self) {
+        let mut remap: Vec<Option<u32>> = vec![None; self.vertex_count()];
+        let mut sources: Vec<u32> = Vec::new();
+        let indices = self.indices.iter().map(|&index| {
+            *remap[index as usize].get_or_insert_with(|| {
+                sources.push(index);
+                (sources.len() - 1) as u32
+            })
+        }).collect();
+        self.gather_vertices(&sources);
+        self.set_indices(indices);
+    }
+
+    /// Runs all the optimizations above, in the order they have to happen. The ACMR never ends
+    /// up worse than it is after welding.
+    pub fn optimize(&mut self) -> OptimizationStats {
+        let vertices_before = self.vertex_count();
+        let acmr_before = acmr(&self.indices, STATS_CACHE_SIZE);
+        self.weld();
+        let welded = self.indices.clone();
+        self.optimize_vertex_cache();
+        self.optimize_overdraw();
+        // Reordering the clusters can cost the cache hits between them
+        if acmr(&self.indices, STATS_CACHE_SIZE) > acmr(&welded, STATS_CACHE_SIZE) {
+            self.set_indices(welded);
+        }
+        self.optimize_vertex_fetch();
+        OptimizationStats {
+            vertices_before,
+            vertices_after: self.vertex_count(),
+            acmr_before,
+            acmr_after: acmr(&self.indices, STATS_CACHE_SIZE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A grid with its triangles in a scrambled order, which the vertex cache hates
+    fn scrambled_grid() -> Mesh {
+        let mut grid = Mesh::plane_grid(10.0, 10.0, 24, 24, [1.0; 4]);
+        let mut triangles: Vec<Vec<u32>> = grid.indices.chunks(3).map(|t| t.to_vec()).collect();
+        let mut state: u32 = 12345;
+        for i in (1..triangles.len()).rev() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            triangles.swap(i, (state >> 8) as usize % (i + 1));
+        }
+        grid.set_indices(triangles.concat());
+        grid
+    }
+
+    // The triangles as their corners' positions, winding kept, in a canonical order
+    fn triangles_by_position(mesh: &Mesh) -> Vec<[[u32; 3]; 3]> {
+        let bits = |i: u32| {
+            let p = mesh.position(i as usize);
+            [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]
+        };
+        let mut triangles: Vec<[[u32; 3]; 3]> = mesh.indices.chunks(3)
+            .map(|t| {
+                let corners = [bits(t[0]), bits(t[1]), bits(t[2])];
+                // Rotate the smallest corner first, which keeps the winding
+                let first = (0..3).min_by_key(|&k| corners[k]).expect("three corners");
+                [corners[first], corners[(first + 1) % 3], corners[(first + 2) % 3]]
+            })
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn acmr_of_separate_triangles_is_three() {
+        assert_eq!(acmr(&[0, 1, 2, 3, 4, 5], STATS_CACHE_SIZE), 3.0);
+        assert_eq!(acmr(&[0, 1, 2, 2, 1, 3], STATS_CACHE_SIZE), 2.0);
+    }
+
+    #[test]
+    fn vertex_cache_order_keeps_every_triangle() {
+        let grid = scrambled_grid();
+        let mut optimized = grid.clone();
+        optimized.optimize_vertex_cache();
+        let sorted = |mesh: &Mesh| {
+            let mut triangles: Vec<Vec<u32>> = mesh.indices.chunks(3).map(|t| t.to_vec()).collect();
+            triangles.sort();
+            triangles
+        };
+        assert_eq!(sorted(&optimized), sorted(&grid));
+        assert!(acmr(&optimized.indices, STATS_CACHE_SIZE) < acmr(&grid.indices, STATS_CACHE_SIZE));
+    }
+
+    #[test]
+    fn optimize_never_makes_acmr_worse() {
+        for mesh in &[scrambled_grid(), Mesh::uv_sphere(1.0, 24, 12, [1.0; 4]), Mesh::torus(2.0, 0.5, 24, 12, [1.0; 4])] {
+            let mut welded = mesh.clone();
+            welded.weld();
+            let mut optimized = mesh.clone();
+            let stats = optimized.optimize();
+            assert!(stats.acmr_after <= acmr(&welded.indices, STATS_CACHE_SIZE), "{}", stats);
+            assert_eq!(stats.acmr_after, acmr(&optimized.indices, STATS_CACHE_SIZE));
+        }
+        let mut scrambled = scrambled_grid();
+        let stats = scrambled.optimize();
+        assert!(stats.acmr_after < stats.acmr_before, "{}", stats);
+    }
+
+    #[test]
+    fn optimize_keeps_lines_and_points() {
+        let ring: Vec<glm::Vec3> = (0..8).map(|i| glm::vec3((i as f32).cos(), 0.0, (i as f32).sin())).collect();
+        let segments: Vec<(glm::Vec3, glm::Vec3)> = ring.windows(2).map(|w| (w[0], w[1])).collect();
+        let meshes = [Mesh::lines(&segments, [1.0; 4]), Mesh::line_strip(&ring, [1.0; 4]), Mesh::points(&ring, [1.0; 4])];
+        let corners = |mesh: &Mesh| mesh.indices.iter().map(|&i| mesh.position(i as usize)).collect::<Vec<_>>();
+        for mesh in &meshes {
+            let mut optimized = mesh.clone();
+            optimized.optimize();
+            assert_eq!(optimized.primitive, mesh.primitive);
+            assert_eq!(corners(&optimized), corners(mesh));
+        }
+    }
+
+    #[test]
+    fn optimize_keeps_every_triangle() {
+        for mesh in &[scrambled_grid(), Mesh::cube(glm::vec3(1.0, 2.0, 3.0), [1.0; 4]), Mesh::torus(2.0, 0.5, 24, 12, [1.0; 4])] {
+            let mut optimized = mesh.clone();
+            optimized.optimize();
+            assert_eq!(triangles_by_position(&optimized), triangles_by_position(mesh));
+            assert!(optimized.vertex_count() <= mesh.vertex_count());
+        }
+    }
+}
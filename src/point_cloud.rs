@@ -0,0 +1,258 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::{BufRead, BufReader};
+
+use crate::mesh::{Mesh, Primitive};
+
+// Loaders for point clouds, e.g. from lidar scans. The points end up in a `Mesh` of
+// `Primitive::Points` without any indices, which is drawn with DrawArrays.
+
+// Color of points whose file doesn't have any
+const DEFAULT_POINT_COLOR: [f32; 4] = [0.8, 0.8, 0.8, 1.0];
+
+fn point_mesh(vertices: Vec<f32>, colors: Vec<f32>) -> Mesh {
+    let mut mesh = Mesh::empty();
+    mesh.primitive = Primitive::Points;
+    mesh.vertices = vertices;
+    mesh.colors = colors;
+    mesh
+}
+
+/// Loads a whitespace separated text file with a point per line. The columns can be
+/// `x y z`, `x y z intensity`, `x y z r g b` or `x y z intensity r g b`. Colors and
+/// intensities are taken as 0-255 if any of them is above 1.
+pub fn load_xyz(path: &str) -> Result<Mesh, String> {
+    let file = fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    let mut max_color: f32 = 0.0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") { continue }
+        let values = line
+            .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+        let color = match values.len() {
+            3 => DEFAULT_POINT_COLOR,
+            4 => [values[3], values[3], values[3], 1.0],
+            6 => [values[3], values[4], values[5], 1.0],
+            7 => [values[4], values[5], values[6], 1.0],
+            n => return Err(format!("{} line {}: expected 3, 4, 6 or 7 values, found {}", path, number + 1, n)),
+        };
+        if values.len() > 3 {
+            max_color = color[..3].iter().fold(max_color, |max, &c| max.max(c));
+        }
+        vertices.extend_from_slice(&values[..3]);
+        colors.extend_from_slice(&color);
+    }
+    if max_color > 1.0 {
+        for (i, c) in colors.iter_mut().enumerate() {
+            if i % 4 != 3 { *c /= 255.0 }
+        }
+    }
+    Ok(point_mesh(vertices, colors))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return Err(format!("unknown PLY type {}", name)),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+
+    // Reads one binary value and converts it to f64
+    fn read(&self, bytes: &[u8], format: PlyFormat) -> f64 {
+        macro_rules! read {
+            ($t:ty) => {{
+                let array = bytes[..std::mem::size_of::<$t>()].try_into().expect("enough bytes for the type");
+                if format == PlyFormat::BinaryBigEndian { <$t>::from_be_bytes(array) as f64 } else { <$t>::from_le_bytes(array) as f64 }
+            }};
+        }
+        match self {
+            PlyType::I8 => read!(i8),
+            PlyType::U8 => read!(u8),
+            PlyType::I16 => read!(i16),
+            PlyType::U16 => read!(u16),
+            PlyType::I32 => read!(i32),
+            PlyType::U32 => read!(u32),
+            PlyType::F32 => read!(f32),
+            PlyType::F64 => read!(f64),
+        }
+    }
+
+    // Integer colors are 0-255, float colors 0-1
+    fn color_scale(&self) -> f32 {
+        match self {
+            PlyType::F32 | PlyType::F64 => 1.0,
+            PlyType::U16 => 1.0 / 65535.0,
+            _ => 1.0 / 255.0,
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    value_type: PlyType,
+    // Type of the length prefix for list properties
+    list_count_type: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+/// Loads the vertices of a PLY file, ASCII or binary, with their colors if they have any.
+/// Faces and other elements are skipped.
+pub fn load_ply(path: &str) -> Result<Mesh, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let header_end = data.windows(10).position(|w| w == b"end_header")
+        .ok_or_else(|| format!("{} has no PLY header", path))?;
+    let body_start = data[header_end..].iter().position(|&b| b == b'\n').map_or(data.len(), |i| header_end + i + 1);
+    let header = String::from_utf8_lossy(&data[..header_end]);
+
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    for line in header.lines().map(|l| l.trim()) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["ply"] | [] => {}
+            ["format", name, _] => format = Some(match *name {
+                "ascii" => PlyFormat::Ascii,
+                "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                _ => return Err(format!("{} has unknown PLY format {}", path, name)),
+            }),
+            ["comment", ..] | ["obj_info", ..] => {}
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("{} has a bad element count: {}", path, line))?,
+                properties: vec![],
+            }),
+            ["property", "list", count_type, value_type, name] => elements.last_mut()
+                .ok_or_else(|| format!("{} has a property before any element", path))?
+                .properties.push(PlyProperty {
+                    name: name.to_string(),
+                    value_type: PlyType::parse(value_type)?,
+                    list_count_type: Some(PlyType::parse(count_type)?),
+                }),
+            ["property", value_type, name] => elements.last_mut()
+                .ok_or_else(|| format!("{} has a property before any element", path))?
+                .properties.push(PlyProperty { name: name.to_string(), value_type: PlyType::parse(value_type)?, list_count_type: None }),
+            _ => return Err(format!("{} has an unknown PLY header line: {}", path, line)),
+        }
+    }
+    let format = format.ok_or_else(|| format!("{} has no PLY format line", path))?;
+
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    let mut body = &data[body_start..];
+    let ascii_body = if format == PlyFormat::Ascii { String::from_utf8_lossy(body).into_owned() } else { String::new() };
+    let mut ascii_tokens = ascii_body.split_whitespace();
+    for element in &elements {
+        let is_vertex = element.name == "vertex";
+        let find = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let position = [find("x"), find("y"), find("z")];
+        let color = [find("red"), find("green"), find("blue"), find("alpha")];
+        if is_vertex && position.iter().any(|p| p.is_none()) {
+            return Err(format!("{} has vertices without x, y and z", path));
+        }
+
+        let mut values = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            for (i, property) in element.properties.iter().enumerate() {
+                // Only scalar properties are kept, lists are read past
+                let count = match property.list_count_type {
+                    None => 1,
+                    Some(_) if format == PlyFormat::Ascii => ascii_tokens.next().and_then(|t| t.parse().ok())
+                        .ok_or_else(|| format!("{} has a bad list length", path))?,
+                    Some(count_type) => {
+                        if body.len() < count_type.size() { return Err(format!("{} ends early", path)) }
+                        let count = count_type.read(body, format) as usize;
+                        body = &body[count_type.size()..];
+                        count
+                    }
+                };
+                for _ in 0..count {
+                    values[i] = match format {
+                        PlyFormat::Ascii => ascii_tokens.next().and_then(|t| t.parse().ok())
+                            .ok_or_else(|| format!("{} has a bad or missing value", path))?,
+                        _ => {
+                            let size = property.value_type.size();
+                            if body.len() < size { return Err(format!("{} ends early", path)) }
+                            let value = property.value_type.read(body, format);
+                            body = &body[size..];
+                            value
+                        }
+                    };
+                }
+            }
+            if is_vertex {
+                vertices.extend(position.iter().map(|p| values[p.expect("checked above")] as f32));
+                if color[..3].iter().all(|c| c.is_some()) {
+                    colors.extend(color.iter().enumerate().map(|(k, c)| match c {
+                        Some(i) => values[*i] as f32 * element.properties[*i].value_type.color_scale(),
+                        None => DEFAULT_POINT_COLOR[k],
+                    }));
+                } else {
+                    colors.extend_from_slice(&DEFAULT_POINT_COLOR);
+                }
+            }
+        }
+        if is_vertex { break }
+    }
+    Ok(point_mesh(vertices, colors))
+}
+
+/// Picks the loader from the file extension
+pub fn load(path: &str) -> Result<Mesh, String> {
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    let mesh = match extension.as_str() {
+        "ply" => load_ply(path)?,
+        "xyz" | "txt" | "pts" | "csv" => load_xyz(path)?,
+        _ => return Err(format!("Don't know how to load a point cloud from {}", path)),
+    };
+    println!("Loaded point cloud {} with {} points.", path, mesh.vertex_count());
+    Ok(mesh)
+}
@@ -0,0 +1,358 @@
+use std::os::raw::c_void;
+use std::path::PathBuf;
+
+use crate::render_target::{ColorFormat, DepthFormat, RenderTarget, RenderTargetBuilder};
+use crate::shader::{Shader, ShaderBuilder};
+
+// Post-processing. The scene is drawn into an HDR render target in linear color, and a chain of
+// full screen passes turns it into the picture on screen. Every effect reads the result of the
+// one before, the last writes to the actual framebuffer.
+
+// How many times the bloom is blurred, each time both horizontally and vertically
+const BLOOM_BLUR_PASSES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    /// Light bleeding out around anything brighter than white
+    Bloom,
+    /// Scales the colors by the exposure and brings them into [0, 1], with the ACES filmic curve
+    ToneMapping,
+    /// Encodes the linear colors as sRGB for the screen
+    Gamma,
+    /// Looks every color up in a 3D color table, expects sRGB colors
+    ColorGrading,
+    /// Fast approximate antialiasing, works best on sRGB colors
+    Fxaa,
+    /// Darkens the corners
+    Vignette,
+}
+
+impl Effect {
+    pub const ALL: [Effect; 6] = [
+        Effect::Bloom,
+        Effect::ToneMapping,
+        Effect::Gamma,
+        Effect::ColorGrading,
+        Effect::Fxaa,
+        Effect::Vignette,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Bloom => "bloom",
+            Effect::ToneMapping => "tonemap",
+            Effect::Gamma => "gamma",
+            Effect::ColorGrading => "grading",
+            Effect::Fxaa => "fxaa",
+            Effect::Vignette => "vignette",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Effect> {
+        Effect::ALL.iter().copied().find(|effect| effect.name() == name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PostSettings {
+    // The effects in the order they are applied
+    pub effects: Vec<Effect>,
+    // What the colors are multiplied with before tone mapping
+    pub exposure: f32,
+    // How bright a color has to be to bloom, and how much of the bloom is added back
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+    // How much the corners are darkened, from 0 to 1
+    pub vignette: f32,
+    // Color table for the grading, an image of size*size by size texels: size slices of blue
+    // side by side, red along each slice and green down it. Grading is skipped without one.
+    pub lut: Option<PathBuf>,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        PostSettings {
+            effects: vec![Effect::Bloom, Effect::ToneMapping, Effect::Gamma, Effect::ColorGrading, Effect::Fxaa, Effect::Vignette],
+            exposure: 1.,
+            bloom_threshold: 1.,
+            bloom_intensity: 0.3,
+            vignette: 0.3,
+            lut: None,
+        }
+    }
+}
+
+/// Parses a comma separated list of effect names
+pub fn parse_effects(list: &str) -> Result<Vec<Effect>, String> {
+    list.split(',')
+        .filter(|name| !name.trim().is_empty())
+        .map(|name| {
+            Effect::from_name(name.trim()).ok_or_else(|| {
+                let names: Vec<&str> = Effect::ALL.iter().map(|e| e.name()).collect();
+                format!("Unknown effect {}, expected one of {}", name, names.join(", "))
+            })
+        })
+        .collect()
+}
+
+/// Loads a color table, see `PostSettings::lut`, into a 3D texture
+unsafe fn load_lut(path: &PathBuf) -> Result<(u32, u32), String> {
+    let image = image::open(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .into_rgb8();
+    let size = image.height();
+    if size < 2 || image.width() != size * size {
+        return Err(format!(
+            "{} is {}x{}, a color table has to be size*size by size",
+            path.display(),
+            image.width(),
+            image.height()
+        ));
+    }
+    // Rearranged from slices side by side to slices one after another
+    let mut texels = Vec::with_capacity((size * size * size * 3) as usize);
+    for blue in 0..size {
+        for green in 0..size {
+            for red in 0..size {
+                texels.extend_from_slice(&image.get_pixel(blue * size + red, green).0);
+            }
+        }
+    }
+    let mut texture = 0;
+    gl::GenTextures(1, &mut texture);
+    gl::BindTexture(gl::TEXTURE_3D, texture);
+    gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+    gl::TexImage3D(
+        gl::TEXTURE_3D,
+        0,
+        gl::RGB8 as i32,
+        size as i32,
+        size as i32,
+        size as i32,
+        0,
+        gl::RGB,
+        gl::UNSIGNED_BYTE,
+        texels.as_ptr() as *const c_void,
+    );
+    gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+    for &wrap in &[gl::TEXTURE_WRAP_S, gl::TEXTURE_WRAP_T, gl::TEXTURE_WRAP_R] {
+        gl::TexParameteri(gl::TEXTURE_3D, wrap, gl::CLAMP_TO_EDGE as i32);
+    }
+    gl::BindTexture(gl::TEXTURE_3D, 0);
+    Ok((texture, size))
+}
+
+unsafe fn post_shader(fragment: &str) -> Shader {
+    ShaderBuilder::new()
+        .attach_file("shaders/post.vert")
+        .attach_file(&format!("shaders/{}.frag", fragment))
+        .link()
+}
+
+unsafe fn hdr_target(width: u32, height: u32) -> Result<RenderTarget, String> {
+    RenderTargetBuilder::new(width, height).color(ColorFormat::Rgba16F).build()
+}
+
+struct Shaders {
+    bloom_extract: Shader,
+    blur: Shader,
+    bloom_combine: Shader,
+    tone_mapping: Shader,
+    gamma: Shader,
+    color_grading: Shader,
+    fxaa: Shader,
+    vignette: Shader,
+}
+
+pub struct PostProcessing {
+    pub settings: PostSettings,
+    // Whether each of `settings.effects` is applied
+    pub enabled: Vec<bool>,
+    // What the scene is drawn into
+    pub scene: RenderTarget,
+    // The effects take turns reading one and writing the other
+    ping_pong: [RenderTarget; 2],
+    // Half size, for blurring the bloom
+    bloom: [RenderTarget; 2],
+    shaders: Shaders,
+    // 0 without a color table
+    lut: u32,
+    lut_size: u32,
+    // Empty, the vertices are made up in the shader, but drawing needs one bound
+    vao: u32,
+}
+
+impl PostProcessing {
+    pub unsafe fn new(settings: PostSettings, width: u32, height: u32, samples: u32) -> Result<Self, String> {
+        let scene = RenderTargetBuilder::new(width, height)
+            .color(ColorFormat::Rgba16F)
+            .depth(DepthFormat::Depth24Stencil8)
+            .samples(samples)
+            .build()?;
+        let ping_pong = [hdr_target(width, height)?, hdr_target(width, height)?];
+        let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+        let bloom = [hdr_target(half_width, half_height)?, hdr_target(half_width, half_height)?];
+        let shaders = Shaders {
+            bloom_extract: post_shader("bloom_extract"),
+            blur: post_shader("blur"),
+            bloom_combine: post_shader("bloom_combine"),
+            tone_mapping: post_shader("tone_mapping"),
+            gamma: post_shader("gamma"),
+            color_grading: post_shader("color_grading"),
+            fxaa: post_shader("fxaa"),
+            vignette: post_shader("vignette"),
+        };
+        let (lut, lut_size) = match &settings.lut {
+            Some(path) => load_lut(path)?,
+            None => (0, 0),
+        };
+        let mut vao = 0;
+        gl::GenVertexArrays(1, &mut vao);
+        let enabled = vec![true; settings.effects.len()];
+        Ok(PostProcessing { settings, enabled, scene, ping_pong, bloom, shaders, lut, lut_size, vao })
+    }
+
+    /// Makes the targets match the size of what is drawn to
+    pub unsafe fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.scene.resize(width, height)?;
+        for target in &mut self.ping_pong {
+            target.resize(width, height)?;
+        }
+        for target in &mut self.bloom {
+            target.resize((width / 2).max(1), (height / 2).max(1))?;
+        }
+        Ok(())
+    }
+
+    /// Turns the `index`th effect on or off, giving it and whether it is now on
+    pub fn toggle(&mut self, index: usize) -> Option<(Effect, bool)> {
+        let enabled = self.enabled.get_mut(index)?;
+        *enabled = !*enabled;
+        Some((self.settings.effects[index], *enabled))
+    }
+
+    /// Makes the scene's render target the one that gets drawn to
+    pub unsafe fn begin(&self) {
+        self.scene.bind();
+    }
+
+    // Draws a full screen triangle with `shader`, reading `source` into `framebuffer`
+    unsafe fn pass(&self, shader: &Shader, source: u32, framebuffer: u32, size: (u32, u32)) {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl::Viewport(0, 0, size.0 as i32, size.1 as i32);
+        shader.activate();
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, source);
+        gl::Uniform1i(shader.get_uniform_location("Source"), 0);
+        gl::BindVertexArray(self.vao);
+        gl::DrawArrays(gl::TRIANGLES, 0, 3);
+    }
+
+    // The bright parts of `source` blurred, in the first bloom target
+    unsafe fn blur_bloom(&self, source: u32) {
+        let shaders = &self.shaders;
+        let size = (self.bloom[0].width, self.bloom[0].height);
+        shaders.bloom_extract.activate();
+        gl::Uniform1f(shaders.bloom_extract.get_uniform_location("Threshold"), self.settings.bloom_threshold);
+        self.pass(&shaders.bloom_extract, source, self.bloom[0].fbo, size);
+        for _ in 0..BLOOM_BLUR_PASSES {
+            for &(from, to, direction) in &[(0, 1, [1., 0.]), (1, 0, [0., 1.])] {
+                shaders.blur.activate();
+                let step = [direction[0] / size.0 as f32, direction[1] / size.1 as f32];
+                gl::Uniform2fv(shaders.blur.get_uniform_location("Step"), 1, step.as_ptr());
+                self.pass(&shaders.blur, self.bloom[from].color_textures[0], self.bloom[to].fbo, size);
+            }
+        }
+    }
+
+    /// Runs the enabled effects on the drawn scene, writing the result to `framebuffer`
+    pub unsafe fn finish(&self, framebuffer: u32, width: u32, height: u32) {
+        self.scene.resolve();
+        gl::Disable(gl::DEPTH_TEST);
+        gl::Disable(gl::BLEND);
+        gl::Disable(gl::CULL_FACE);
+
+        let shaders = &self.shaders;
+        let size = (self.scene.width, self.scene.height);
+        let effects: Vec<Effect> = self
+            .settings
+            .effects
+            .iter()
+            .zip(&self.enabled)
+            .filter(|&(&effect, &enabled)| enabled && (effect != Effect::ColorGrading || self.lut != 0))
+            .map(|(&effect, _)| effect)
+            .collect();
+
+        let mut source = self.scene.color_textures[0];
+        for (i, effect) in effects.iter().enumerate() {
+            let last = i + 1 == effects.len();
+            let target = &self.ping_pong[i % 2];
+            let (output, output_size) = if last { (framebuffer, (width, height)) } else { (target.fbo, size) };
+            let shader = match effect {
+                Effect::Bloom => {
+                    self.blur_bloom(source);
+                    shaders.bloom_combine.activate();
+                    gl::ActiveTexture(gl::TEXTURE1);
+                    gl::BindTexture(gl::TEXTURE_2D, self.bloom[0].color_textures[0]);
+                    gl::Uniform1i(shaders.bloom_combine.get_uniform_location("Bloom"), 1);
+                    let location = shaders.bloom_combine.get_uniform_location("Intensity");
+                    gl::Uniform1f(location, self.settings.bloom_intensity);
+                    &shaders.bloom_combine
+                }
+                Effect::ToneMapping => {
+                    shaders.tone_mapping.activate();
+                    gl::Uniform1f(shaders.tone_mapping.get_uniform_location("Exposure"), self.settings.exposure);
+                    &shaders.tone_mapping
+                }
+                Effect::Gamma => &shaders.gamma,
+                Effect::ColorGrading => {
+                    shaders.color_grading.activate();
+                    gl::ActiveTexture(gl::TEXTURE1);
+                    gl::BindTexture(gl::TEXTURE_3D, self.lut);
+                    gl::Uniform1i(shaders.color_grading.get_uniform_location("Lut"), 1);
+                    gl::Uniform1f(shaders.color_grading.get_uniform_location("LutSize"), self.lut_size as f32);
+                    &shaders.color_grading
+                }
+                Effect::Fxaa => {
+                    shaders.fxaa.activate();
+                    let texel = [1. / size.0 as f32, 1. / size.1 as f32];
+                    gl::Uniform2fv(shaders.fxaa.get_uniform_location("TexelSize"), 1, texel.as_ptr());
+                    &shaders.fxaa
+                }
+                Effect::Vignette => {
+                    shaders.vignette.activate();
+                    gl::Uniform1f(shaders.vignette.get_uniform_location("Strength"), self.settings.vignette);
+                    &shaders.vignette
+                }
+            };
+            self.pass(shader, source, output, output_size);
+            source = target.color_textures[0];
+        }
+        if effects.is_empty() {
+            // Nothing to do, but the scene still has to get to the framebuffer
+            let (w, h) = (size.0 as i32, size.1 as i32);
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.scene.resolved_fbo());
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, framebuffer);
+            gl::BlitFramebuffer(0, 0, w, h, 0, 0, width as i32, height as i32, gl::COLOR_BUFFER_BIT, gl::LINEAR);
+        }
+
+        gl::BindVertexArray(0);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl::Viewport(0, 0, width as i32, height as i32);
+        gl::Enable(gl::DEPTH_TEST);
+        gl::Enable(gl::BLEND);
+        gl::Enable(gl::CULL_FACE);
+    }
+}
+
+impl Drop for PostProcessing {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.lut);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
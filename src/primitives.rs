@@ -0,0 +1,406 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+
+use crate::mesh::{Mesh, Primitive};
+
+// Procedural debug geometry. Everything is built with counter clockwise front faces, outward
+// normals, UVs in [0, 1] and tangents, so the results can go straight into a GpuMesh.
+
+impl Mesh {
+    pub fn push_vertex(&mut self, position: glm::Vec3, normal: glm::Vec3, uv: glm::Vec2) -> u32 {
+        self.vertices.extend_from_slice(position.as_slice());
+        self.normals.extend_from_slice(normal.as_slice());
+        self.uvs.extend_from_slice(uv.as_slice());
+        (self.vertex_count() - 1) as u32
+    }
+
+    pub fn push_triangle(&mut self, a: u32, b: u32, c: u32) {
+        self.indices.extend_from_slice(&[a, b, c]);
+    }
+
+    // Adds the triangles of a (rows + 1) x (columns + 1) vertex grid starting at `first`,
+    // where consecutive rows go "up" in the surface's v direction
+    pub fn push_grid(&mut self, first: u32, rows: u32, columns: u32) {
+        for i in 0..rows {
+            for j in 0..columns {
+                let a = first + i * (columns + 1) + j;
+                let b = a + columns + 1;
+                self.push_triangle(a, a + 1, b + 1);
+                self.push_triangle(a, b + 1, b);
+            }
+        }
+    }
+
+    // Flat disc facing +y or -y, used to cap cylinders and cones
+    fn push_cap(&mut self, radius: f32, y: f32, segments: u32, up: bool) {
+        let normal = glm::vec3(0.0, if up { 1.0 } else { -1.0 }, 0.0);
+        let center = self.push_vertex(glm::vec3(0.0, y, 0.0), normal, glm::vec2(0.5, 0.5));
+        for j in 0..=segments {
+            let theta = 2.0 * PI * j as f32 / segments as f32;
+            let (sin, cos) = theta.sin_cos();
+            let v = if up { 0.5 + 0.5 * sin } else { 0.5 - 0.5 * sin };
+            self.push_vertex(glm::vec3(radius * cos, y, -radius * sin), normal, glm::vec2(0.5 + 0.5 * cos, v));
+        }
+        for j in 0..segments {
+            let (a, b) = (center + 1 + j, center + 2 + j);
+            if up { self.push_triangle(center, a, b) } else { self.push_triangle(center, b, a) }
+        }
+    }
+
+    // Fills in colors, index count and tangents once all vertices and triangles are pushed
+    pub fn finish(mut self, color: [f32; 4]) -> Mesh {
+        self.colors = color.iter().cloned().cycle().take(self.vertex_count() * 4).collect();
+        self.index_count = self.indices.len() as i32;
+        self.generate_tangents();
+        self
+    }
+}
+
+impl Mesh {
+    /// Axis aligned box centered at the origin, with hard edges and a full UV square per face
+    pub fn cube(size: glm::Vec3, color: [f32; 4]) -> Mesh {
+        let mut mesh = Mesh::empty();
+        let half = size * 0.5;
+        // (normal, u axis, v axis) with cross(u, v) == normal
+        let faces = [
+            (glm::vec3( 1.0, 0.0, 0.0), glm::vec3(0.0, 0.0, -1.0), glm::vec3(0.0, 1.0,  0.0)),
+            (glm::vec3(-1.0, 0.0, 0.0), glm::vec3(0.0, 0.0,  1.0), glm::vec3(0.0, 1.0,  0.0)),
+            (glm::vec3(0.0,  1.0, 0.0), glm::vec3(1.0, 0.0,  0.0), glm::vec3(0.0, 0.0, -1.0)),
+            (glm::vec3(0.0, -1.0, 0.0), glm::vec3(1.0, 0.0,  0.0), glm::vec3(0.0, 0.0,  1.0)),
+            (glm::vec3(0.0, 0.0,  1.0), glm::vec3(1.0, 0.0,  0.0), glm::vec3(0.0, 1.0,  0.0)),
+            (glm::vec3(0.0, 0.0, -1.0), glm::vec3(-1.0, 0.0, 0.0), glm::vec3(0.0, 1.0,  0.0)),
+        ];
+        for (normal, u, v) in faces.iter() {
+            let first = mesh.vertex_count() as u32;
+            for &(su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter() {
+                let position = (normal + u * su + v * sv).component_mul(&half);
+                mesh.push_vertex(position, *normal, glm::vec2(0.5 + 0.5 * su, 0.5 + 0.5 * sv));
+            }
+            mesh.push_triangle(first, first + 1, first + 2);
+            mesh.push_triangle(first, first + 2, first + 3);
+        }
+        mesh.finish(color)
+    }
+
+    /// Sphere made of `sectors` slices around the y axis and `stacks` rings from pole to pole
+    pub fn uv_sphere(radius: f32, sectors: u32, stacks: u32, color: [f32; 4]) -> Mesh {
+        let (sectors, stacks) = (sectors.max(3), stacks.max(2));
+        let mut mesh = Mesh::empty();
+        for i in 0..=stacks {
+            // From the south pole up, so rows follow the v direction
+            let phi = PI * (1.0 - i as f32 / stacks as f32);
+            for j in 0..=sectors {
+                let theta = 2.0 * PI * j as f32 / sectors as f32;
+                let normal = glm::vec3(phi.sin() * theta.cos(), phi.cos(), -phi.sin() * theta.sin());
+                let uv = glm::vec2(j as f32 / sectors as f32, i as f32 / stacks as f32);
+                mesh.push_vertex(normal * radius, normal, uv);
+            }
+        }
+        // The quads touching the poles collapse into triangles, so only their non-degenerate half is kept
+        for i in 0..stacks {
+            for j in 0..sectors {
+                let a = i * (sectors + 1) + j;
+                let b = a + sectors + 1;
+                if i != 0 { mesh.push_triangle(a, a + 1, b + 1) }
+                if i != stacks - 1 { mesh.push_triangle(a, b + 1, b) }
+            }
+        }
+        mesh.finish(color)
+    }
+
+    /// Sphere made by repeatedly subdividing an icosahedron, giving evenly sized triangles
+    pub fn icosphere(radius: f32, subdivisions: u32, color: [f32; 4]) -> Mesh {
+        let t = (1.0 + 5f32.sqrt()) / 2.0;
+        let mut points: Vec<glm::Vec3> = [
+            (-1.0, t, 0.0), (1.0, t, 0.0), (-1.0, -t, 0.0), (1.0, -t, 0.0),
+            (0.0, -1.0, t), (0.0, 1.0, t), (0.0, -1.0, -t), (0.0, 1.0, -t),
+            (t, 0.0, -1.0), (t, 0.0, 1.0), (-t, 0.0, -1.0), (-t, 0.0, 1.0),
+        ].iter().map(|&(x, y, z)| glm::normalize(&glm::vec3(x, y, z))).collect();
+        let mut triangles: Vec<[u32; 3]> = vec![
+            [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+            [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+            [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+            [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+        ];
+
+        for _ in 0..subdivisions {
+            let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+            let mut midpoint = |a: u32, b: u32, points: &mut Vec<glm::Vec3>| {
+                *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    points.push(glm::normalize(&(points[a as usize] + points[b as usize])));
+                    (points.len() - 1) as u32
+                })
+            };
+            let mut subdivided = Vec::with_capacity(triangles.len() * 4);
+            for &[a, b, c] in &triangles {
+                let ab = midpoint(a, b, &mut points);
+                let bc = midpoint(b, c, &mut points);
+                let ca = midpoint(c, a, &mut points);
+                subdivided.extend_from_slice(&[[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]);
+            }
+            triangles = subdivided;
+        }
+
+        let mut mesh = Mesh::empty();
+        let spherical_uv = |p: &glm::Vec3| glm::vec2(0.5 + (-p.z).atan2(p.x) / (2.0 * PI), 0.5 + p.y.asin() / PI);
+        for p in &points {
+            mesh.push_vertex(p * radius, *p, spherical_uv(p));
+        }
+        // Triangles straddling the u = 0 / u = 1 seam are cut along it, so the texture doesn't
+        // wrap backwards across them. Vertices on the seam, the poles included, get a copy for
+        // either side.
+        let on_seam = |p: &glm::Vec3| p.z == 0.0 && p.x <= 0.0;
+        let mut seam_vertices: HashMap<(u32, u32, bool), u32> = HashMap::new();
+        // Where the edge from a to b crosses the seam, or a itself if it's on it, at u = 1 if `high`
+        let mut seam_vertex = |mesh: &mut Mesh, a: u32, b: u32, high: bool| {
+            *seam_vertices.entry((a.min(b), a.max(b), high)).or_insert_with(|| {
+                let (pa, pb) = (points[a as usize], points[b as usize]);
+                let mut p = if on_seam(&pa) { pa } else { pa + (pb - pa) * (pa.z / (pa.z - pb.z)) };
+                p.z = 0.0;
+                let p = glm::normalize(&p);
+                let uv = glm::vec2(if high { 1.0 } else { 0.0 }, 0.5 + p.y.asin() / PI);
+                mesh.push_vertex(p * radius, p, uv)
+            })
+        };
+        for &corners in &triangles {
+            // Which side of the seam each corner is on, true for u close to 1, or None if on it
+            let sides: Vec<Option<bool>> = corners.iter()
+                .map(|&i| if on_seam(&points[i as usize]) { None } else { Some(mesh.uv(i as usize).x > 0.5) })
+                .collect();
+            let off_seam: Vec<f32> = (0..3).filter(|&k| sides[k].is_some()).map(|k| mesh.uv(corners[k] as usize).x).collect();
+            let spread = off_seam.iter().cloned().fold(0.0, f32::max) - off_seam.iter().cloned().fold(1.0, f32::min);
+            if spread < 0.5 {
+                // On one side, the corners on the seam just join it
+                let high = off_seam.iter().sum::<f32>() / off_seam.len().max(1) as f32 > 0.5;
+                let mut corners = corners;
+                for k in (0..3).filter(|&k| sides[k].is_none()) {
+                    corners[k] = seam_vertex(&mut mesh, corners[k], corners[k], high);
+                }
+                mesh.push_triangle(corners[0], corners[1], corners[2]);
+                continue;
+            }
+            // The corner the seam separates from the next two, or runs through
+            let first = (0..3)
+                .find(|&k| {
+                    let (next, last) = (sides[(k + 1) % 3], sides[(k + 2) % 3]);
+                    match sides[k] {
+                        Some(side) => next == Some(!side) && last == Some(!side),
+                        None => next.is_some() && last.is_some() && next != last,
+                    }
+                })
+                .expect("a triangle across the seam has corners on both sides");
+            let (a, b, c) = (corners[first], corners[(first + 1) % 3], corners[(first + 2) % 3]);
+            match sides[first] {
+                None => {
+                    let (b_side, c_side) = (sides[(first + 1) % 3] == Some(true), sides[(first + 2) % 3] == Some(true));
+                    let (a_b, bc_b) = (seam_vertex(&mut mesh, a, a, b_side), seam_vertex(&mut mesh, b, c, b_side));
+                    mesh.push_triangle(a_b, b, bc_b);
+                    let (a_c, bc_c) = (seam_vertex(&mut mesh, a, a, c_side), seam_vertex(&mut mesh, b, c, c_side));
+                    mesh.push_triangle(a_c, bc_c, c);
+                }
+                Some(side) => {
+                    let (ab, ac) = (seam_vertex(&mut mesh, a, b, side), seam_vertex(&mut mesh, a, c, side));
+                    mesh.push_triangle(a, ab, ac);
+                    let (ab, ac) = (seam_vertex(&mut mesh, a, b, !side), seam_vertex(&mut mesh, a, c, !side));
+                    mesh.push_triangle(ab, b, c);
+                    mesh.push_triangle(ab, c, ac);
+                }
+            }
+        }
+        mesh.finish(color)
+    }
+
+    /// Capped cylinder along the y axis with its base at the origin
+    pub fn cylinder(radius: f32, height: f32, segments: u32, color: [f32; 4]) -> Mesh {
+        let segments = segments.max(3);
+        let mut mesh = Mesh::empty();
+        for &y in [0.0, height].iter() {
+            for j in 0..=segments {
+                let u = j as f32 / segments as f32;
+                let (sin, cos) = (2.0 * PI * u).sin_cos();
+                let normal = glm::vec3(cos, 0.0, -sin);
+                mesh.push_vertex(glm::vec3(radius * cos, y, -radius * sin), normal, glm::vec2(u, y / height));
+            }
+        }
+        mesh.push_grid(0, 1, segments);
+        mesh.push_cap(radius, 0.0, segments, false);
+        mesh.push_cap(radius, height, segments, true);
+        mesh.finish(color)
+    }
+
+    /// Capped cone along the y axis with its base at the origin and the tip at `height`
+    pub fn cone(radius: f32, height: f32, segments: u32, color: [f32; 4]) -> Mesh {
+        let segments = segments.max(3);
+        let mut mesh = Mesh::empty();
+        let slope_normal = |theta: f32| {
+            let (sin, cos) = theta.sin_cos();
+            glm::normalize(&glm::vec3(height * cos, radius, -height * sin))
+        };
+        for j in 0..=segments {
+            let u = j as f32 / segments as f32;
+            let (sin, cos) = (2.0 * PI * u).sin_cos();
+            mesh.push_vertex(glm::vec3(radius * cos, 0.0, -radius * sin), slope_normal(2.0 * PI * u), glm::vec2(u, 0.0));
+        }
+        // One tip vertex per side, each with the normal of the middle of its side
+        for j in 0..segments {
+            let u = (j as f32 + 0.5) / segments as f32;
+            mesh.push_vertex(glm::vec3(0.0, height, 0.0), slope_normal(2.0 * PI * u), glm::vec2(u, 1.0));
+        }
+        for j in 0..segments {
+            mesh.push_triangle(j, j + 1, segments + 1 + j);
+        }
+        mesh.push_cap(radius, 0.0, segments, false);
+        mesh.finish(color)
+    }
+
+    /// Torus lying in the xz plane, centered at the origin
+    pub fn torus(major_radius: f32, minor_radius: f32, major_segments: u32, minor_segments: u32, color: [f32; 4]) -> Mesh {
+        let (major_segments, minor_segments) = (major_segments.max(3), minor_segments.max(3));
+        let mut mesh = Mesh::empty();
+        for i in 0..=minor_segments {
+            let v = i as f32 / minor_segments as f32;
+            let (sin_v, cos_v) = (2.0 * PI * v).sin_cos();
+            for j in 0..=major_segments {
+                let u = j as f32 / major_segments as f32;
+                let (sin_u, cos_u) = (2.0 * PI * u).sin_cos();
+                // Starting at the inner equator so the tube's outward normal turns the right way
+                let normal = glm::vec3(-cos_v * cos_u, -sin_v, cos_v * sin_u);
+                let center = glm::vec3(major_radius * cos_u, 0.0, -major_radius * sin_u);
+                mesh.push_vertex(center + normal * minor_radius, normal, glm::vec2(u, v));
+            }
+        }
+        mesh.push_grid(0, minor_segments, major_segments);
+        mesh.finish(color)
+    }
+
+    /// Flat grid in the xz plane facing +y, centered at the origin
+    pub fn plane_grid(width: f32, depth: f32, x_divisions: u32, z_divisions: u32, color: [f32; 4]) -> Mesh {
+        let (x_divisions, z_divisions) = (x_divisions.max(1), z_divisions.max(1));
+        let mut mesh = Mesh::empty();
+        for i in 0..=z_divisions {
+            let v = i as f32 / z_divisions as f32;
+            for j in 0..=x_divisions {
+                let u = j as f32 / x_divisions as f32;
+                let position = glm::vec3((u - 0.5) * width, 0.0, (0.5 - v) * depth);
+                mesh.push_vertex(position, glm::vec3(0.0, 1.0, 0.0), glm::vec2(u, v));
+            }
+        }
+        mesh.push_grid(0, z_divisions, x_divisions);
+        mesh.finish(color)
+    }
+
+    /// Arrow along +y from the origin, `length` long including the head
+    #[allow(dead_code)]
+    pub fn arrow(length: f32, color: [f32; 4]) -> Mesh {
+        let head_length = 0.2 * length;
+        let mut arrow = Mesh::cylinder(0.02 * length, length - head_length, 12, color);
+        let mut head = Mesh::cone(0.06 * length, head_length, 12, color);
+        head.transform(&glm::translation(&glm::vec3(0.0, length - head_length, 0.0)));
+        arrow.append(&head);
+        arrow
+    }
+}
+
+// Line and point meshes for debug drawing. They go through the same pipeline as everything
+// else, but have no normals or UVs since there is no surface to light or texture.
+impl Mesh {
+    fn unlit(positions: &[glm::Vec3], indices: Vec<u32>, primitive: Primitive, color: [f32; 4]) -> Mesh {
+        let mut mesh = Mesh::empty();
+        mesh.vertices = positions.iter().flat_map(|p| p.iter().cloned().collect::<Vec<f32>>()).collect();
+        mesh.colors = color.iter().cloned().cycle().take(positions.len() * 4).collect();
+        mesh.primitive = primitive;
+        mesh.set_indices(indices);
+        mesh
+    }
+
+    /// Separate line segments between each pair of points
+    #[allow(dead_code)]
+    pub fn lines(segments: &[(glm::Vec3, glm::Vec3)], color: [f32; 4]) -> Mesh {
+        let positions: Vec<glm::Vec3> = segments.iter().flat_map(|&(a, b)| vec![a, b]).collect();
+        Mesh::unlit(&positions, (0..positions.len() as u32).collect(), Primitive::Lines, color)
+    }
+
+    /// One connected line through all the points
+    #[allow(dead_code)]
+    pub fn line_strip(points: &[glm::Vec3], color: [f32; 4]) -> Mesh {
+        Mesh::unlit(points, (0..points.len() as u32).collect(), Primitive::LineStrip, color)
+    }
+
+    pub fn points(points: &[glm::Vec3], color: [f32; 4]) -> Mesh {
+        Mesh::unlit(points, (0..points.len() as u32).collect(), Primitive::Points, color)
+    }
+
+    /// Every edge of the triangles, once
+    pub fn wireframe(&self, color: [f32; 4]) -> Mesh {
+        let mut seen: HashSet<(u32, u32)> = HashSet::new();
+        let mut indices = Vec::new();
+        for t in 0..self.triangle_count() {
+            let [a, b, c] = self.triangle(t);
+            for &(from, to) in &[(a, b), (b, c), (c, a)] {
+                let edge = (from.min(to) as u32, from.max(to) as u32);
+                if seen.insert(edge) {
+                    indices.extend_from_slice(&[edge.0, edge.1]);
+                }
+            }
+        }
+        let positions: Vec<glm::Vec3> = (0..self.vertex_count()).map(|i| self.position(i)).collect();
+        Mesh::unlit(&positions, indices, Primitive::Lines, color)
+    }
+
+    /// A line along the normal of every vertex
+    #[allow(dead_code)]
+    pub fn normal_lines(&self, length: f32, color: [f32; 4]) -> Mesh {
+        let segments: Vec<(glm::Vec3, glm::Vec3)> = (0..self.vertex_count())
+            .map(|i| (self.position(i), self.position(i) + length * self.normal(i)))
+            .collect();
+        Mesh::lines(&segments, color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks that every triangle faces away from `centre_of` its centroid, and that the normals
+    // have unit length and the UVs are in [0, 1]
+    fn assert_well_formed(name: &str, mesh: &Mesh, centre_of: impl Fn(glm::Vec3) -> glm::Vec3) {
+        assert!(mesh.validate().is_clean(), "{}: {}", name, mesh.validate());
+        for t in 0..mesh.triangle_count() {
+            let [a, b, c] = mesh.triangle(t);
+            let centroid = (mesh.position(a) + mesh.position(b) + mesh.position(c)) / 3.0;
+            let facing = glm::dot(&mesh.face_cross(t), &(centroid - centre_of(centroid)));
+            assert!(facing > 0.0, "{}: triangle {} faces inwards", name, t);
+        }
+        for i in 0..mesh.vertex_count() {
+            let length = glm::length(&mesh.normal(i));
+            assert!((length - 1.0).abs() < 1e-5, "{}: normal {} has length {}", name, i, length);
+        }
+        for uv in mesh.uvs.chunks_exact(2) {
+            assert!(uv.iter().all(|x| (0.0..=1.0).contains(x)), "{}: UV {:?} is outside [0, 1]", name, uv);
+        }
+    }
+
+    #[test]
+    fn closed_primitives_face_outwards() {
+        let origin = |_| glm::zero();
+        assert_well_formed("cube", &Mesh::cube(glm::vec3(1.0, 2.0, 3.0), [1.0; 4]), origin);
+        assert_well_formed("uv sphere", &Mesh::uv_sphere(1.5, 16, 8, [1.0; 4]), origin);
+        for subdivisions in 0..4 {
+            let icosphere = Mesh::icosphere(1.5, subdivisions, [1.0; 4]);
+            assert_well_formed("icosphere", &icosphere, origin);
+            // Cut along the seam rather than wrapping backwards across it. Only the triangles
+            // over the poles reach half way round.
+            for t in 0..icosphere.triangle_count() {
+                let us: Vec<f32> = icosphere.triangle(t).iter().map(|&i| icosphere.uv(i).x).collect();
+                let spread = us.iter().cloned().fold(0.0, f32::max) - us.iter().cloned().fold(1.0, f32::min);
+                assert!(spread <= 0.5, "icosphere triangle {} spans {:?}", t, us);
+            }
+        }
+        assert_well_formed("cylinder", &Mesh::cylinder(0.5, 2.0, 16, [1.0; 4]), |_| glm::vec3(0.0, 1.0, 0.0));
+        // Away from the nearest point on the circle through the middle of the tube
+        let ring = |p: glm::Vec3| 2.0 * glm::normalize(&glm::vec3(p.x, 0.0, p.z));
+        assert_well_formed("torus", &Mesh::torus(2.0, 0.5, 24, 12, [1.0; 4]), ring);
+    }
+}
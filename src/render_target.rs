@@ -0,0 +1,364 @@
+use std::os::raw::c_void;
+use std::ptr;
+
+use image::RgbaImage;
+
+// Offscreen framebuffers to render into, for shadow maps, post-processing and captures. Without
+// multisampling the attachments are textures that can be sampled right away. With it, drawing
+// goes to multisampled renderbuffers, which `resolve` averages into the textures.
+
+/// Format of a color attachment
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorFormat {
+    Rgba8,
+    // Half floats, for HDR colors
+    Rgba16F,
+    // A single float, e.g. for depth or ids
+    R32F,
+}
+
+impl ColorFormat {
+    fn internal_format(&self) -> gl::types::GLenum {
+        match self {
+            ColorFormat::Rgba8 => gl::RGBA8,
+            ColorFormat::Rgba16F => gl::RGBA16F,
+            ColorFormat::R32F => gl::R32F,
+        }
+    }
+
+    // The format and type textures are allocated with. No data is uploaded, but they have to be valid.
+    fn pixel_format(&self) -> (gl::types::GLenum, gl::types::GLenum) {
+        match self {
+            ColorFormat::Rgba8 => (gl::RGBA, gl::UNSIGNED_BYTE),
+            ColorFormat::Rgba16F => (gl::RGBA, gl::FLOAT),
+            ColorFormat::R32F => (gl::RED, gl::FLOAT),
+        }
+    }
+}
+
+/// Format of the depth attachment, with or without stencil
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DepthFormat {
+    Depth24,
+    Depth32F,
+    Depth24Stencil8,
+}
+
+impl DepthFormat {
+    fn internal_format(&self) -> gl::types::GLenum {
+        match self {
+            DepthFormat::Depth24 => gl::DEPTH_COMPONENT24,
+            DepthFormat::Depth32F => gl::DEPTH_COMPONENT32F,
+            DepthFormat::Depth24Stencil8 => gl::DEPTH24_STENCIL8,
+        }
+    }
+
+    fn pixel_format(&self) -> (gl::types::GLenum, gl::types::GLenum) {
+        match self {
+            DepthFormat::Depth24 => (gl::DEPTH_COMPONENT, gl::UNSIGNED_INT),
+            DepthFormat::Depth32F => (gl::DEPTH_COMPONENT, gl::FLOAT),
+            DepthFormat::Depth24Stencil8 => (gl::DEPTH_STENCIL, gl::UNSIGNED_INT_24_8),
+        }
+    }
+
+    fn attachment(&self) -> gl::types::GLenum {
+        match self {
+            DepthFormat::Depth24Stencil8 => gl::DEPTH_STENCIL_ATTACHMENT,
+            _ => gl::DEPTH_ATTACHMENT,
+        }
+    }
+
+    pub fn has_stencil(&self) -> bool {
+        *self == DepthFormat::Depth24Stencil8
+    }
+}
+
+/// Turns RGBA pixels read back from OpenGL, which starts at the bottom row, into an image
+pub fn image_from_gl_pixels(width: u32, height: u32, pixels: &[u8]) -> RgbaImage {
+    let row = width as usize * 4;
+    let flipped: Vec<u8> = pixels.chunks_exact(row).rev().flatten().copied().collect();
+    RgbaImage::from_raw(width, height, flipped).expect("pixels to fill the image")
+}
+
+/// Makes the window the framebuffer that gets drawn to again
+#[allow(dead_code)]
+pub unsafe fn bind_default_framebuffer(width: u32, height: u32) {
+    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+    gl::Viewport(0, 0, width as i32, height as i32);
+}
+
+fn status_name(status: gl::types::GLenum) -> &'static str {
+    match status {
+        gl::FRAMEBUFFER_UNDEFINED => "undefined",
+        gl::FRAMEBUFFER_INCOMPLETE_ATTACHMENT => "incomplete attachment",
+        gl::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT => "missing attachment",
+        gl::FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER => "incomplete draw buffer",
+        gl::FRAMEBUFFER_INCOMPLETE_READ_BUFFER => "incomplete read buffer",
+        gl::FRAMEBUFFER_UNSUPPORTED => "unsupported combination of formats",
+        gl::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE => "mismatched multisampling",
+        gl::FRAMEBUFFER_INCOMPLETE_LAYER_TARGETS => "mismatched layers",
+        _ => "unknown status",
+    }
+}
+
+unsafe fn check_complete(fbo: u32) -> Result<(), String> {
+    gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+    let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+    if status == gl::FRAMEBUFFER_COMPLETE {
+        Ok(())
+    } else {
+        Err(format!("Framebuffer is incomplete: {} (0x{:x})", status_name(status), status))
+    }
+}
+
+fn color_attachments(count: usize) -> Vec<gl::types::GLenum> {
+    (0..count as u32).map(|i| gl::COLOR_ATTACHMENT0 + i).collect()
+}
+
+/// What a render target is made of, see `RenderTarget`
+#[derive(Clone, Debug)]
+pub struct RenderTargetBuilder {
+    pub width: u32,
+    pub height: u32,
+    pub colors: Vec<ColorFormat>,
+    pub depth: Option<DepthFormat>,
+    // 1 for no multisampling
+    pub samples: u32,
+}
+
+impl RenderTargetBuilder {
+    pub fn new(width: u32, height: u32) -> Self {
+        RenderTargetBuilder { width, height, colors: vec![], depth: None, samples: 1 }
+    }
+
+    /// Adds a color attachment, they are numbered in the order they are added
+    pub fn color(mut self, format: ColorFormat) -> Self {
+        self.colors.push(format);
+        self
+    }
+
+    pub fn depth(mut self, format: DepthFormat) -> Self {
+        self.depth = Some(format);
+        self
+    }
+
+    /// Multisamples with this many samples per pixel
+    pub fn samples(mut self, samples: u32) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    pub unsafe fn build(self) -> Result<RenderTarget, String> {
+        RenderTarget::new(self)
+    }
+}
+
+/// A framebuffer with color and depth attachments of its own. Draw to it after `bind`, and
+/// sample from `color_textures` and `depth_texture`, after a `resolve` if it is multisampled.
+pub struct RenderTarget {
+    pub fbo: u32,
+    pub width: u32,
+    pub height: u32,
+    #[allow(dead_code)]
+    pub samples: u32,
+    // One per color attachment
+    pub color_textures: Vec<u32>,
+    // 0 if there is no depth attachment
+    pub depth_texture: u32,
+    // The framebuffer the textures are attached to. The same as `fbo` without multisampling.
+    resolve_fbo: u32,
+    multisample_renderbuffers: Vec<u32>,
+    builder: RenderTargetBuilder,
+}
+
+impl RenderTarget {
+    unsafe fn new(mut builder: RenderTargetBuilder) -> Result<Self, String> {
+        if builder.width == 0 || builder.height == 0 {
+            return Err(format!("Render target can't be {}x{}", builder.width, builder.height));
+        }
+        if builder.colors.is_empty() && builder.depth.is_none() {
+            return Err("Render target needs at least one attachment".to_string());
+        }
+        let mut max_samples = 0;
+        gl::GetIntegerv(gl::MAX_SAMPLES, &mut max_samples);
+        if builder.samples > max_samples as u32 {
+            println!("Warning: {} samples asked for, but only {} are supported", builder.samples, max_samples);
+            builder.samples = max_samples.max(1) as u32;
+        }
+        let (width, height) = (builder.width as i32, builder.height as i32);
+
+        // The textures are what gets sampled, whether or not they are drawn to directly
+        let mut resolve_fbo = 0;
+        gl::GenFramebuffers(1, &mut resolve_fbo);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, resolve_fbo);
+        let mut color_textures = vec![0; builder.colors.len()];
+        gl::GenTextures(color_textures.len() as i32, color_textures.as_mut_ptr());
+        for (i, (&texture, format)) in color_textures.iter().zip(&builder.colors).enumerate() {
+            let (pixel_format, pixel_type) = format.pixel_format();
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, format.internal_format() as i32, width, height, 0, pixel_format, pixel_type, ptr::null());
+            // No mipmaps, so the default minification filter would leave the texture incomplete
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0 + i as u32, gl::TEXTURE_2D, texture, 0);
+        }
+        let mut depth_texture = 0;
+        if let Some(format) = builder.depth {
+            let (pixel_format, pixel_type) = format.pixel_format();
+            gl::GenTextures(1, &mut depth_texture);
+            gl::BindTexture(gl::TEXTURE_2D, depth_texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, format.internal_format() as i32, width, height, 0, pixel_format, pixel_type, ptr::null());
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER, format.attachment(), gl::TEXTURE_2D, depth_texture, 0);
+        }
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+        let attachments = color_attachments(builder.colors.len());
+        gl::DrawBuffers(attachments.len() as i32, attachments.as_ptr());
+        if attachments.is_empty() {
+            gl::ReadBuffer(gl::NONE);
+        }
+
+        // Multisampled renderbuffers in a framebuffer of their own, resolved into the textures
+        let mut fbo = resolve_fbo;
+        let mut multisample_renderbuffers = vec![];
+        if builder.samples > 1 {
+            gl::GenFramebuffers(1, &mut fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+            let formats = builder
+                .colors
+                .iter()
+                .enumerate()
+                .map(|(i, format)| (format.internal_format(), gl::COLOR_ATTACHMENT0 + i as u32))
+                .chain(builder.depth.map(|format| (format.internal_format(), format.attachment())));
+            for (internal_format, attachment) in formats {
+                let mut renderbuffer = 0;
+                gl::GenRenderbuffers(1, &mut renderbuffer);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
+                gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, builder.samples as i32, internal_format, width, height);
+                gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, attachment, gl::RENDERBUFFER, renderbuffer);
+                multisample_renderbuffers.push(renderbuffer);
+            }
+            gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+            gl::DrawBuffers(attachments.len() as i32, attachments.as_ptr());
+            if attachments.is_empty() {
+                gl::ReadBuffer(gl::NONE);
+            }
+        }
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+
+        let target = RenderTarget {
+            fbo,
+            width: builder.width,
+            height: builder.height,
+            samples: builder.samples,
+            color_textures,
+            depth_texture,
+            resolve_fbo,
+            multisample_renderbuffers,
+            builder,
+        };
+        // Dropping the target on failure deletes whatever was made
+        check_complete(target.resolve_fbo)?;
+        if target.fbo != target.resolve_fbo {
+            check_complete(target.fbo)?;
+        }
+        Ok(target)
+    }
+
+    /// Makes this the framebuffer that gets drawn to, covering all of it
+    pub unsafe fn bind(&self) {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo);
+        gl::Viewport(0, 0, self.width as i32, self.height as i32);
+    }
+
+    /// Recreates the attachments at a new size, their contents are lost
+    #[allow(dead_code)]
+    pub unsafe fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
+        }
+        let mut builder = self.builder.clone();
+        builder.width = width;
+        builder.height = height;
+        *self = RenderTarget::new(builder)?;
+        Ok(())
+    }
+
+    pub fn is_multisampled(&self) -> bool {
+        self.fbo != self.resolve_fbo
+    }
+
+    /// Averages the samples into the textures. Does nothing without multisampling.
+    pub unsafe fn resolve(&self) {
+        if !self.is_multisampled() {
+            return;
+        }
+        let (width, height) = (self.width as i32, self.height as i32);
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo);
+        gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.resolve_fbo);
+        // Blits go from one read buffer to all draw buffers, so one attachment at a time
+        for attachment in color_attachments(self.color_textures.len()) {
+            gl::ReadBuffer(attachment);
+            gl::DrawBuffers(1, &attachment);
+            gl::BlitFramebuffer(0, 0, width, height, 0, 0, width, height, gl::COLOR_BUFFER_BIT, gl::NEAREST);
+        }
+        if let Some(depth) = self.builder.depth {
+            let mask = if depth.has_stencil() { gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT } else { gl::DEPTH_BUFFER_BIT };
+            gl::BlitFramebuffer(0, 0, width, height, 0, 0, width, height, mask, gl::NEAREST);
+        }
+        // Put back the draw buffers the blits changed
+        let attachments = color_attachments(self.color_textures.len());
+        gl::DrawBuffers(attachments.len() as i32, attachments.as_ptr());
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo);
+        gl::ReadBuffer(attachments.first().copied().unwrap_or(gl::NONE));
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+    }
+
+    /// The framebuffer to read the finished pixels from, after a `resolve`
+    #[allow(dead_code)]
+    pub fn resolved_fbo(&self) -> u32 {
+        self.resolve_fbo
+    }
+
+    /// Resolves and reads back the first color attachment, top row first like image files expect.
+    /// Float formats are clamped to [0, 1].
+    pub unsafe fn read_pixels(&self) -> RgbaImage {
+        self.resolve();
+        let mut pixels = vec![0u8; self.width as usize * self.height as usize * 4];
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.resolve_fbo);
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl::ReadPixels(
+            0,
+            0,
+            self.width as i32,
+            self.height as i32,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut c_void,
+        );
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        image_from_gl_pixels(self.width, self.height, &pixels)
+    }
+}
+
+impl Drop for RenderTarget {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.resolve_fbo);
+            if self.is_multisampled() {
+                gl::DeleteFramebuffers(1, &self.fbo);
+            }
+            gl::DeleteRenderbuffers(self.multisample_renderbuffers.len() as i32, self.multisample_renderbuffers.as_ptr());
+            gl::DeleteTextures(self.color_textures.len() as i32, self.color_textures.as_ptr());
+            gl::DeleteTextures(1, &self.depth_texture);
+        }
+    }
+}
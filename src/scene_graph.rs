@@ -5,6 +5,10 @@ use std::mem::ManuallyDrop;
 use std::pin::Pin;
 use std::ptr;
 
+use crate::gpu_mesh::GpuMesh;
+use crate::light::Light;
+use crate::material::{normal_matrix, Material};
+
 // Used to crete an unholy abomination upon which you should not cast your gaze.
 // This ended up being a necessity due to wanting to keep the code written by students as "straight forward" as possible
 // It is very very double plus ungood Rust, and intentionally leaks memory like a sieve. But it works, and you're more than welcome to pretend it doesn't exist!
@@ -22,8 +26,20 @@ pub struct SceneNode {
 
     pub current_transformation_matrix: glm::Mat4,
 
-    pub vao_id: u32,
-    pub index_count: i32,
+    // The mesh drawn for this node, null for nodes that only group their children.
+    // Raw for the same reasons as the children, the meshes have to outlive the scene graph.
+    pub mesh: *const GpuMesh,
+    // Texture id of a tangent space normal map, 0 means the node has none
+    pub normal_map: u32,
+    // Size in pixels of points, when the mesh is made of points
+    pub point_size: f32,
+    // Distance at which points are `point_size` big, they shrink and grow with distance
+    // from there. None keeps them the same size on screen.
+    pub point_attenuation: Option<f32>,
+    // How the mesh reflects the lights
+    pub material: Material,
+    // A light that moves with the node, shining from its origin
+    pub light: Option<Light>,
 
     pub children: Vec<*mut SceneNode>,
 }
@@ -32,7 +48,10 @@ pub struct HelicopterStruct {
     pub body: Node,
     pub main_rotor: Node,
     pub tail_rotor: Node,
+    #[allow(dead_code)]
     pub door: Node,
+    #[allow(dead_code)]
+    pub searchlight: Node,
 }
 
 impl SceneNode {
@@ -43,20 +62,28 @@ impl SceneNode {
             scale: glm::vec3(1.0, 1.0, 1.0),
             reference_point: glm::zero(),
             current_transformation_matrix: glm::identity(),
-            vao_id: 0,
-            index_count: -1,
+            mesh: ptr::null(),
+            normal_map: 0,
+            point_size: 1.0,
+            point_attenuation: None,
+            material: Material::default(),
+            light: None,
             children: vec![],
         })))
     }
-    pub fn from_vao(vao_id: u32, index_count: i32, reference_point: glm::Vec3) -> Node {
+    pub fn from_mesh(mesh: &GpuMesh, reference_point: glm::Vec3) -> Node {
         ManuallyDrop::new(Pin::new(Box::new(SceneNode {
             position: glm::zero(),
             rotation: glm::zero(),
             scale: glm::vec3(1.0, 1.0, 1.0),
-            reference_point: reference_point,
+            reference_point,
             current_transformation_matrix: glm::identity(),
-            vao_id,
-            index_count,
+            mesh: mesh as *const GpuMesh,
+            normal_map: 0,
+            point_size: 1.0,
+            point_attenuation: None,
+            material: Material::default(),
+            light: None,
             children: vec![],
         })))
     }
@@ -107,8 +134,8 @@ impl SceneNode {
                 Reference: [{:.2}, {:.2}, {:.2}]
                 Current Transformation Matrix: {}
             }}",
-            self.vao_id,
-            self.index_count,
+            unsafe { self.mesh.as_ref() }.map_or(0, |mesh| mesh.vao),
+            unsafe { self.mesh.as_ref() }.map_or(0, |mesh| mesh.index_count),
             self.children.len(),
             self.position.x,
             self.position.y,
@@ -128,12 +155,11 @@ pub unsafe fn draw_scene(
     root: &SceneNode,
     view_projection_matrix: &glm::Mat4,
     camera_position: &glm::Vec3,
-    lightsource: &glm::Vec3,
     program_id: &gl::types::GLuint,
 ) {
     // Check if node is drawable, set uniforms, draw
 
-    if root.index_count > 1 {
+    if let Some(mesh) = root.mesh.as_ref() {
         let cname = CString::new("ViewProjectionMatrix")
             .expect("expected uniform name to have no nul bytes");
         let unilocation =
@@ -142,7 +168,7 @@ pub unsafe fn draw_scene(
             unilocation,
             1,
             gl::FALSE,
-            view_projection_matrix.as_slice().as_ptr() as *const f32,
+            view_projection_matrix.as_slice().as_ptr(),
         );
 
         let cname =
@@ -153,28 +179,63 @@ pub unsafe fn draw_scene(
             unilocation,
             1,
             gl::FALSE,
-            root.current_transformation_matrix.as_slice().as_ptr() as *const f32,
+            root.current_transformation_matrix.as_slice().as_ptr(),
+        );
+
+        let cname =
+            CString::new("NormalMatrix").expect("expected uniform name to have no nul bytes");
+        let unilocation =
+            gl::GetUniformLocation(*program_id, cname.as_bytes_with_nul().as_ptr() as *const i8);
+        gl::UniformMatrix3fv(
+            unilocation,
+            1,
+            gl::FALSE,
+            normal_matrix(&root.current_transformation_matrix).as_slice().as_ptr(),
         );
 
         let cname =
             CString::new("CameraPosition").expect("expected uniform name to have no nul bytes");
         let unilocation =
             gl::GetUniformLocation(*program_id, cname.as_bytes_with_nul().as_ptr() as *const i8);
-        gl::Uniform3fv(unilocation, 1, camera_position.as_ptr() as *const f32);
+        gl::Uniform3fv(unilocation, 1, camera_position.as_ptr());
 
         let cname =
-            CString::new("LightSource").expect("expected uniform name to have no nul bytes");
+            CString::new("UseNormalMap").expect("expected uniform name to have no nul bytes");
         let unilocation =
             gl::GetUniformLocation(*program_id, cname.as_bytes_with_nul().as_ptr() as *const i8);
-        gl::Uniform3fv(unilocation, 1, lightsource.as_ptr() as *const f32);
-
-        gl::BindVertexArray(root.vao_id);
-        gl::DrawElements(
-            gl::TRIANGLES,
-            root.index_count,
-            gl::UNSIGNED_INT,
-            ptr::null(),
-        );
+        gl::Uniform1i(unilocation, (root.normal_map != 0) as i32);
+        if root.normal_map != 0 {
+            let cname =
+                CString::new("NormalMap").expect("expected uniform name to have no nul bytes");
+            let unilocation = gl::GetUniformLocation(
+                *program_id,
+                cname.as_bytes_with_nul().as_ptr() as *const i8,
+            );
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, root.normal_map);
+            gl::Uniform1i(unilocation, 0);
+        }
+
+        // Lines and points have no surface to light
+        let cname = CString::new("Unlit").expect("expected uniform name to have no nul bytes");
+        let unilocation =
+            gl::GetUniformLocation(*program_id, cname.as_bytes_with_nul().as_ptr() as *const i8);
+        gl::Uniform1i(unilocation, (mesh.mode != gl::TRIANGLES) as i32);
+
+        let cname = CString::new("PointSize").expect("expected uniform name to have no nul bytes");
+        let unilocation =
+            gl::GetUniformLocation(*program_id, cname.as_bytes_with_nul().as_ptr() as *const i8);
+        gl::Uniform1f(unilocation, root.point_size);
+
+        let cname = CString::new("PointReferenceDistance")
+            .expect("expected uniform name to have no nul bytes");
+        let unilocation =
+            gl::GetUniformLocation(*program_id, cname.as_bytes_with_nul().as_ptr() as *const i8);
+        gl::Uniform1f(unilocation, root.point_attenuation.unwrap_or(0.0));
+
+        root.material.apply(*program_id);
+
+        mesh.draw();
     }
     // Recurse
     for &child in &root.children {
@@ -182,7 +243,6 @@ pub unsafe fn draw_scene(
             &*child,
             view_projection_matrix,
             camera_position,
-            lightsource,
             program_id,
         );
     }
@@ -196,8 +256,9 @@ pub unsafe fn update_node_transformations(root: &mut SceneNode, transformation_s
     let roty = glm::rotation(root.rotation.y, &glm::vec3(0., 1., 0.));
     let rotz = glm::rotation(root.rotation.z, &glm::vec3(0., 0., 1.));
     let rotation = reference * rotx * roty * rotz * glm::inverse(&reference);
+    let scaling = glm::scaling(&root.scale);
 
-    root.current_transformation_matrix = transformation_so_far * translation * rotation;
+    root.current_transformation_matrix = transformation_so_far * translation * rotation * scaling;
 
     // Update the node's transformation matrix
     // Recurse
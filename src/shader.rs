@@ -1,10 +1,17 @@
-use gl;
 use std::{ffi::CString, path::Path, ptr, str};
 
 pub struct Shader {
     pub program_id: u32,
 }
 
+// An active vertex shader input, as reported by the linked program
+#[derive(Clone, Debug)]
+pub struct ShaderAttribute {
+    pub name: String,
+    pub location: i32,
+    pub components: usize,
+}
+
 pub struct ShaderBuilder {
     pub program_id: u32,
     shaders: Vec<u32>,
@@ -32,11 +39,43 @@ impl Shader {
     pub unsafe fn activate(&self) {
         gl::UseProgram(self.program_id);
     }
+
+    // The vertex inputs the linked program actually uses, unused ones are optimized away
+    pub unsafe fn attributes(&self) -> Vec<ShaderAttribute> {
+        let mut count = 0;
+        gl::GetProgramiv(self.program_id, gl::ACTIVE_ATTRIBUTES, &mut count);
+        (0..count as u32).map(|i| {
+            let mut name = vec![0u8; 256];
+            let (mut length, mut size, mut attribute_type) = (0, 0, 0);
+            gl::GetActiveAttrib(
+                self.program_id,
+                i,
+                name.len() as i32,
+                &mut length,
+                &mut size,
+                &mut attribute_type,
+                name.as_mut_ptr() as *mut gl::types::GLchar,
+            );
+            name.truncate(length as usize);
+            let c_name = CString::new(name.clone()).expect("attribute names have no nul bytes");
+            let components = match attribute_type {
+                gl::FLOAT_VEC2 | gl::INT_VEC2 | gl::UNSIGNED_INT_VEC2 => 2,
+                gl::FLOAT_VEC3 | gl::INT_VEC3 | gl::UNSIGNED_INT_VEC3 => 3,
+                gl::FLOAT_VEC4 | gl::INT_VEC4 | gl::UNSIGNED_INT_VEC4 => 4,
+                _ => 1,
+            };
+            ShaderAttribute {
+                name: String::from_utf8_lossy(&name).to_string(),
+                location: gl::GetAttribLocation(self.program_id, c_name.as_ptr()),
+                components,
+            }
+        }).collect()
+    }
 }
 
-impl Into<gl::types::GLenum> for ShaderType {
-    fn into(self) -> gl::types::GLenum {
-        match self {
+impl From<ShaderType> for gl::types::GLenum {
+    fn from(shader_type: ShaderType) -> gl::types::GLenum {
+        match shader_type {
             ShaderType::Vertex => gl::VERTEX_SHADER,
             ShaderType::Fragment => gl::FRAGMENT_SHADER,
             ShaderType::TessellationControl => gl::TESS_CONTROL_SHADER,
@@ -73,7 +112,7 @@ impl ShaderBuilder {
             let shader_type =
                 ShaderType::from_ext(extension).expect("Failed to parse file extension.");
             let shader_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source. {}", shader_path));
+                .unwrap_or_else(|_| panic!("Failed to read shader source. {}", shader_path));
             self.compile_shader(&shader_src, shader_type)
         } else {
             panic!(
@@ -104,8 +143,7 @@ impl ShaderBuilder {
 
     unsafe fn check_shader_errors(&self, shader_id: u32) -> bool {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
+        let mut info_log = vec![0u8; 512 - 1];
         gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
             gl::GetShaderInfoLog(
@@ -125,8 +163,7 @@ impl ShaderBuilder {
 
     unsafe fn check_linker_errors(&self) -> bool {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
+        let mut info_log = vec![0u8; 512 - 1];
         gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
             gl::GetProgramInfoLog(
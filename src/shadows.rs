@@ -0,0 +1,175 @@
+extern crate nalgebra_glm as glm;
+
+use crate::render_target::{DepthFormat, RenderTarget, RenderTargetBuilder};
+use crate::scene_graph::{draw_scene, SceneNode};
+use crate::shader::Shader;
+
+// Cascaded shadow maps for a directional light. The view frustum is cut into slices by
+// distance, and each slice gets a shadow map of its own covering it, so nearby shadows are
+// sharp while the shadows across the whole terrain still fit. The cascades are drawn side by
+// side into a single depth texture. Point and spot lights have no shadow maps, they would need
+// cube and perspective ones.
+
+// Has to match MAX_CASCADES in simple.frag
+pub const MAX_CASCADES: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    // Width and height in texels of every cascade
+    pub resolution: u32,
+    pub cascades: usize,
+    // How far from the camera shadows are drawn
+    pub distance: f32,
+    // 0 splits the distance evenly, 1 logarithmically, which gives nearby slices more detail
+    pub split_lambda: f32,
+    // How far behind a slice casters can be and still throw their shadow into it
+    pub caster_margin: f32,
+    // Depth bias of the nearest cascade, in the [0, 1] depth range of the shadow map
+    pub bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            resolution: 1024,
+            cascades: 4,
+            distance: 800.,
+            split_lambda: 0.75,
+            caster_margin: 500.,
+            bias: 0.0005,
+        }
+    }
+}
+
+/// Where each slice of the frustum ends, from `near` out to `far`
+pub fn split_distances(near: f32, far: f32, cascades: usize, lambda: f32) -> Vec<f32> {
+    (1..=cascades)
+        .map(|i| {
+            let fraction = i as f32 / cascades as f32;
+            let logarithmic = near * (far / near).powf(fraction);
+            let uniform = near + (far - near) * fraction;
+            lambda * logarithmic + (1. - lambda) * uniform
+        })
+        .collect()
+}
+
+/// The corners of the frustum `projection * view` covers, in world space
+fn frustum_corners(view_projection: &glm::Mat4) -> Vec<glm::Vec3> {
+    let inverse = glm::inverse(view_projection);
+    let mut corners = Vec::with_capacity(8);
+    for &x in &[-1., 1.] {
+        for &y in &[-1., 1.] {
+            for &z in &[-1., 1.] {
+                let corner = inverse * glm::vec4(x, y, z, 1.);
+                corners.push(corner.xyz() / corner.w);
+            }
+        }
+    }
+    corners
+}
+
+/// A light view and orthographic projection covering the bounding sphere of the frustum slice
+/// with the given corners, for a light in `light_direction`, which points towards the light.
+/// The projection moves in whole texels, so shadow edges don't crawl as the camera turns and moves.
+pub fn cascade_matrix(corners: &[glm::Vec3], light_direction: &glm::Vec3, resolution: u32, caster_margin: f32) -> glm::Mat4 {
+    let center = corners.iter().fold(glm::vec3(0., 0., 0.), |sum, c| sum + c) / corners.len() as f32;
+    let radius = corners.iter().map(|c| glm::distance(c, &center)).fold(0., f32::max);
+    // Rounded up, so the size only changes in steps
+    let radius = (radius * 16.).ceil() / 16.;
+
+    let direction = glm::normalize(light_direction);
+    let up = if direction.y.abs() > 0.99 { glm::vec3(0., 0., 1.) } else { glm::vec3(0., 1., 0.) };
+    let light_view = glm::look_at(&direction, &glm::vec3(0., 0., 0.), &up);
+
+    let texel = 2. * radius / resolution as f32;
+    let center = light_view * glm::vec4(center.x, center.y, center.z, 1.);
+    let (x, y) = ((center.x / texel).floor() * texel, (center.y / texel).floor() * texel);
+    // The light looks down -z, so further from the light is more negative
+    let projection = glm::ortho(
+        x - radius,
+        x + radius,
+        y - radius,
+        y + radius,
+        -center.z - radius - caster_margin,
+        -center.z + radius,
+    );
+    projection * light_view
+}
+
+/// The shadow maps of one directional light, see `fit` and `render`
+pub struct ShadowMaps {
+    pub settings: ShadowSettings,
+    pub target: RenderTarget,
+}
+
+impl ShadowMaps {
+    pub unsafe fn new(mut settings: ShadowSettings) -> Result<Self, String> {
+        if settings.cascades == 0 || settings.cascades > MAX_CASCADES {
+            println!("Warning: {} shadow cascades asked for, using {}", settings.cascades, MAX_CASCADES);
+            settings.cascades = MAX_CASCADES;
+        }
+        let target = RenderTargetBuilder::new(settings.resolution * settings.cascades as u32, settings.resolution)
+            .depth(DepthFormat::Depth32F)
+            .build()?;
+        // Sampled through a sampler2DShadow, which compares and filters between 4 texels in one go
+        gl::BindTexture(gl::TEXTURE_2D, target.depth_texture);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_COMPARE_FUNC, gl::LEQUAL as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+        Ok(ShadowMaps { settings, target })
+    }
+
+    /// Fits the cascades to the camera's frustum, giving the light view projection of each
+    pub fn fit(&self, view: &glm::Mat4, aspect: f32, field_of_view: f32, near: f32, light_direction: &glm::Vec3) -> Vec<glm::Mat4> {
+        let settings = &self.settings;
+        let splits = split_distances(near, settings.distance, settings.cascades, settings.split_lambda);
+        let mut slice_near = near;
+        splits
+            .iter()
+            .map(|&slice_far| {
+                let projection = glm::perspective(aspect, field_of_view, slice_near, slice_far);
+                slice_near = slice_far;
+                let corners = frustum_corners(&(projection * view));
+                cascade_matrix(&corners, light_direction, settings.resolution, settings.caster_margin)
+            })
+            .collect()
+    }
+
+    /// Draws the depth of the scene as seen from the light into every cascade. Leaves the
+    /// shadow maps' framebuffer bound.
+    pub unsafe fn render(&self, matrices: &[glm::Mat4], root: &SceneNode, camera_position: &glm::Vec3, program_id: u32) {
+        self.target.bind();
+        gl::Clear(gl::DEPTH_BUFFER_BIT);
+        gl::UseProgram(program_id);
+        // Both sides cast shadows, the terrain is a single surface. The offset pushes the
+        // depths back a little against shadow acne.
+        gl::Disable(gl::CULL_FACE);
+        gl::Enable(gl::POLYGON_OFFSET_FILL);
+        gl::PolygonOffset(2., 4.);
+        let resolution = self.settings.resolution as i32;
+        for (i, matrix) in matrices.iter().enumerate() {
+            gl::Viewport(i as i32 * resolution, 0, resolution, resolution);
+            draw_scene(root, matrix, camera_position, &program_id);
+        }
+        gl::Disable(gl::POLYGON_OFFSET_FILL);
+        gl::Enable(gl::CULL_FACE);
+    }
+
+    /// Sets the shadow uniforms of the program that lights the scene, and binds the shadow
+    /// maps to `texture_unit`
+    pub unsafe fn bind(&self, matrices: &[glm::Mat4], shader: &Shader, texture_unit: u32) {
+        let location = |name: &str| shader.get_uniform_location(name);
+        gl::ActiveTexture(gl::TEXTURE0 + texture_unit);
+        gl::BindTexture(gl::TEXTURE_2D, self.target.depth_texture);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::Uniform1i(location("ShadowMap"), texture_unit as i32);
+        gl::Uniform1i(location("UseShadows"), 1);
+        gl::Uniform1i(location("CascadeCount"), matrices.len() as i32);
+        gl::Uniform1f(location("ShadowBias"), self.settings.bias);
+        // Shadow coordinates are in [-1, 1] like clip space, the shader maps them to [0, 1]
+        let values: Vec<f32> = matrices.iter().flat_map(|m| m.as_slice().to_vec()).collect();
+        gl::UniformMatrix4fv(location("ShadowMatrices"), matrices.len() as i32, gl::FALSE, values.as_ptr());
+    }
+}
@@ -0,0 +1,434 @@
+extern crate nalgebra_glm as glm;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::mesh::Mesh;
+
+// Quadric error metric after Garland and Heckbert. Vertices are only ever collapsed onto one of
+// their neighbours, never to a new position, so every surviving vertex keeps its exact attributes.
+
+// Symmetric 4x4 matrix stored as its upper triangle: a2 ab ac ad b2 bc bd c2 cd d2
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(normal: glm::Vec3, point: glm::Vec3, weight: f64) -> Self {
+        let (a, b, c) = (normal.x as f64, normal.y as f64, normal.z as f64);
+        let d = -(a * point.x as f64 + b * point.y as f64 + c * point.z as f64);
+        let w = weight;
+        Quadric([w*a*a, w*a*b, w*a*c, w*a*d, w*b*b, w*b*c, w*b*d, w*c*c, w*c*d, w*d*d])
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a += b;
+        }
+    }
+
+    fn error(&self, p: &glm::Vec3) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x as f64, p.y as f64, p.z as f64);
+        q[0]*x*x + 2.0*q[1]*x*y + 2.0*q[2]*x*z + 2.0*q[3]*x
+            + q[4]*y*y + 2.0*q[5]*y*z + 2.0*q[6]*y
+            + q[7]*z*z + 2.0*q[8]*z
+            + q[9]
+    }
+}
+
+// Candidate collapse of vertex group `from` onto `to`, ordered so the cheapest pops first
+struct Collapse {
+    cost: f64,
+    from: usize,
+    to: usize,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+// Penalty for moving a vertex off a mesh border, relative to the surface error
+const BOUNDARY_WEIGHT: f64 = 1000.0;
+// Collapses that turn any remaining triangle by more than this (as a cosine) are rejected
+const MIN_NORMAL_COSINE: f32 = 0.2;
+
+// Working state of the simplification. Vertices sharing a position form a group, groups are
+// what gets collapsed, and the mesh vertices within a group are the attribute variants along seams.
+struct Simplifier<'a> {
+    mesh: &'a Mesh,
+    group_of: Vec<usize>,
+    positions: Vec<glm::Vec3>,
+    quadrics: Vec<Quadric>,
+    versions: Vec<u32>,
+    on_boundary: Vec<bool>,
+    // The groups every group shares a border edge with
+    boundary_neighbours: Vec<Vec<usize>>,
+    triangles: Vec<[u32; 3]>,
+    alive: Vec<bool>,
+    triangles_of: Vec<Vec<usize>>,
+    alive_count: usize,
+}
+
+impl<'a> Simplifier<'a> {
+    fn new(mesh: &'a Mesh) -> Self {
+        let mut groups: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut positions = Vec::new();
+        let group_of: Vec<usize> = (0..mesh.vertex_count()).map(|i| {
+            let p = mesh.position(i);
+            *groups.entry([p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]).or_insert_with(|| {
+                positions.push(p);
+                positions.len() - 1
+            })
+        }).collect();
+        let group_count = positions.len();
+
+        let mut triangles = Vec::new();
+        let mut triangles_of = vec![Vec::new(); group_count];
+        let mut quadrics = vec![Quadric::default(); group_count];
+        let mut edge_uses: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for t in 0..mesh.triangle_count() {
+            let corners = [mesh.indices[3*t], mesh.indices[3*t + 1], mesh.indices[3*t + 2]];
+            let g = [group_of[corners[0] as usize], group_of[corners[1] as usize], group_of[corners[2] as usize]];
+            if g[0] == g[1] || g[1] == g[2] || g[0] == g[2] { continue }
+
+            let index = triangles.len();
+            triangles.push(corners);
+            let cross = glm::cross(&(positions[g[1]] - positions[g[0]]), &(positions[g[2]] - positions[g[0]]));
+            let area = glm::length(&cross);
+            if area > 0.0 {
+                let plane = Quadric::from_plane(cross / area, positions[g[0]], area as f64 * 0.5);
+                for &group in &g {
+                    quadrics[group].add(&plane);
+                }
+            }
+            for k in 0..3 {
+                triangles_of[g[k]].push(index);
+                let (a, b) = (g[k], g[(k + 1) % 3]);
+                edge_uses.entry((a.min(b), a.max(b))).or_default().push(index);
+            }
+        }
+
+        // Border edges get a steep plane standing on them, so moving off the border is expensive
+        let mut on_boundary = vec![false; group_count];
+        let mut boundary_neighbours = vec![Vec::new(); group_count];
+        for (&(a, b), users) in &edge_uses {
+            if users.len() != 1 { continue }
+            let corners = triangles[users[0]];
+            let g: Vec<usize> = corners.iter().map(|&c| group_of[c as usize]).collect();
+            let face_normal = glm::cross(&(positions[g[1]] - positions[g[0]]), &(positions[g[2]] - positions[g[0]]));
+            let edge = positions[b] - positions[a];
+            let normal = glm::cross(&edge, &face_normal);
+            let length = glm::length(&normal);
+            if length > 0.0 {
+                let plane = Quadric::from_plane(normal / length, positions[a], BOUNDARY_WEIGHT * glm::length2(&edge) as f64);
+                quadrics[a].add(&plane);
+                quadrics[b].add(&plane);
+            }
+            on_boundary[a] = true;
+            on_boundary[b] = true;
+            boundary_neighbours[a].push(b);
+            boundary_neighbours[b].push(a);
+        }
+
+        let alive_count = triangles.len();
+        Simplifier {
+            mesh,
+            group_of,
+            positions,
+            quadrics,
+            versions: vec![0; group_count],
+            on_boundary,
+            boundary_neighbours,
+            alive: vec![true; triangles.len()],
+            triangles,
+            triangles_of,
+            alive_count,
+        }
+    }
+
+    fn groups(&self, t: usize) -> [usize; 3] {
+        let c = self.triangles[t];
+        [self.group_of[c[0] as usize], self.group_of[c[1] as usize], self.group_of[c[2] as usize]]
+    }
+
+    fn neighbours(&self, group: usize) -> HashSet<usize> {
+        self.triangles_of[group].iter()
+            .filter(|&&t| self.alive[t])
+            .flat_map(|&t| self.groups(t).to_vec())
+            .filter(|&g| g != group)
+            .collect()
+    }
+
+    fn candidate(&self, from: usize, to: usize) -> Collapse {
+        let mut quadric = self.quadrics[from];
+        quadric.add(&self.quadrics[to]);
+        Collapse {
+            cost: quadric.error(&self.positions[to]),
+            from,
+            to,
+            versions: (self.versions[from], self.versions[to]),
+        }
+    }
+
+    // For every mesh vertex of `from`, the vertex of `to` it turns into. None if the collapse
+    // would tear a seam, i.e. some variant of `from` has no matching variant across the edge.
+    fn vertex_mapping(&self, from: usize, to: usize) -> Option<HashMap<u32, u32>> {
+        let mut mapping: HashMap<u32, u32> = HashMap::new();
+        let mut variants: HashSet<u32> = HashSet::new();
+        for &t in &self.triangles_of[from] {
+            if !self.alive[t] { continue }
+            let corners = self.triangles[t];
+            let from_vertex = *corners.iter().find(|&&c| self.group_of[c as usize] == from)?;
+            variants.insert(from_vertex);
+            if let Some(&to_vertex) = corners.iter().find(|&&c| self.group_of[c as usize] == to) {
+                if *mapping.entry(from_vertex).or_insert(to_vertex) != to_vertex {
+                    return None;
+                }
+            }
+        }
+        if variants.iter().all(|v| mapping.contains_key(v)) { Some(mapping) } else { None }
+    }
+
+    fn is_valid(&self, collapse: &Collapse) -> bool {
+        let (from, to) = (collapse.from, collapse.to);
+        if self.on_boundary[from] && !self.boundary_neighbours[from].contains(&to) {
+            return false;
+        }
+
+        // Link condition: the only neighbours the two may share are the tips of the edge's triangles,
+        // otherwise the collapse pinches the surface into a non-manifold shape
+        let shared_triangles = self.triangles_of[from].iter()
+            .filter(|&&t| self.alive[t] && self.groups(t).contains(&to))
+            .count();
+        let shared_neighbours = self.neighbours(from).intersection(&self.neighbours(to)).count();
+        if shared_neighbours > shared_triangles {
+            return false;
+        }
+
+        for &t in &self.triangles_of[from] {
+            if !self.alive[t] { continue }
+            let g = self.groups(t);
+            if g.contains(&to) { continue }
+            let before: Vec<glm::Vec3> = g.iter().map(|&i| self.positions[i]).collect();
+            let after: Vec<glm::Vec3> = g.iter().map(|&i| self.positions[if i == from { to } else { i }]).collect();
+            let normal_before = glm::cross(&(before[1] - before[0]), &(before[2] - before[0]));
+            let normal_after = glm::cross(&(after[1] - after[0]), &(after[2] - after[0]));
+            let (length_before, length_after) = (glm::length(&normal_before), glm::length(&normal_after));
+            if length_after <= 0.0 { return false }
+            if length_before > 0.0 && glm::dot(&normal_before, &normal_after) < MIN_NORMAL_COSINE * length_before * length_after {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn collapse(&mut self, from: usize, to: usize, mapping: &HashMap<u32, u32>) {
+        let from_triangles = std::mem::take(&mut self.triangles_of[from]);
+        for t in from_triangles {
+            if !self.alive[t] { continue }
+            if self.groups(t).contains(&to) {
+                self.alive[t] = false;
+                self.alive_count -= 1;
+                continue;
+            }
+            for corner in self.triangles[t].iter_mut() {
+                if let Some(&mapped) = mapping.get(corner) {
+                    *corner = mapped;
+                }
+            }
+            self.triangles_of[to].push(t);
+        }
+        let from_quadric = self.quadrics[from];
+        self.quadrics[to].add(&from_quadric);
+        self.on_boundary[to] |= self.on_boundary[from];
+        // Border edges of `from` now end at `to`, the one between the two is gone
+        for other in std::mem::take(&mut self.boundary_neighbours[from]) {
+            self.boundary_neighbours[other].retain(|&g| g != from);
+            if other != to && !self.boundary_neighbours[to].contains(&other) {
+                self.boundary_neighbours[to].push(other);
+                self.boundary_neighbours[other].push(to);
+            }
+        }
+        self.versions[from] += 1;
+        self.versions[to] += 1;
+        let alive = &self.alive;
+        self.triangles_of[to].retain(|&t| alive[t]);
+    }
+
+    fn run(&mut self, target_triangles: usize) {
+        let mut heap = BinaryHeap::new();
+        for group in 0..self.positions.len() {
+            for neighbour in self.neighbours(group) {
+                heap.push(self.candidate(group, neighbour));
+            }
+        }
+
+        while self.alive_count > target_triangles {
+            let collapse = match heap.pop() {
+                Some(collapse) => collapse,
+                None => break,
+            };
+            if collapse.versions != (self.versions[collapse.from], self.versions[collapse.to]) { continue }
+            if !self.is_valid(&collapse) { continue }
+            let mapping = match self.vertex_mapping(collapse.from, collapse.to) {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+
+            self.collapse(collapse.from, collapse.to, &mapping);
+            for neighbour in self.neighbours(collapse.to) {
+                heap.push(self.candidate(collapse.to, neighbour));
+                heap.push(self.candidate(neighbour, collapse.to));
+            }
+        }
+    }
+
+    fn result(&self) -> Mesh {
+        let indices: Vec<u32> = self.triangles.iter().zip(&self.alive)
+            .filter(|(_, &alive)| alive)
+            .flat_map(|(corners, _)| corners.iter().cloned())
+            .collect();
+        self.mesh.with_indices(&indices)
+    }
+}
+
+impl Mesh {
+    /// Reduces the mesh to about `target_ratio` of its triangles by quadric error edge
+    /// collapses. Open borders and attribute seams are kept in place, so the result may stop
+    /// short of the target on meshes made mostly of those.
+    pub fn simplify(&self, target_ratio: f32) -> Mesh {
+        let target = (self.triangle_count() as f32 * target_ratio.clamp(0.0, 1.0)) as usize;
+        let mut simplifier = Simplifier::new(self);
+        simplifier.run(target);
+        simplifier.result()
+    }
+}
+
+/// Level of detail when every doubling of the distance beyond `lod_distance` drops a level
+pub fn lod_level(distance: f32, lod_distance: f32, level_count: usize) -> usize {
+    let mut level = 0;
+    let mut threshold = lod_distance;
+    while distance > threshold && level + 1 < level_count {
+        level += 1;
+        threshold *= 2.0;
+    }
+    level
+}
+
+/// Versions of a mesh with halving triangle counts, the full detail one first
+pub struct LodChain {
+    pub levels: Vec<Mesh>,
+    pub lod_distance: f32,
+}
+
+impl LodChain {
+    pub fn new(mesh: &Mesh, level_count: usize, lod_distance: f32) -> Self {
+        let mut levels = vec![mesh.clone()];
+        for _ in 1..level_count.max(1) {
+            let next = levels.last().expect("chain is never empty").simplify(0.5);
+            levels.push(next);
+        }
+        LodChain { levels, lod_distance }
+    }
+
+    pub fn level_for(&self, distance: f32) -> usize {
+        lod_level(distance, self.lod_distance, self.levels.len())
+    }
+}
+
+/// LOD chains for a model made of several meshes, like the helicopter and its parts. The parts
+/// switch levels together, so a model is never drawn with a mix of levels.
+pub struct ModelLods {
+    pub parts: Vec<LodChain>,
+}
+
+impl ModelLods {
+    pub fn new<'m>(parts: impl IntoIterator<Item = &'m Mesh>, level_count: usize, lod_distance: f32) -> Self {
+        ModelLods {
+            parts: parts.into_iter().map(|mesh| LodChain::new(mesh, level_count, lod_distance)).collect(),
+        }
+    }
+
+    pub fn level_for(&self, distance: f32) -> usize {
+        self.parts.first().map_or(0, |part| part.level_for(distance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: f32 = 10.0;
+
+    fn on_outline(p: &glm::Vec3) -> bool {
+        p.x.abs() == SIZE / 2.0 || p.z.abs() == SIZE / 2.0
+    }
+
+    // Edges used by a single triangle, as pairs of positions
+    fn border_edges(mesh: &Mesh) -> Vec<(glm::Vec3, glm::Vec3)> {
+        let mut uses: HashMap<(u32, u32), usize> = HashMap::new();
+        for triangle in mesh.indices.chunks(3) {
+            for k in 0..3 {
+                let (a, b) = (triangle[k], triangle[(k + 1) % 3]);
+                *uses.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        uses.into_iter()
+            .filter(|&(_, count)| count == 1)
+            .map(|((a, b), _)| (mesh.position(a as usize), mesh.position(b as usize)))
+            .collect()
+    }
+
+    #[test]
+    fn simplify_halves_a_grid() {
+        let grid = Mesh::plane_grid(SIZE, SIZE, 16, 16, [1.0; 4]);
+        let simplified = grid.simplify(0.5);
+        assert!(simplified.triangle_count() <= grid.triangle_count() / 2);
+        assert!(simplified.triangle_count() > 0);
+    }
+
+    #[test]
+    fn simplify_keeps_the_border_in_place() {
+        let grid = Mesh::plane_grid(SIZE, SIZE, 16, 16, [1.0; 4]);
+        let simplified = grid.simplify(0.5);
+
+        let border = border_edges(&simplified);
+        assert!(!border.is_empty());
+        for (a, b) in &border {
+            assert!(on_outline(a) && on_outline(b), "border edge {:?} to {:?} moved inside", a, b);
+        }
+        // Collapsing along the border may drop vertices on it, but never the corners
+        for &(x, z) in &[(-1.0, -1.0), (-1.0, 1.0), (1.0, -1.0), (1.0, 1.0)] {
+            let corner = glm::vec3(x * SIZE / 2.0, 0.0, z * SIZE / 2.0);
+            assert!(simplified.indices.iter().any(|&i| simplified.position(i as usize) == corner));
+        }
+        assert_eq!(simplified.bounds(), grid.bounds());
+    }
+
+    #[test]
+    fn model_parts_share_a_level() {
+        let parts = [Mesh::plane_grid(SIZE, SIZE, 8, 8, [1.0; 4]), Mesh::cube(glm::vec3(1.0, 1.0, 1.0), [1.0; 4])];
+        let lods = ModelLods::new(&parts, 3, 50.0);
+        assert!(lods.parts.iter().all(|part| part.levels.len() == 3));
+        assert_eq!(lods.level_for(10.0), 0);
+        assert_eq!(lods.level_for(75.0), 1);
+        assert_eq!(lods.level_for(1000.0), 2);
+    }
+}
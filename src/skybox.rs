@@ -0,0 +1,223 @@
+extern crate nalgebra_glm as glm;
+
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+
+use crate::environment::CubeImage;
+use crate::shader::{Shader, ShaderBuilder};
+use crate::util::Random;
+use crate::world::Camera;
+
+// The sky around the scene, drawn behind everything and reflected by shiny surfaces. Loaded
+// from six face images or one equirectangular image, or made up as a starfield.
+
+// Texture unit the sky is bound to for reflections, after the environment's, see environment.rs
+pub const SKYBOX_UNIT: u32 = 8;
+// File names of the faces in a skybox directory, in GL's order, with any image extension
+const FACE_NAMES: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+// Faces made from an equirectangular image are a quarter of its width, within these bounds
+const MIN_FACE_SIZE: usize = 16;
+const MAX_FACE_SIZE: usize = 1024;
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Linear colors of an image file, row by row. Radiance .hdr files are linear already, other
+/// images are taken to be sRGB.
+fn load_linear(path: &Path) -> Result<(usize, usize, Vec<glm::Vec3>), String> {
+    let is_hdr = path
+        .extension()
+        .is_some_and(|e| e.to_string_lossy().eq_ignore_ascii_case("hdr"));
+    if is_hdr {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let decoder = image::hdr::HdrDecoder::new(BufReader::new(file))
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let metadata = decoder.metadata();
+        let pixels = decoder
+            .read_image_hdr()
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let colors = pixels.iter().map(|p| glm::vec3(p[0], p[1], p[2])).collect();
+        return Ok((metadata.width as usize, metadata.height as usize, colors));
+    }
+    let image = image::open(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .into_rgb8();
+    let colors = image
+        .pixels()
+        .map(|p| glm::vec3(srgb_to_linear(p[0] as f32 / 255.), srgb_to_linear(p[1] as f32 / 255.), srgb_to_linear(p[2] as f32 / 255.)))
+        .collect();
+    Ok((image.width() as usize, image.height() as usize, colors))
+}
+
+/// A cube map from the images px, nx, py, ny, pz and nz in `directory`, which have to be
+/// square and all the same size
+pub fn load_faces(directory: &Path) -> Result<CubeImage, String> {
+    let entries: Vec<PathBuf> = directory
+        .read_dir()
+        .map_err(|e| format!("Failed to read {}: {}", directory.display(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    let mut size = 0;
+    let mut faces = Vec::with_capacity(6);
+    for name in &FACE_NAMES {
+        let path = entries
+            .iter()
+            .find(|p| p.file_stem().is_some_and(|stem| stem.to_string_lossy().eq_ignore_ascii_case(name)))
+            .ok_or_else(|| format!("No {} face in {}", name, directory.display()))?;
+        let (width, height, colors) = load_linear(path)?;
+        if width != height || (size != 0 && width != size) {
+            return Err(format!(
+                "{} is {}x{}, the faces have to be square and the same size",
+                path.display(),
+                width,
+                height
+            ));
+        }
+        size = width;
+        faces.push(colors);
+    }
+    Ok(CubeImage { size, faces })
+}
+
+/// A cube map from an equirectangular image, with the zenith along the top edge and -z in the
+/// middle
+pub fn load_equirectangular(path: &Path) -> Result<CubeImage, String> {
+    let (width, height, colors) = load_linear(path)?;
+    if width < 2 || height < 2 {
+        return Err(format!("{} is too small for a sky", path.display()));
+    }
+    let texel = |x: usize, y: usize| colors[y.min(height - 1) * width + x % width];
+    let size = (width / 4).clamp(MIN_FACE_SIZE, MAX_FACE_SIZE);
+    Ok(CubeImage::from_fn(size, |direction| {
+        // Bilinearly filtered, wrapping around horizontally
+        let u = 0.5 + direction.x.atan2(-direction.z) / (2. * PI);
+        let v = direction.y.clamp(-1., 1.).acos() / PI;
+        let (x, y) = (u * width as f32 - 0.5 + width as f32, (v * height as f32 - 0.5).max(0.));
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (fx, fy) = (x.fract(), y.fract());
+        let top = glm::lerp(&texel(x0, y0), &texel(x0 + 1, y0), fx);
+        let bottom = glm::lerp(&texel(x0, y0 + 1), &texel(x0 + 1, y0 + 1), fx);
+        glm::lerp(&top, &bottom, fy)
+    }))
+}
+
+/// Loads a sky from a directory of six faces, or from an equirectangular image
+pub fn load(path: &Path) -> Result<CubeImage, String> {
+    if path.is_dir() {
+        load_faces(path)
+    } else {
+        load_equirectangular(path)
+    }
+}
+
+/// Sprinkles `count` stars over `sky`, the same ones for the same seed. Most are faint, a few
+/// are brighter than white.
+pub fn add_stars(sky: &mut CubeImage, count: usize, seed: u32) {
+    let mut random = Random::new(seed);
+    for _ in 0..count {
+        // Evenly spread over the sphere
+        let z = 2. * random.next_f32() - 1.;
+        let angle = 2. * PI * random.next_f32();
+        let r = (1. - z * z).sqrt();
+        let direction = glm::vec3(r * angle.cos(), r * angle.sin(), z);
+        let brightness = 0.05 + 3. * random.next_f32().powi(12);
+        // From reddish to bluish
+        let temperature = random.next_f32();
+        let tint = glm::vec3(1. - 0.2 * temperature, 0.9, 0.8 + 0.2 * temperature);
+        let (face, index) = sky.texel_index(&direction);
+        sky.faces[face][index] += tint * brightness;
+    }
+}
+
+/// The sky on the GPU
+pub struct Skybox {
+    pub texture: u32,
+    pub shader: Shader,
+    // Empty, the vertices are made up in the shader, but drawing needs one bound
+    vao: u32,
+}
+
+impl Skybox {
+    pub unsafe fn new(sky: &CubeImage) -> Self {
+        let mut texture = 0;
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, texture);
+        for (face, texels) in sky.faces.iter().enumerate() {
+            let data: Vec<f32> = texels.iter().flat_map(|c| vec![c.x, c.y, c.z]).collect();
+            gl::TexImage2D(
+                gl::TEXTURE_CUBE_MAP_POSITIVE_X + face as u32,
+                0,
+                gl::RGB16F as i32,
+                sky.size as i32,
+                sky.size as i32,
+                0,
+                gl::RGB,
+                gl::FLOAT,
+                data.as_ptr() as *const c_void,
+            );
+        }
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        for &wrap in &[gl::TEXTURE_WRAP_S, gl::TEXTURE_WRAP_T, gl::TEXTURE_WRAP_R] {
+            gl::TexParameteri(gl::TEXTURE_CUBE_MAP, wrap, gl::CLAMP_TO_EDGE as i32);
+        }
+        gl::GenerateMipmap(gl::TEXTURE_CUBE_MAP);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
+
+        let shader = ShaderBuilder::new()
+            .attach_file("shaders/skybox.vert")
+            .attach_file("shaders/skybox.frag")
+            .link();
+        let mut vao = 0;
+        gl::GenVertexArrays(1, &mut vao);
+        Skybox { texture, shader, vao }
+    }
+
+    /// Draws the sky wherever nothing has been drawn yet, so after the opaque geometry. Leaves
+    /// the sky's program in use. Writes linear colors if `linear_output` is set, see post.rs.
+    pub unsafe fn draw(&self, camera: &Camera, linear_output: bool) {
+        // Only the camera's rotation, the sky is infinitely far away
+        let rotation = glm::mat3_to_mat4(&glm::mat4_to_mat3(&camera.view));
+        let inverse = glm::inverse(&(camera.projection() * rotation));
+
+        self.shader.activate();
+        gl::UniformMatrix4fv(self.shader.get_uniform_location("InverseViewProjection"), 1, gl::FALSE, inverse.as_ptr());
+        gl::Uniform1i(self.shader.get_uniform_location("LinearOutput"), linear_output as i32);
+        self.bind(&self.shader);
+
+        // The sky is at the far plane, where the depth buffer was cleared to, so it passes with
+        // LEQUAL exactly where nothing is in front of it
+        gl::DepthFunc(gl::LEQUAL);
+        gl::DepthMask(gl::FALSE);
+        gl::BindVertexArray(self.vao);
+        gl::DrawArrays(gl::TRIANGLES, 0, 3);
+        gl::BindVertexArray(0);
+        gl::DepthMask(gl::TRUE);
+        gl::DepthFunc(gl::LESS);
+    }
+
+    /// Binds the sky for reflections in `shader`, which has to be in use
+    pub unsafe fn bind(&self, shader: &Shader) {
+        gl::ActiveTexture(gl::TEXTURE0 + SKYBOX_UNIT);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, self.texture);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::Uniform1i(shader.get_uniform_location("Sky"), SKYBOX_UNIT as i32);
+    }
+}
+
+impl Drop for Skybox {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
@@ -0,0 +1,780 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use crate::mesh::Mesh;
+use crate::mesh_cache;
+use crate::simplify::lod_level;
+use crate::util::Random;
+
+// Integer hash used as the source of randomness, so a seed always gives the same terrain
+fn hash(x: i32, y: i32, seed: u32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x27d4_eb2d) ^ (y as u32).wrapping_mul(0x1656_67b1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    h
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// Perlin style gradient noise, roughly in [-1, 1]
+fn gradient_noise(x: f32, y: f32, seed: u32) -> f32 {
+    periodic_gradient_noise(x, y, 0, seed)
+}
+
+// Like gradient_noise, but repeating every `period` units along x and y, unless it is 0
+fn periodic_gradient_noise(x: f32, y: f32, period: i32, seed: u32) -> f32 {
+    let wrap = |i: i32| if period > 0 { i.rem_euclid(period) } else { i };
+    let (x0, y0) = (x.floor() as i32, y.floor() as i32);
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let corner = |i: i32, j: i32| {
+        let angle = hash(wrap(x0 + i), wrap(y0 + j), seed) as f32 / u32::MAX as f32 * 2.0 * PI;
+        angle.cos() * (fx - i as f32) + angle.sin() * (fy - j as f32)
+    };
+    let (u, v) = (fade(fx), fade(fy));
+    std::f32::consts::SQRT_2 * lerp(lerp(corner(0, 0), corner(1, 0), u), lerp(corner(0, 1), corner(1, 1), u), v)
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseKind {
+    // Fractal Brownian motion, rolling hills
+    Fbm,
+    // Inverted absolute noise, sharp ridges and valleys
+    Ridged,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseSettings {
+    pub kind: NoiseKind,
+    pub octaves: u32,
+    // Features per heightmap width for the first octave
+    pub frequency: f32,
+    pub lacunarity: f32,
+    pub gain: f32,
+}
+
+impl Default for NoiseSettings {
+    fn default() -> Self {
+        NoiseSettings { kind: NoiseKind::Fbm, octaves: 6, frequency: 4.0, lacunarity: 2.0, gain: 0.5 }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CraterSettings {
+    pub count: usize,
+    // Radii as a fraction of the heightmap width. Small craters are much more common than big ones.
+    pub min_radius: f32,
+    pub max_radius: f32,
+    // Bowl depth relative to the radius, in heightmap units
+    pub depth: f32,
+}
+
+impl Default for CraterSettings {
+    fn default() -> Self {
+        CraterSettings { count: 60, min_radius: 0.01, max_radius: 0.12, depth: 0.6 }
+    }
+}
+
+/// Grid of height samples, stored row by row. Row 0 is the far (-z) edge of the terrain,
+/// like the top row of an image seen from above. Heights are nominally in [0, 1].
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    pub width: usize,
+    pub depth: usize,
+    pub heights: Vec<f32>,
+}
+
+#[allow(dead_code)]
+impl Heightmap {
+    /// Reads a grayscale image, keeping full precision for 16 bit images. Panics on an empty image.
+    pub fn from_image(path: &str) -> Heightmap {
+        let image = image::open(path).unwrap_or_else(|e| panic!("Failed to load heightmap {}: {}", path, e));
+        let (width, depth, heights) = match image {
+            image::DynamicImage::ImageLuma16(buffer) => {
+                let heights = buffer.pixels().map(|p| p[0] as f32 / u16::MAX as f32).collect();
+                (buffer.width(), buffer.height(), heights)
+            }
+            other => {
+                let buffer = other.to_luma8();
+                let heights = buffer.pixels().map(|p| p[0] as f32 / u8::MAX as f32).collect();
+                (buffer.width(), buffer.height(), heights)
+            }
+        };
+        if width == 0 || depth == 0 {
+            panic!("Heightmap {} is empty", path);
+        }
+        Heightmap { width: width as usize, depth: depth as usize, heights }
+    }
+
+    /// Fractal noise heightmap, normalized to [0, 1]. Panics if either side is 0.
+    pub fn noise(width: usize, depth: usize, seed: u32, settings: &NoiseSettings) -> Heightmap {
+        if width == 0 || depth == 0 {
+            panic!("Can't make an empty {}x{} heightmap", width, depth);
+        }
+        let mut heights = Vec::with_capacity(width * depth);
+        for row in 0..depth {
+            for column in 0..width {
+                let (x, y) = (column as f32 / width as f32, row as f32 / width as f32);
+                let (mut frequency, mut amplitude, mut height) = (settings.frequency, 1.0, 0.0);
+                for octave in 0..settings.octaves {
+                    let n = gradient_noise(x * frequency, y * frequency, seed.wrapping_add(octave));
+                    height += amplitude * match settings.kind {
+                        NoiseKind::Fbm => n,
+                        NoiseKind::Ridged => {
+                            let ridge = 1.0 - n.abs();
+                            ridge * ridge
+                        }
+                    };
+                    frequency *= settings.lacunarity;
+                    amplitude *= settings.gain;
+                }
+                heights.push(height);
+            }
+        }
+        let mut heightmap = Heightmap { width, depth, heights };
+        heightmap.normalize();
+        heightmap
+    }
+
+    /// Rolling noise covered in craters, normalized to [0, 1]
+    pub fn lunar(size: usize, seed: u32) -> Heightmap {
+        let settings = NoiseSettings { octaves: 7, frequency: 3.0, gain: 0.45, ..Default::default() };
+        let mut heightmap = Heightmap::noise(size, size, seed, &settings);
+        for h in heightmap.heights.iter_mut() {
+            *h *= 0.15;
+        }
+        heightmap.stamp_craters(seed, &CraterSettings::default());
+        heightmap.normalize();
+        heightmap
+    }
+
+    /// Rescales the heights to exactly cover [0, 1]
+    pub fn normalize(&mut self) {
+        let min = self.heights.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = self.heights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let range = if max > min { max - min } else { 1.0 };
+        for h in self.heights.iter_mut() {
+            *h = (*h - min) / range;
+        }
+    }
+
+    /// Presses bowl shaped craters with raised rims into the heightmap
+    pub fn stamp_craters(&mut self, seed: u32, settings: &CraterSettings) {
+        let mut random = Random::new(seed);
+        for _ in 0..settings.count {
+            let (cx, cy) = (random.next_f32() * self.width as f32, random.next_f32() * self.depth as f32);
+            // Squaring the random number skews the distribution towards small craters
+            let t = random.next_f32();
+            let radius_fraction = settings.min_radius * (settings.max_radius / settings.min_radius).powf(t * t);
+            let radius = radius_fraction * self.width as f32;
+            let bowl_depth = settings.depth * radius_fraction;
+
+            let reach = (2.0 * radius).ceil() as i32;
+            for y in (cy as i32 - reach).max(0)..(cy as i32 + reach).min(self.depth as i32) {
+                for x in (cx as i32 - reach).max(0)..(cx as i32 + reach).min(self.width as i32) {
+                    let d = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt() / radius;
+                    if d >= 2.0 { continue }
+                    let bowl = if d < 1.0 { (d * d - 1.0) * bowl_depth } else { 0.0 };
+                    let rim = 0.25 * bowl_depth * (-((d - 1.0) / 0.3).powi(2)).exp();
+                    self.heights[y as usize * self.width + x as usize] += bowl + rim;
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, column: usize, row: usize) -> f32 {
+        self.heights[row.min(self.depth - 1) * self.width + column.min(self.width - 1)]
+    }
+
+    /// Bilinear sample, u goes along +x and v along -z, both in [0, 1]
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let y = (1.0 - v.clamp(0.0, 1.0)) * (self.depth - 1) as f32;
+        let (column, row) = (x.floor() as usize, y.floor() as usize);
+        let (fx, fy) = (x - column as f32, y - row as f32);
+        lerp(
+            lerp(self.get(column, row), self.get(column + 1, row), fx),
+            lerp(self.get(column, row + 1), self.get(column + 1, row + 1), fx),
+            fy,
+        )
+    }
+}
+
+// Uniform grid over the xz plane where every cell lists the triangles whose bounding box overlaps it
+struct TriangleGrid {
+    min: glm::Vec2,
+    cell_size: glm::Vec2,
+    columns: usize,
+    rows: usize,
+    cells: Vec<Vec<u32>>,
+}
+
+impl TriangleGrid {
+    fn new(mesh: &Mesh) -> Self {
+        let mut min = glm::vec2(f32::INFINITY, f32::INFINITY);
+        let mut max = glm::vec2(f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for p in mesh.vertices.chunks_exact(3) {
+            min = glm::min2(&min, &glm::vec2(p[0], p[2]));
+            max = glm::max2(&max, &glm::vec2(p[0], p[2]));
+        }
+        if mesh.vertex_count() == 0 {
+            min = glm::zero();
+            max = glm::zero();
+        }
+
+        // Aim for a couple of triangles per cell
+        let side = ((mesh.triangle_count() / 2) as f32).sqrt().ceil().max(1.0) as usize;
+        let extent = glm::max2(&(max - min), &glm::vec2(1e-6, 1e-6));
+        let mut grid = TriangleGrid {
+            min,
+            cell_size: extent / side as f32,
+            columns: side,
+            rows: side,
+            cells: vec![Vec::new(); side * side],
+        };
+
+        for t in 0..mesh.triangle_count() {
+            let corners: Vec<glm::Vec3> = mesh.triangle(t).iter().map(|&i| mesh.position(i)).collect();
+            let low = corners.iter().fold(glm::vec2(f32::INFINITY, f32::INFINITY), |m, p| glm::min2(&m, &glm::vec2(p.x, p.z)));
+            let high = corners.iter().fold(glm::vec2(f32::NEG_INFINITY, f32::NEG_INFINITY), |m, p| glm::max2(&m, &glm::vec2(p.x, p.z)));
+            let (first_column, first_row) = grid.cell_of(low);
+            let (last_column, last_row) = grid.cell_of(high);
+            for row in first_row..=last_row {
+                for column in first_column..=last_column {
+                    grid.cells[row * grid.columns + column].push(t as u32);
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell_of(&self, p: glm::Vec2) -> (usize, usize) {
+        let cell = (p - self.min).component_div(&self.cell_size);
+        (
+            (cell.x.max(0.0) as usize).min(self.columns - 1),
+            (cell.y.max(0.0) as usize).min(self.rows - 1),
+        )
+    }
+
+    fn triangles_at(&self, x: f32, z: f32) -> &[u32] {
+        let p = glm::vec2(x, z);
+        let outside = p.x < self.min.x || p.y < self.min.y
+            || p.x > self.min.x + self.cell_size.x * self.columns as f32
+            || p.y > self.min.y + self.cell_size.y * self.rows as f32;
+        if outside { return &[] }
+        let (column, row) = self.cell_of(p);
+        &self.cells[row * self.columns + column]
+    }
+}
+
+/// A terrain mesh that can be asked for the ground height and normal under any point.
+/// The terrain node sits at the origin of the scene, so its model space is world space.
+pub struct Terrain {
+    pub mesh: Mesh,
+    grid: TriangleGrid,
+}
+
+impl Terrain {
+    pub fn new(mesh: Mesh) -> Self {
+        let grid = TriangleGrid::new(&mesh);
+        Terrain { mesh, grid }
+    }
+
+    /// Projects the UVs straight down, repeating every `tile_size` in x and z, for detail
+    /// textures like the normal map. The tangents are generated again to match.
+    pub fn tile_uvs(&mut self, tile_size: f32) {
+        let mesh = &mut self.mesh;
+        mesh.uvs = mesh.vertices.chunks_exact(3).flat_map(|p| [p[0] / tile_size, p[2] / tile_size]).collect();
+        mesh.generate_tangents();
+        // Tangent generation can split vertices, which the grid refers to
+        self.grid = TriangleGrid::new(&self.mesh);
+    }
+
+    pub fn load(path: &str) -> Self {
+        println!("Loading terrain model...");
+        let before = std::time::Instant::now();
+        if let Some(mesh) = mesh_cache::load(path, "terrain") {
+            println!("Done in {:.3}ms, from the mesh cache.", before.elapsed().as_micros() as f32 / 1e3);
+            return Terrain::new(mesh);
+        }
+        let (models, _materials) = tobj::load_obj(path, true).expect("Failed to load terrain model");
+        let after = std::time::Instant::now();
+        println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
+        if models.len() != 1 { panic!("Please use a model with a single mesh") }
+
+        let terrain = models[0].to_owned();
+        println!("Loaded {} with {} points and {} triangles.", terrain.name, terrain.mesh.positions.len() /3, terrain.mesh.indices.len() / 3);
+
+        let (mesh, stats) = Mesh::from(terrain.mesh, [1.0, 1.0, 1.0, 1.0]);
+        if let Some(stats) = stats {
+            println!("{}: {}", terrain.name, stats);
+        }
+        mesh_cache::store(path, "terrain", &mesh);
+        Terrain::new(mesh)
+    }
+
+    // Finds the highest triangle straight above or below (x, z), and the barycentric
+    // coordinates of the point within it
+    fn surface_at(&self, x: f32, z: f32) -> Option<(usize, glm::Vec3, f32)> {
+        let p = glm::vec2(x, z);
+        let mut best: Option<(usize, glm::Vec3, f32)> = None;
+        for &t in self.grid.triangles_at(x, z) {
+            let [a, b, c] = self.mesh.triangle(t as usize);
+            let (pa, pb, pc) = (self.mesh.position(a), self.mesh.position(b), self.mesh.position(c));
+            let (e1, e2, d) = (glm::vec2(pb.x - pa.x, pb.z - pa.z), glm::vec2(pc.x - pa.x, pc.z - pa.z), p - glm::vec2(pa.x, pa.z));
+            let denominator = e1.x * e2.y - e2.x * e1.y;
+            if denominator.abs() <= f32::EPSILON { continue }
+            let v = (d.x * e2.y - e2.x * d.y) / denominator;
+            let w = (e1.x * d.y - d.x * e1.y) / denominator;
+            let u = 1.0 - v - w;
+            // A little slack so points exactly on shared edges don't fall through the cracks
+            let slack = -1e-5;
+            if u < slack || v < slack || w < slack { continue }
+            let height = u * pa.y + v * pb.y + w * pc.y;
+            if best.is_none_or(|(_, _, h)| height > h) {
+                best = Some((t as usize, glm::vec3(u, v, w), height));
+            }
+        }
+        best
+    }
+
+    /// Height of the ground at (x, z), or None outside the terrain
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.surface_at(x, z).map(|(_, _, height)| height)
+    }
+
+    /// Interpolated ground normal at (x, z), or None outside the terrain
+    #[allow(dead_code)]
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<glm::Vec3> {
+        let (t, barycentric, _) = self.surface_at(x, z)?;
+        let [a, b, c] = self.mesh.triangle(t);
+        let normal = if self.mesh.normals.len() == self.mesh.vertices.len() {
+            self.mesh.normal(a) * barycentric.x + self.mesh.normal(b) * barycentric.y + self.mesh.normal(c) * barycentric.z
+        } else {
+            self.mesh.face_cross(t)
+        };
+        Some(glm::normalize(&normal))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainSettings {
+    // Number of vertices along x and z
+    pub resolution: (usize, usize),
+    // World space extent of the terrain, y is the height of a heightmap value of 1
+    pub size: glm::Vec3,
+    pub color: [f32; 4],
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        TerrainSettings { resolution: (256, 256), size: glm::vec3(1000.0, 80.0, 1000.0), color: [1.0, 1.0, 1.0, 1.0] }
+    }
+}
+
+impl Terrain {
+    /// Builds a gridded terrain centered at the origin from a heightmap. Normals come
+    /// from central differences of the heightmap, so they are smooth across the whole grid.
+    pub fn generate(heightmap: &Heightmap, settings: &TerrainSettings) -> Terrain {
+        let (columns, rows) = (settings.resolution.0.max(2), settings.resolution.1.max(2));
+        let size = settings.size;
+        let (du, dv) = (1.0 / (columns - 1) as f32, 1.0 / (rows - 1) as f32);
+        let height = |u: f32, v: f32| heightmap.sample(u, v) * size.y;
+
+        let mut mesh = Mesh::empty();
+        for i in 0..rows {
+            let v = i as f32 * dv;
+            for j in 0..columns {
+                let u = j as f32 * du;
+                let position = glm::vec3((u - 0.5) * size.x, height(u, v), (0.5 - v) * size.z);
+                let slope_x = (height(u + du, v) - height(u - du, v)) / (2.0 * du * size.x);
+                // v runs along -z, so the z slope has the opposite sign
+                let slope_z = -(height(u, v + dv) - height(u, v - dv)) / (2.0 * dv * size.z);
+                let normal = glm::normalize(&glm::vec3(-slope_x, 1.0, -slope_z));
+                mesh.push_vertex(position, normal, glm::vec2(u, v));
+            }
+        }
+        mesh.push_grid(0, (rows - 1) as u32, (columns - 1) as u32);
+        Terrain::new(mesh.finish(settings.color))
+    }
+}
+
+/// A seamlessly repeating normal map of small bumps and pits, for detail on the lunar surface
+/// up close. In tangent space and encoded in [0, 1], like normal map images.
+pub fn detail_normal_map(size: usize, seed: u32) -> image::RgbaImage {
+    let n = size.max(4) as i32;
+    // Heights in texels, so the slopes below come out in the right proportion
+    let mut heights = vec![0.0; (n * n) as usize];
+    for y in 0..n {
+        for x in 0..n {
+            let (mut frequency, mut amplitude, mut height) = (4, 3.0, 0.0);
+            for octave in 0..4 {
+                let (u, v) = (x as f32 / n as f32 * frequency as f32, y as f32 / n as f32 * frequency as f32);
+                height += amplitude * periodic_gradient_noise(u, v, frequency, seed.wrapping_add(octave));
+                frequency *= 2;
+                amplitude *= 0.5;
+            }
+            heights[(y * n + x) as usize] = height;
+        }
+    }
+    // Pits, wrapping around the edges like the noise
+    let mut random = Random::new(seed);
+    for _ in 0..40 {
+        let (cx, cy) = (random.next_f32() * n as f32, random.next_f32() * n as f32);
+        let radius = n as f32 * (0.01 + 0.04 * random.next_f32().powi(2));
+        let reach = (2.0 * radius).ceil() as i32;
+        for y in cy as i32 - reach..cy as i32 + reach {
+            for x in cx as i32 - reach..cx as i32 + reach {
+                let d = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt() / radius;
+                let bowl = if d < 1.0 { (d * d - 1.0) * 0.25 * radius } else { 0.0 };
+                let rim = 0.1 * radius * (-((d - 1.0) / 0.3).powi(2)).exp();
+                heights[(y.rem_euclid(n) * n + x.rem_euclid(n)) as usize] += bowl + rim;
+            }
+        }
+    }
+
+    let at = |x: i32, y: i32| heights[(y.rem_euclid(n) * n + x.rem_euclid(n)) as usize];
+    image::RgbaImage::from_fn(n as u32, n as u32, |x, y| {
+        let (x, y) = (x as i32, y as i32);
+        // Rows go along v, which the bitangent points along
+        let slope = glm::vec2(at(x + 1, y) - at(x - 1, y), at(x, y + 1) - at(x, y - 1)) / 2.0;
+        let normal = glm::normalize(&glm::vec3(-slope.x, -slope.y, 1.0));
+        let encode = |c: f32| ((c * 0.5 + 0.5) * 255.0).round() as u8;
+        image::Rgba([encode(normal.x), encode(normal.y), encode(normal.z), 255])
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkSettings {
+    pub chunks_per_side: usize,
+    // Number of detail levels per chunk, including the full detail one
+    pub lod_levels: usize,
+    // Chunks closer than this are drawn at full detail, every doubling of the distance drops a level
+    pub lod_distance: f32,
+    // How far the skirts hanging from the chunk borders reach down, to hide cracks between levels
+    pub skirt_depth: f32,
+}
+
+impl Default for ChunkSettings {
+    fn default() -> Self {
+        ChunkSettings { chunks_per_side: 8, lod_levels: 4, lod_distance: 200.0, skirt_depth: 5.0 }
+    }
+}
+
+pub struct TerrainChunk {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+    // Most detailed first
+    pub lods: Vec<Mesh>,
+}
+
+/// A terrain mesh cut into a grid of chunks, each with several levels of detail, so only
+/// the chunks close to the camera have to be drawn in full.
+pub struct ChunkedTerrain {
+    pub chunks: Vec<TerrainChunk>,
+    pub settings: ChunkSettings,
+}
+
+// Simplifies a mesh by snapping all vertices within a grid cell to the one closest to the
+// cell center. Pinned vertices, the ones on borders with other chunks, stay where they are and
+// nothing snaps to them, so neighbouring chunks still meet at every level.
+fn cluster_vertices(mesh: &Mesh, pinned: &[bool], origin: glm::Vec2, extent: glm::Vec2, cells: usize) -> Mesh {
+    let cell_size = extent / cells as f32;
+    let cell_of = |i: usize| {
+        let p = mesh.position(i);
+        let cell = (glm::vec2(p.x, p.z) - origin).component_div(&cell_size);
+        let (column, row) = ((cell.x.max(0.0) as usize).min(cells - 1), (cell.y.max(0.0) as usize).min(cells - 1));
+        let center = origin + glm::vec2(column as f32 + 0.5, row as f32 + 0.5).component_mul(&cell_size);
+        (row * cells + column, glm::distance2(&glm::vec2(p.x, p.z), &center))
+    };
+
+    let mut representatives: Vec<Option<(usize, f32)>> = vec![None; cells * cells];
+    for i in (0..mesh.vertex_count()).filter(|&i| !pinned[i]) {
+        let (cell, distance) = cell_of(i);
+        if representatives[cell].is_none_or(|(_, best)| distance < best) {
+            representatives[cell] = Some((i, distance));
+        }
+    }
+
+    let snap = |i: usize| if pinned[i] { i } else { representatives[cell_of(i).0].expect("vertex without a cell").0 };
+    let mut triangles = Vec::new();
+    for t in 0..mesh.triangle_count() {
+        let snapped: Vec<u32> = mesh.triangle(t).iter().map(|&i| snap(i) as u32).collect();
+        if snapped[0] != snapped[1] && snapped[1] != snapped[2] && snapped[0] != snapped[2] {
+            triangles.extend_from_slice(&snapped);
+        }
+    }
+    mesh.with_indices(&triangles)
+}
+
+// Hangs a vertical strip below every border edge of the mesh, facing outwards
+fn add_skirts(mesh: &mut Mesh, depth: f32) {
+    let mut edge_uses: HashMap<(u32, u32), usize> = HashMap::new();
+    for t in 0..mesh.triangle_count() {
+        for k in 0..3 {
+            let (a, b) = (mesh.indices[3*t + k], mesh.indices[3*t + (k + 1) % 3]);
+            *edge_uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+
+    let mut lowered: HashMap<u32, u32> = HashMap::new();
+    let mut sources: Vec<u32> = (0..mesh.vertex_count() as u32).collect();
+    let mut skirt = Vec::new();
+    for t in 0..mesh.triangle_count() {
+        for k in 0..3 {
+            let (a, b) = (mesh.indices[3*t + k], mesh.indices[3*t + (k + 1) % 3]);
+            if edge_uses[&(a.min(b), a.max(b))] != 1 { continue }
+            let mut lower = |i: u32| *lowered.entry(i).or_insert_with(|| {
+                sources.push(i);
+                (sources.len() - 1) as u32
+            });
+            let (a_low, b_low) = (lower(a), lower(b));
+            skirt.extend_from_slice(&[a, a_low, b_low, a, b_low, b]);
+        }
+    }
+
+    let original_count = mesh.vertex_count();
+    mesh.gather_vertices(&sources);
+    for i in original_count..mesh.vertex_count() {
+        mesh.vertices[3*i + 1] -= depth;
+    }
+    mesh.indices.extend_from_slice(&skirt);
+    mesh.index_count = mesh.indices.len() as i32;
+}
+
+impl ChunkedTerrain {
+    /// Cuts a terrain mesh into chunks by triangle center in the xz plane, and builds the
+    /// lower detail levels of every chunk
+    pub fn new(mesh: &Mesh, settings: &ChunkSettings) -> Self {
+        let side = settings.chunks_per_side.max(1);
+        let mut min = glm::vec2(f32::INFINITY, f32::INFINITY);
+        let mut max = glm::vec2(f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for p in mesh.vertices.chunks_exact(3) {
+            min = glm::min2(&min, &glm::vec2(p[0], p[2]));
+            max = glm::max2(&max, &glm::vec2(p[0], p[2]));
+        }
+        let chunk_size = glm::max2(&(max - min), &glm::vec2(1e-6, 1e-6)) / side as f32;
+
+        let mut triangles_in: Vec<Vec<usize>> = vec![Vec::new(); side * side];
+        for t in 0..mesh.triangle_count() {
+            let center = mesh.triangle(t).iter().fold(glm::zero::<glm::Vec3>(), |sum, &i| sum + mesh.position(i)) / 3.0;
+            let cell = (glm::vec2(center.x, center.z) - min).component_div(&chunk_size);
+            let (column, row) = ((cell.x.max(0.0) as usize).min(side - 1), (cell.y.max(0.0) as usize).min(side - 1));
+            triangles_in[row * side + column].push(t);
+        }
+
+        // Which chunk uses each position, or None if several do. Compared by position rather
+        // than index, as vertices can be split along a border.
+        let key = |p: glm::Vec3| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+        let mut chunk_at: HashMap<[u32; 3], Option<usize>> = HashMap::new();
+        for (chunk, triangles) in triangles_in.iter().enumerate() {
+            for &t in triangles {
+                for &i in &mesh.triangle(t) {
+                    let user = chunk_at.entry(key(mesh.position(i))).or_insert(Some(chunk));
+                    if *user != Some(chunk) { *user = None }
+                }
+            }
+        }
+        // Cells per chunk side matching the full detail vertex spacing of the whole terrain,
+        // halved for every level, so all chunks share one grid
+        let base_cells = ((mesh.vertex_count() as f32).sqrt() / side as f32).round().max(1.0) as usize;
+
+        let mut chunks = Vec::new();
+        for (i, triangles) in triangles_in.iter().enumerate() {
+            if triangles.is_empty() { continue }
+            let origin = min + glm::vec2((i % side) as f32, (i / side) as f32).component_mul(&chunk_size);
+            let full = mesh.submesh(triangles);
+            let pinned: Vec<bool> = (0..full.vertex_count()).map(|v| chunk_at[&key(full.position(v))].is_none()).collect();
+
+            let mut lods = Vec::with_capacity(settings.lod_levels.max(1));
+            for level in 0..settings.lod_levels.max(1) {
+                let mut lod = if level == 0 {
+                    full.clone()
+                } else {
+                    cluster_vertices(&full, &pinned, origin, chunk_size, (base_cells >> level).max(1))
+                };
+                add_skirts(&mut lod, settings.skirt_depth);
+                lods.push(lod);
+            }
+
+            let mut low = glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+            let mut high = glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+            for v in 0..full.vertex_count() {
+                low = glm::min2(&low, &full.position(v));
+                high = glm::max2(&high, &full.position(v));
+            }
+            chunks.push(TerrainChunk { min: low, max: high, lods });
+        }
+
+        ChunkedTerrain { chunks, settings: *settings }
+    }
+
+    /// Level of detail to draw a chunk at, from the camera's distance to its bounding box
+    pub fn lod_for(&self, chunk: &TerrainChunk, camera_position: &glm::Vec3) -> usize {
+        let outside = glm::max2(&(chunk.min - camera_position), &(camera_position - chunk.max));
+        let distance = glm::length(&glm::max2(&outside, &glm::zero()));
+        lod_level(distance, self.settings.lod_distance, chunk.lods.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A heightmap whose height is the column plus ten times the row
+    fn ramp(width: usize, depth: usize) -> Heightmap {
+        let heights = (0..width * depth).map(|i| ((i % width) + 10 * (i / width)) as f32).collect();
+        Heightmap { width, depth, heights }
+    }
+
+    #[test]
+    fn heightmap_from_image_keeps_16_bit_precision() {
+        let path = std::env::temp_dir().join(format!("gloom-heightmap-{}.png", std::process::id()));
+        let image = image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::from_fn(3, 2, |x, y| image::Luma([(x * 1000 + y * 20000 + 1) as u16]));
+        image.save(&path).expect("failed to write the heightmap");
+        let heightmap = Heightmap::from_image(path.to_str().expect("temporary path isn't UTF-8"));
+        std::fs::remove_file(&path).ok();
+        assert_eq!((heightmap.width, heightmap.depth), (3, 2));
+        // Row 1, column 2, which an 8 bit image couldn't tell from its neighbours
+        assert_eq!(heightmap.get(2, 1), 22001.0 / u16::MAX as f32);
+        assert_eq!(heightmap.get(0, 0), 1.0 / u16::MAX as f32);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty")]
+    fn empty_heightmaps_are_rejected() {
+        Heightmap::noise(0, 0, 1, &NoiseSettings::default());
+    }
+
+    #[test]
+    fn noise_is_normalized_and_repeatable() {
+        for &kind in &[NoiseKind::Fbm, NoiseKind::Ridged] {
+            let settings = NoiseSettings { kind, ..Default::default() };
+            let heightmap = Heightmap::noise(32, 16, 7, &settings);
+            assert_eq!(heightmap.heights.len(), 32 * 16);
+            let min = heightmap.heights.iter().cloned().fold(f32::INFINITY, f32::min);
+            let max = heightmap.heights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            assert_eq!((min, max), (0.0, 1.0));
+            assert_eq!(heightmap.heights, Heightmap::noise(32, 16, 7, &settings).heights);
+            assert_ne!(heightmap.heights, Heightmap::noise(32, 16, 8, &settings).heights);
+        }
+    }
+
+    #[test]
+    fn craters_dig_bowls_and_raise_rims() {
+        let flat = Heightmap { width: 64, depth: 64, heights: vec![0.5; 64 * 64] };
+        let mut cratered = flat.clone();
+        // Big enough that some reach over the edges
+        cratered.stamp_craters(3, &CraterSettings { count: 20, min_radius: 0.05, max_radius: 0.3, depth: 0.6 });
+        assert!(cratered.heights.iter().any(|&h| h < 0.5));
+        assert!(cratered.heights.iter().any(|&h| h > 0.5));
+        // Rims are a quarter as high as the bowls are deep
+        let deepest = cratered.heights.iter().cloned().fold(f32::INFINITY, f32::min);
+        let highest = cratered.heights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        assert!(0.5 - deepest > highest - 0.5);
+    }
+
+    #[test]
+    fn sample_hits_the_corners_and_clamps_outside() {
+        let heightmap = ramp(4, 3);
+        // v = 1 is row 0, the far edge
+        assert_eq!(heightmap.sample(0.0, 1.0), heightmap.get(0, 0));
+        assert_eq!(heightmap.sample(1.0, 1.0), heightmap.get(3, 0));
+        assert_eq!(heightmap.sample(0.0, 0.0), heightmap.get(0, 2));
+        assert_eq!(heightmap.sample(1.0, 0.0), heightmap.get(3, 2));
+        assert_eq!(heightmap.sample(-0.5, 2.0), heightmap.get(0, 0));
+        assert_eq!(heightmap.sample(1.5, -1.0), heightmap.get(3, 2));
+    }
+
+    #[test]
+    fn sample_interpolates_bilinearly() {
+        let heightmap = ramp(4, 3);
+        // Halfway between columns 1 and 2 on the middle row
+        assert!((heightmap.sample(0.5, 0.5) - 11.5).abs() < 1e-5);
+        // A quarter of the way from row 2 to row 1 along the last column
+        assert!((heightmap.sample(1.0, 0.125) - 20.5).abs() < 1e-5);
+        let single = Heightmap { width: 1, depth: 1, heights: vec![0.25] };
+        assert_eq!(single.sample(0.3, 0.9), 0.25);
+    }
+
+    // Positions along x = 0 in a chunk, which is where the two western and two eastern chunks
+    // of a centered terrain meet
+    fn positions_on_middle_border(mesh: &Mesh) -> Vec<[u32; 3]> {
+        let mut positions: Vec<[u32; 3]> = (0..mesh.vertex_count())
+            .map(|v| mesh.position(v))
+            .filter(|p| p.x.abs() < 1e-4)
+            .map(|p| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()])
+            .collect();
+        positions.sort();
+        positions.dedup();
+        positions
+    }
+
+    // A 10 by 10 terrain with a vertex every unit, its heights given along x by `height_of_u`
+    fn terrain_from(height_of_u: impl Fn(f32) -> f32) -> Terrain {
+        let heights = (0..11 * 11).map(|i| height_of_u((i % 11) as f32 / 10.0)).collect();
+        let heightmap = Heightmap { width: 11, depth: 11, heights };
+        let settings = TerrainSettings { resolution: (11, 11), size: glm::vec3(10.0, 4.0, 10.0), color: [1.0; 4] };
+        Terrain::generate(&heightmap, &settings)
+    }
+
+    fn assert_close(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::distance(&actual, &expected) < 1e-5, "got {:?}, expected {:?}", actual, expected);
+    }
+
+    #[test]
+    fn height_and_normal_of_flat_ground() {
+        let terrain = terrain_from(|_| 0.5);
+        for &(x, z) in &[(0.0, 0.0), (1.3, -0.7), (-4.9, 4.9), (3.5, 2.25)] {
+            let height = terrain.height_at(x, z).expect("inside the terrain");
+            assert!((height - 2.0).abs() < 1e-5, "height {} at ({}, {})", height, x, z);
+            assert_close(terrain.normal_at(x, z).expect("inside the terrain"), glm::vec3(0.0, 1.0, 0.0));
+        }
+        assert_eq!(terrain.height_at(5.5, 0.0), None);
+        assert_eq!(terrain.normal_at(0.0, -6.0), None);
+    }
+
+    #[test]
+    fn height_and_normal_of_sloped_ground() {
+        // Rises 4 over the 10 units from x = -5 to 5
+        let terrain = terrain_from(|u| u);
+        let slope_normal = glm::normalize(&glm::vec3(-0.4, 1.0, 0.0));
+        // Away from the edges, where the central differences are one sided
+        for &(x, z) in &[(0.0, 0.0), (1.3, -0.7), (-2.6, 3.1)] {
+            let height = terrain.height_at(x, z).expect("inside the terrain");
+            assert!((height - 0.4 * (x + 5.0)).abs() < 1e-5, "height {} at x = {}", height, x);
+            assert_close(terrain.normal_at(x, z).expect("inside the terrain"), slope_normal);
+        }
+    }
+
+    #[test]
+    fn adjacent_chunks_share_border_positions() {
+        let mut mesh = Mesh::plane_grid(16.0, 16.0, 32, 32, [1.0; 4]);
+        for p in mesh.vertices.chunks_exact_mut(3) {
+            p[1] = (0.7 * p[0]).sin() + (0.5 * p[2]).cos();
+        }
+        let settings = ChunkSettings { chunks_per_side: 2, lod_levels: 4, lod_distance: 100.0, skirt_depth: 1.0 };
+        let chunked = ChunkedTerrain::new(&mesh, &settings);
+        assert_eq!(chunked.chunks.len(), 4);
+        // The first two chunks are side by side along x
+        let (west, east) = (&chunked.chunks[0], &chunked.chunks[1]);
+        for level in 0..settings.lod_levels {
+            let border = positions_on_middle_border(&west.lods[level]);
+            assert!(!border.is_empty());
+            assert_eq!(border, positions_on_middle_border(&east.lods[level]), "level {}", level);
+        }
+        // The lower levels still get simpler
+        assert!(west.lods[3].triangle_count() < west.lods[0].triangle_count() / 4);
+    }
+}
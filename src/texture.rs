@@ -0,0 +1,46 @@
+use std::os::raw::c_void;
+
+// Decodes an image file to RGBA. Doesn't touch GL, so it can run on an asset worker thread.
+pub fn decode_image(path: &str) -> image::RgbaImage {
+    image::open(path)
+        .unwrap_or_else(|e| panic!("Failed to load texture {}: {}", path, e))
+        .to_rgba8()
+}
+
+// Uploads an already decoded image of colors, like a base color or emissive texture, which
+// are stored sRGB encoded. Sampling it gives linear colors.
+pub unsafe fn upload_color_texture(image: &image::RgbaImage) -> u32 {
+    upload_texture_as(image, gl::SRGB8_ALPHA8)
+}
+
+// Uploads an already decoded image into a mipmapped, repeating RGBA texture and returns its id.
+// Normal maps are stored as-is, the shader is responsible for remapping them from [0, 1] to [-1, 1].
+pub unsafe fn upload_texture(image: &image::RgbaImage) -> u32 {
+    upload_texture_as(image, gl::RGBA8)
+}
+
+// Uploads an already decoded image, stored on the GPU in `internal_format`
+pub unsafe fn upload_texture_as(image: &image::RgbaImage, internal_format: gl::types::GLenum) -> u32 {
+    let (width, height) = image.dimensions();
+
+    let mut texture_id: u32 = 0;
+    gl::GenTextures(1, &mut texture_id);
+    gl::BindTexture(gl::TEXTURE_2D, texture_id);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+    gl::TexImage2D(
+        gl::TEXTURE_2D,
+        0,
+        internal_format as i32,
+        width as i32,
+        height as i32,
+        0,
+        gl::RGBA,
+        gl::UNSIGNED_BYTE,
+        image.as_ptr() as *const c_void,
+    );
+    gl::GenerateMipmap(gl::TEXTURE_2D);
+    texture_id
+}
@@ -30,3 +30,18 @@ pub extern "system" fn debug_callback(
     }
 }
 
+// Small xorshift generator, for placing craters and stars
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u32) -> Self {
+        Random(0x9e37_79b9_7f4a_7c15 ^ seed as u64)
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
@@ -0,0 +1,284 @@
+use std::ops::Range;
+use std::os::raw::c_void;
+
+use crate::mesh::Mesh;
+use crate::shader::{Shader, ShaderAttribute};
+
+/// Which mesh attribute a vertex attribute is filled from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Semantic {
+    Position,
+    Color,
+    Normal,
+    Uv,
+    Tangent,
+}
+
+impl Semantic {
+    // Values per vertex in the mesh, and what to use for meshes that don't have the attribute
+    fn source<'a>(&self, mesh: &'a Mesh) -> (&'a [f32], usize, [f32; 4]) {
+        match self {
+            Semantic::Position => (&mesh.vertices, 3, [0.0, 0.0, 0.0, 1.0]),
+            Semantic::Color => (&mesh.colors, 4, [1.0, 1.0, 1.0, 1.0]),
+            Semantic::Normal => (&mesh.normals, 3, [0.0, 1.0, 0.0, 0.0]),
+            Semantic::Uv => (&mesh.uvs, 2, [0.0, 0.0, 0.0, 0.0]),
+            Semantic::Tangent => (&mesh.tangents, 4, [1.0, 0.0, 0.0, 1.0]),
+        }
+    }
+}
+
+/// How each component is stored in the vertex buffer
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttributeType {
+    Float,
+    Byte,
+    UnsignedByte,
+    Short,
+    UnsignedShort,
+}
+
+impl AttributeType {
+    pub fn size(&self) -> usize {
+        match self {
+            AttributeType::Float => 4,
+            AttributeType::Byte | AttributeType::UnsignedByte => 1,
+            AttributeType::Short | AttributeType::UnsignedShort => 2,
+        }
+    }
+
+    pub fn gl_type(&self) -> gl::types::GLenum {
+        match self {
+            AttributeType::Float => gl::FLOAT,
+            AttributeType::Byte => gl::BYTE,
+            AttributeType::UnsignedByte => gl::UNSIGNED_BYTE,
+            AttributeType::Short => gl::SHORT,
+            AttributeType::UnsignedShort => gl::UNSIGNED_SHORT,
+        }
+    }
+
+    // Normalized integers map [0, 1] (or [-1, 1] when signed) to their whole range
+    fn push(&self, value: f32, normalized: bool, bytes: &mut Vec<u8>) {
+        let scaled = |max: f32, min: f32| (if normalized { value * max } else { value }).round().clamp(min, max);
+        match self {
+            AttributeType::Float => bytes.extend_from_slice(&value.to_le_bytes()),
+            AttributeType::Byte => bytes.push(scaled(i8::MAX as f32, i8::MIN as f32) as i8 as u8),
+            AttributeType::UnsignedByte => bytes.push(scaled(u8::MAX as f32, 0.0) as u8),
+            AttributeType::Short => bytes.extend_from_slice(&(scaled(i16::MAX as f32, i16::MIN as f32) as i16).to_le_bytes()),
+            AttributeType::UnsignedShort => bytes.extend_from_slice(&(scaled(u16::MAX as f32, 0.0) as u16).to_le_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VertexAttribute {
+    // Name of the matching input in the vertex shader
+    pub name: String,
+    pub semantic: Semantic,
+    pub location: u32,
+    pub components: usize,
+    pub attribute_type: AttributeType,
+    pub normalized: bool,
+}
+
+impl VertexAttribute {
+    pub fn new(name: &str, semantic: Semantic, location: u32, components: usize) -> Self {
+        VertexAttribute {
+            name: name.to_string(),
+            semantic,
+            location,
+            components,
+            attribute_type: AttributeType::Float,
+            normalized: false,
+        }
+    }
+
+    /// Stores the attribute with a smaller type, e.g. colors as normalized unsigned bytes
+    pub fn stored_as(mut self, attribute_type: AttributeType, normalized: bool) -> Self {
+        self.attribute_type = attribute_type;
+        self.normalized = normalized;
+        self
+    }
+
+    pub fn byte_size(&self) -> usize {
+        self.components * self.attribute_type.size()
+    }
+}
+
+/// Interleaved puts all the attributes of a vertex next to each other in one buffer,
+/// planar gives every attribute a buffer of its own
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferArrangement {
+    Interleaved,
+    Planar,
+}
+
+/// One vertex buffer's worth of data, and where each attribute is in it
+pub struct VertexBufferData {
+    pub bytes: Vec<u8>,
+    pub stride: usize,
+    // (index into the layout's attributes, byte offset of the first component)
+    pub attributes: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VertexLayout {
+    pub attributes: Vec<VertexAttribute>,
+    pub arrangement: BufferArrangement,
+    // Shader inputs the layout deliberately leaves out, they read GL's constant (0, 0, 0, 1)
+    pub unused: Vec<String>,
+}
+
+impl VertexLayout {
+    pub fn new(arrangement: BufferArrangement) -> Self {
+        VertexLayout { attributes: vec![], arrangement, unused: vec![] }
+    }
+
+    pub fn with(mut self, attribute: VertexAttribute) -> Self {
+        self.attributes.push(attribute);
+        self
+    }
+
+    /// The layout shaders/simple.vert expects
+    pub fn standard() -> Self {
+        VertexLayout::new(BufferArrangement::Interleaved)
+            .with(VertexAttribute::new("VertexPosition", Semantic::Position, 0, 3))
+            .with(VertexAttribute::new("vertex_color", Semantic::Color, 1, 4))
+            .with(VertexAttribute::new("vertex_normal", Semantic::Normal, 2, 3))
+            .with(VertexAttribute::new("vertex_uv", Semantic::Uv, 3, 2))
+            .with(VertexAttribute::new("vertex_tangent", Semantic::Tangent, 4, 4))
+    }
+
+    pub fn without(mut self, shader_input: &str) -> Self {
+        self.unused.push(shader_input.to_string());
+        self
+    }
+
+    /// Just positions and byte colors, a quarter of the standard layout's size. Points are
+    /// drawn unlit, so they don't need anything else.
+    pub fn point_cloud() -> Self {
+        VertexLayout::new(BufferArrangement::Interleaved)
+            .with(VertexAttribute::new("VertexPosition", Semantic::Position, 0, 3))
+            .with(VertexAttribute::new("vertex_color", Semantic::Color, 1, 4).stored_as(AttributeType::UnsignedByte, true))
+            .without("vertex_normal")
+            .without("vertex_uv")
+            .without("vertex_tangent")
+    }
+
+    pub fn stride(&self) -> usize {
+        self.attributes.iter().map(|a| a.byte_size()).sum()
+    }
+
+    // Appends one attribute of one vertex, padding with the defaults if the mesh lacks it
+    fn push_vertex_attribute(attribute: &VertexAttribute, mesh: &Mesh, vertex: usize, bytes: &mut Vec<u8>) {
+        let (values, size, default) = attribute.semantic.source(mesh);
+        let present = values.len() == mesh.vertex_count() * size;
+        for component in 0..attribute.components {
+            let value = if present && component < size {
+                values[vertex * size + component]
+            } else {
+                default[component.min(3)]
+            };
+            attribute.attribute_type.push(value, attribute.normalized, bytes);
+        }
+    }
+
+    /// Packs the mesh's vertices into buffers following the layout
+    pub fn build(&self, mesh: &Mesh) -> Vec<VertexBufferData> {
+        self.build_range(mesh, 0..mesh.vertex_count())
+    }
+
+    /// Packs only some of the vertices, for updating part of a buffer
+    pub fn build_range(&self, mesh: &Mesh, vertices: Range<usize>) -> Vec<VertexBufferData> {
+        match self.arrangement {
+            BufferArrangement::Interleaved => {
+                let stride = self.stride();
+                let mut bytes = Vec::with_capacity(stride * vertices.len());
+                for vertex in vertices {
+                    for attribute in &self.attributes {
+                        VertexLayout::push_vertex_attribute(attribute, mesh, vertex, &mut bytes);
+                    }
+                }
+                let mut offset = 0;
+                let attributes = self.attributes.iter().enumerate().map(|(i, attribute)| {
+                    offset += attribute.byte_size();
+                    (i, offset - attribute.byte_size())
+                }).collect();
+                vec![VertexBufferData { bytes, stride, attributes }]
+            }
+            BufferArrangement::Planar => self.attributes.iter().enumerate().map(|(i, attribute)| {
+                let mut bytes = Vec::with_capacity(attribute.byte_size() * vertices.len());
+                for vertex in vertices.clone() {
+                    VertexLayout::push_vertex_attribute(attribute, mesh, vertex, &mut bytes);
+                }
+                VertexBufferData { bytes, stride: attribute.byte_size(), attributes: vec![(i, 0)] }
+            }).collect(),
+        }
+    }
+
+    /// Uploads the buffers and points the attributes of the currently bound VAO at them.
+    /// Returns the ids of the vertex buffers.
+    pub unsafe fn upload(&self, mesh: &Mesh, usage: gl::types::GLenum) -> Vec<u32> {
+        self.build(mesh).iter().map(|buffer| {
+            let mut vbo: u32 = 0;
+            gl::GenBuffers(1, &mut vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                buffer.bytes.len() as isize,
+                buffer.bytes.as_ptr() as *const c_void,
+                usage,
+            );
+            for &(i, offset) in &buffer.attributes {
+                let attribute = &self.attributes[i];
+                gl::EnableVertexAttribArray(attribute.location);
+                gl::VertexAttribPointer(
+                    attribute.location,
+                    attribute.components as i32,
+                    attribute.attribute_type.gl_type(),
+                    if attribute.normalized { gl::TRUE } else { gl::FALSE },
+                    buffer.stride as i32,
+                    offset as *const c_void,
+                );
+            }
+            vbo
+        }).collect()
+    }
+
+    /// Checks the layout against the vertex inputs the linked shader actually uses. Inputs
+    /// the layout doesn't provide, or provides at another location, are errors. Fewer
+    /// components than the shader reads is fine, GL fills in the rest, so that is just a warning.
+    pub fn validate(&self, shader_attributes: &[ShaderAttribute]) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for input in shader_attributes {
+            // Built in inputs like gl_VertexID are reported too, but need no buffer
+            if input.name.starts_with("gl_") || self.unused.contains(&input.name) { continue }
+            match self.attributes.iter().find(|a| a.name == input.name) {
+                None => errors.push(format!("shader input {} is missing from the vertex layout", input.name)),
+                Some(attribute) if input.location >= 0 && attribute.location != input.location as u32 => errors.push(format!(
+                    "shader input {} is at location {}, but the vertex layout puts it at {}",
+                    input.name, input.location, attribute.location
+                )),
+                Some(attribute) if attribute.components < input.components => println!(
+                    "Warning: vertex layout gives {} {} components, the shader reads {}",
+                    input.name, attribute.components, input.components
+                ),
+                Some(_) => {}
+            }
+        }
+        for attribute in &self.attributes {
+            if !(1..=4).contains(&attribute.components) {
+                errors.push(format!("vertex attribute {} has {} components, it has to be 1 to 4", attribute.name, attribute.components));
+            }
+            if self.attributes.iter().filter(|a| a.location == attribute.location).count() > 1 {
+                errors.push(format!("vertex attribute {} shares location {} with another attribute", attribute.name, attribute.location));
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("\n")) }
+    }
+
+    pub unsafe fn validate_against(&self, shader: &Shader) -> Result<(), String> {
+        self.validate(&shader.attributes())
+    }
+}
@@ -0,0 +1,523 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::assets::{AssetManager, LoadingProgress};
+use crate::environment::{self, CubeImage, Environment, EnvironmentMaps};
+use crate::gpu_mesh::GpuMesh;
+use crate::light::{self, Light, LightBuffer, LightKind};
+use crate::material::{Material, MtlPbrMaterial, PbrMaterial};
+use crate::mesh::{Helicopter, Mesh};
+use crate::point_cloud;
+use crate::scene_graph::{draw_scene, update_node_transformations, HelicopterStruct, Node, SceneNode};
+use crate::shader::{Shader, ShaderBuilder};
+use crate::shadows::{ShadowMaps, ShadowSettings};
+use crate::simplify::ModelLods;
+use crate::skybox::{self, Skybox};
+use crate::terrain::{self, ChunkSettings, ChunkedTerrain, Heightmap, Terrain, TerrainSettings};
+use crate::texture;
+use crate::util;
+use crate::vertex_layout::VertexLayout;
+
+// Everything the demo scene is made of, shared by the window and the headless renderer
+
+const HELICOPTER_COUNT: usize = 5;
+// The course's models, which aren't part of the repository
+pub const LUNAR_SURFACE_PATH: &str = "resources/lunarsurface.obj";
+pub const HELICOPTER_PATH: &str = "resources/helicopter.obj";
+// The moon made up when there is no lunar surface model
+const GENERATED_SURFACE_RESOLUTION: usize = 256;
+const GENERATED_SURFACE_SEED: u32 = 1;
+// Fine detail on the lunar surface, generated when this file doesn't exist
+const LUNAR_NORMAL_MAP_PATH: &str = "resources/lunarsurface_normal.png";
+const DETAIL_MAP_SIZE: usize = 256;
+const DETAIL_MAP_SEED: u32 = 3;
+// How far the lunar surface's normal map reaches before it repeats
+pub const DETAIL_TILE_SIZE: f32 = 6.;
+// Which way the sunlight falls, down from the far corner of the terrain
+pub const SUN_DIRECTION: [f32; 3] = [800., -500., 600.];
+// Light that reaches everything, standing in for what bounces off the surroundings
+const AMBIENT_LIGHT: [f32; 3] = [0.1, 0.1, 0.1];
+// The starfield around the moon, over a faint glow towards the horizon. In linear color.
+const SKY_SIZE: usize = 512;
+const STAR_COUNT: usize = 6000;
+const STAR_SEED: u32 = 7;
+const SKY_ZENITH: [f32; 3] = [0.01, 0.01, 0.015];
+const SKY_HORIZON: [f32; 3] = [0.03, 0.03, 0.035];
+const SKY_GROUND: [f32; 3] = [0.02, 0.02, 0.02];
+// How many lights are drawn at most, the ones brightest around the camera are kept
+pub const DEFAULT_MAX_LIGHTS: usize = 8;
+// Vertical field of view of the camera, in radians
+pub const FIELD_OF_VIEW: f32 = PI / 2.;
+pub const NEAR_PLANE: f32 = 0.1;
+pub const FAR_PLANE: f32 = 50000.;
+// Texture unit the shadow maps are bound to, the normal maps use 0
+const SHADOW_MAP_UNIT: u32 = 1;
+
+/// Where the scene is seen from
+pub struct Camera {
+    pub view: glm::Mat4,
+    pub position: glm::Vec3,
+    // Width over height of what is drawn to
+    pub aspect: f32,
+}
+
+impl Camera {
+    /// A camera at `position` looking towards `target`
+    pub fn looking_at(position: glm::Vec3, target: glm::Vec3, aspect: f32) -> Self {
+        Camera { view: glm::look_at(&position, &target, &glm::vec3(0., 1., 0.)), position, aspect }
+    }
+
+    pub fn projection(&self) -> glm::Mat4 {
+        glm::perspective(self.aspect, FIELD_OF_VIEW, NEAR_PLANE, FAR_PLANE)
+    }
+}
+
+/// The scene as loaded from disk, before anything is uploaded
+pub struct SceneAssets {
+    pub lunar_surface: Terrain,
+    pub lunar_chunks: ChunkedTerrain,
+    pub lunar_normal_map: image::RgbaImage,
+    pub helicopter_lods: ModelLods,
+    // From the helicopter model's MTL files, one per part
+    pub helicopter_materials: Vec<Option<MtlPbrMaterial>>,
+    pub point_cloud: Option<Mesh>,
+    pub sky: CubeImage,
+    pub environment: EnvironmentMaps,
+}
+
+/// A cratered lunar surface from noise, the same every time for the same resolution
+pub fn generated_lunar_surface(resolution: usize) -> Terrain {
+    let settings = TerrainSettings { resolution: (resolution, resolution), ..Default::default() };
+    Terrain::generate(&Heightmap::lunar(resolution, GENERATED_SURFACE_SEED), &settings)
+}
+
+/// The normal map for the lunar surface's fine detail, see `DETAIL_TILE_SIZE`
+pub fn lunar_normal_map() -> image::RgbaImage {
+    if Path::new(LUNAR_NORMAL_MAP_PATH).exists() {
+        texture::decode_image(LUNAR_NORMAL_MAP_PATH)
+    } else {
+        terrain::detail_normal_map(DETAIL_MAP_SIZE, DETAIL_MAP_SEED)
+    }
+}
+
+/// The sky of the lunar scene when none is given
+pub fn starfield() -> CubeImage {
+    let mut sky = environment::gradient_sky(
+        SKY_SIZE,
+        glm::make_vec3(&SKY_ZENITH),
+        glm::make_vec3(&SKY_HORIZON),
+        glm::make_vec3(&SKY_GROUND),
+    );
+    skybox::add_stars(&mut sky, STAR_COUNT, STAR_SEED);
+    sky
+}
+
+impl SceneAssets {
+    /// Parses and processes the models on worker threads. `wait` is called over and over
+    /// until they are done, e.g. to draw a loading screen. The sky is loaded from
+    /// `skybox_path` if given, see `skybox::load`, and is a starfield otherwise. Without the
+    /// lunar surface model a surface is generated, without the helicopter model there are no
+    /// helicopters.
+    pub fn load(
+        point_cloud_path: Option<String>,
+        skybox_path: Option<PathBuf>,
+        mut wait: impl FnMut(&LoadingProgress),
+    ) -> Self {
+        let mut assets = AssetManager::new(2);
+        let terrain_asset = assets.load("lunar surface", || {
+            let lunar_surface = if Path::new(LUNAR_SURFACE_PATH).exists() {
+                Terrain::load(LUNAR_SURFACE_PATH)
+            } else {
+                println!("Warning: {} not found, generating a lunar surface instead", LUNAR_SURFACE_PATH);
+                generated_lunar_surface(GENERATED_SURFACE_RESOLUTION)
+            };
+            let mut lunar_surface = lunar_surface;
+            lunar_surface.tile_uvs(DETAIL_TILE_SIZE);
+            let lunar_chunks = ChunkedTerrain::new(&lunar_surface.mesh, &ChunkSettings::default());
+            (lunar_surface, lunar_chunks, lunar_normal_map())
+        });
+        let helicopter_asset = assets.load("helicopter", || {
+            if !Path::new(HELICOPTER_PATH).exists() {
+                println!("Warning: {} not found, drawing no helicopters", HELICOPTER_PATH);
+                return (ModelLods { parts: vec![] }, vec![]);
+            }
+            let helicopter = Helicopter::load(HELICOPTER_PATH);
+            // Simplified versions of every helicopter part, for drawing them far away
+            let helicopter_lods = ModelLods::new((0..4).map(|i| &helicopter[i]), 3, 60.);
+            (helicopter_lods, helicopter.materials)
+        });
+        // An optional point cloud, e.g. a lidar scan, given as .xyz or .ply on the command line
+        let point_cloud_asset = point_cloud_path.map(|path| {
+            assets.load("point cloud", move || {
+                point_cloud::load(&path).unwrap_or_else(|e| panic!("{}", e))
+            })
+        });
+        let sky_asset = assets.load("sky", move || {
+            let sky = match skybox_path {
+                Some(path) => skybox::load(&path).unwrap_or_else(|e| panic!("{}", e)),
+                None => starfield(),
+            };
+            let environment = EnvironmentMaps::new(&sky);
+            (sky, environment)
+        });
+        let mut reported_finished = 0;
+        loop {
+            let progress = assets.progress();
+            if let Some((name, error)) = progress.failed.first() {
+                panic!("Failed to load {}: {}", name, error);
+            }
+            if progress.finished != reported_finished {
+                reported_finished = progress.finished;
+                println!("Loaded {}/{} assets", progress.finished, progress.total);
+            }
+            wait(&progress);
+            if progress.is_done() {
+                break;
+            }
+        }
+        let (lunar_surface, lunar_chunks, lunar_normal_map) = terrain_asset.expect();
+        let (sky, environment) = sky_asset.expect();
+        let (helicopter_lods, helicopter_materials) = helicopter_asset.expect();
+        SceneAssets {
+            lunar_surface,
+            lunar_chunks,
+            lunar_normal_map,
+            helicopter_lods,
+            helicopter_materials,
+            point_cloud: point_cloud_asset.map(|asset| asset.expect()),
+            sky,
+            environment,
+        }
+    }
+}
+
+/// A node holding the sun, which casts the shadows
+pub fn sun() -> Node {
+    let mut sun = SceneNode::new();
+    let mut light = Light::directional(glm::make_vec3(&SUN_DIRECTION), glm::vec3(1., 1., 1.));
+    light.casts_shadows = true;
+    sun.light = Some(light);
+    sun
+}
+
+/// A helicopter made of the given parts, with the rotors, the door and a searchlight as children
+/// of the body
+pub fn assemble_helicopter(mut body: Node, main_rotor: Node, tail_rotor: Node, door: Node) -> HelicopterStruct {
+    // Under the nose, pointing ahead and down at the ground
+    let mut searchlight = SceneNode::new();
+    searchlight.set_position(glm::vec3(0., 0.5, -3.));
+    searchlight.light = Some(Light::spot(
+        glm::vec3(0., -1., -0.8),
+        glm::vec3(1., 0.95, 0.8),
+        60.,
+        0.2,
+        0.35,
+    ));
+
+    body.add_child(&main_rotor);
+    body.add_child(&tail_rotor);
+    body.add_child(&door);
+    body.add_child(&searchlight);
+
+    HelicopterStruct { body, main_rotor, tail_rotor, door, searchlight }
+}
+
+/// Turns the rotors of the `i`th helicopter to where they are `elapsed` seconds in
+pub fn spin_rotors(heli: &mut HelicopterStruct, i: usize, elapsed: f32) {
+    heli.main_rotor.set_rotation(glm::vec3(
+        0.,
+        elapsed * (7. + (i as f32) * 0.5),
+        0.,
+    ));
+    heli.tail_rotor.set_rotation(glm::vec3(
+        elapsed * (5. - (i as f32) * 0.2),
+        0.,
+        0.,
+    ));
+}
+
+/// Sets up the OpenGL state every renderer of the scene expects, and prints what it runs on
+pub unsafe fn setup_gl() {
+    gl::Enable(gl::DEPTH_TEST);
+    gl::DepthFunc(gl::LESS);
+    gl::Enable(gl::CULL_FACE);
+    gl::Disable(gl::MULTISAMPLE);
+    gl::Enable(gl::BLEND);
+    gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+    gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+    gl::DebugMessageCallback(Some(util::debug_callback), ptr::null());
+    gl::Enable(gl::PROGRAM_POINT_SIZE);
+    // Filter across the edges of cube map faces, blurry environment levels show seams otherwise
+    gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+
+    // Print some diagnostics
+    println!(
+        "{}: {}",
+        util::get_gl_string(gl::VENDOR),
+        util::get_gl_string(gl::RENDERER)
+    );
+    println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
+    println!(
+        "GLSL\t: {}",
+        util::get_gl_string(gl::SHADING_LANGUAGE_VERSION)
+    );
+}
+
+/// The scene uploaded to the GPU, along with the shaders it is drawn with
+pub struct World {
+    pub assets: SceneAssets,
+    pub shader: Shader,
+    // Draws the depth of the scene from the light
+    pub shadow_shader: Shader,
+    // None if they couldn't be made, then nothing casts shadows
+    pub shadows: Option<ShadowMaps>,
+    pub lights: LightBuffer,
+    // Lights past this many are left out, see `light::select`
+    pub max_lights: usize,
+    // What the physically based materials are lit by besides the lights
+    pub environment: Environment,
+    pub skybox: Skybox,
+    // Set when the picture is post-processed, then colors are written linear and unclamped,
+    // see post.rs. Otherwise they are encoded for the screen.
+    pub linear_output: bool,
+    pub lunar_chunk_meshes: Vec<Vec<GpuMesh>>,
+    pub lunar_normal_map: u32,
+    pub helicopter_lod_meshes: Vec<Vec<GpuMesh>>,
+    // The uploaded materials of the helicopter parts and their normal maps
+    pub helicopter_materials: Vec<Option<(PbrMaterial, u32)>>,
+    pub point_cloud_mesh: Option<GpuMesh>,
+}
+
+impl World {
+    pub unsafe fn upload(assets: SceneAssets) -> Self {
+        //I personally think this was way to difficult to figure out...
+        let shader_builder = ShaderBuilder::new();
+        let shader_builder = shader_builder.attach_file("shaders/simple.vert");
+        let shader_builder = shader_builder.attach_file("shaders/simple.frag");
+        let shader = shader_builder.link();
+        let shadow_shader = ShaderBuilder::new()
+            .attach_file("shaders/shadow.vert")
+            .attach_file("shaders/shadow.frag")
+            .link();
+        let shadows = ShadowMaps::new(ShadowSettings::default())
+            .map_err(|e| println!("Warning: drawing without shadows: {}", e))
+            .ok();
+        let lights = LightBuffer::new();
+        let environment = Environment::new(&assets.environment);
+        let skybox = Skybox::new(&assets.sky);
+
+        let vertex_layout = VertexLayout::standard();
+        vertex_layout
+            .validate_against(&shader)
+            .unwrap_or_else(|e| panic!("Vertex layout doesn't match the shader:\n{}", e));
+
+        let lunar_normal_map = texture::upload_texture(&assets.lunar_normal_map);
+        //upload the meshes, one per level of detail for every terrain chunk
+        let lunar_chunk_meshes = assets
+            .lunar_chunks
+            .chunks
+            .iter()
+            .map(|chunk| chunk.lods.iter().map(|lod| GpuMesh::new(lod, &vertex_layout)).collect())
+            .collect();
+        let helicopter_lod_meshes = assets
+            .helicopter_lods
+            .parts
+            .iter()
+            .map(|chain| {
+                chain.levels.iter().map(|level| GpuMesh::new(level, &vertex_layout)).collect()
+            })
+            .collect();
+        let helicopter_materials = assets
+            .helicopter_materials
+            .iter()
+            .map(|material| material.as_ref().map(|material| material.upload()))
+            .collect();
+
+        // Point clouds can have millions of points, so they get a more compact layout
+        let point_cloud_layout = VertexLayout::point_cloud();
+        point_cloud_layout
+            .validate_against(&shader)
+            .unwrap_or_else(|e| panic!("Point cloud layout doesn't match the shader:\n{}", e));
+        let point_cloud_mesh = assets
+            .point_cloud
+            .as_ref()
+            .map(|points| GpuMesh::new(points, &point_cloud_layout));
+
+        World {
+            assets,
+            shader,
+            shadow_shader,
+            shadows,
+            lights,
+            max_lights: DEFAULT_MAX_LIGHTS,
+            environment,
+            linear_output: false,
+            skybox,
+            lunar_chunk_meshes,
+            lunar_normal_map,
+            helicopter_lod_meshes,
+            helicopter_materials,
+            point_cloud_mesh,
+        }
+    }
+
+    /// Maps the VAOs back to the meshes they were made from, for exporting the scene
+    pub fn mesh_for_vao(&self) -> HashMap<gl::types::GLuint, &Mesh> {
+        self.lunar_chunk_meshes
+            .iter()
+            .flatten()
+            .zip(self.assets.lunar_chunks.chunks.iter().flat_map(|chunk| &chunk.lods))
+            .chain(
+                self.helicopter_lod_meshes
+                    .iter()
+                    .flatten()
+                    .zip(self.assets.helicopter_lods.parts.iter().flat_map(|chain| &chain.levels)),
+            )
+            .chain(self.point_cloud_mesh.iter().zip(self.assets.point_cloud.iter()))
+            .map(|(gpu_mesh, mesh)| (gpu_mesh.vao, mesh))
+            .collect()
+    }
+
+    /// Whether the helicopter model was loaded
+    pub fn has_helicopters(&self) -> bool {
+        !self.helicopter_lod_meshes.is_empty()
+    }
+
+    /// A helicopter at the origin, with its rotors and door as children of the body. Only
+    /// when `has_helicopters`.
+    pub unsafe fn helicopter(&self) -> HelicopterStruct {
+        let part = |i: usize, reference_point: glm::Vec3| {
+            let mut node = SceneNode::from_mesh(&self.helicopter_lod_meshes[i][0], reference_point);
+            if let Some((pbr, normal_map)) = self.helicopter_materials[i] {
+                node.material = Material::physically_based(pbr);
+                node.normal_map = normal_map;
+            }
+            node
+        };
+        assemble_helicopter(
+            part(0, glm::vec3(0., 0., 0.)),
+            part(1, glm::vec3(0., 0., 0.)),
+            part(2, glm::vec3(0.35, 2.3, 10.4)),
+            part(3, glm::vec3(0., 0., 0.)),
+        )
+    }
+
+    /// Builds the scene graph as it looks `elapsed` seconds in, seen from `camera_position`,
+    /// with its transformations updated and ready to draw
+    pub unsafe fn build_scene(&self, elapsed: f32, camera_position: &glm::Vec3) -> Node {
+        let lunar_surface = &self.assets.lunar_surface;
+        let lunar_chunks = &self.assets.lunar_chunks;
+        let helicopter_lod_meshes = &self.helicopter_lod_meshes;
+        let mut root_scene = SceneNode::new();
+        root_scene.add_child(&sun());
+
+        // Pick the level of detail of each terrain chunk from its distance to the camera
+        for (chunk, meshes) in lunar_chunks.chunks.iter().zip(&self.lunar_chunk_meshes) {
+            let level = lunar_chunks.lod_for(chunk, camera_position);
+            let mut chunk_scene = SceneNode::from_mesh(&meshes[level], glm::vec3(0., 0., 0.));
+            chunk_scene.normal_map = self.lunar_normal_map;
+            root_scene.add_child(&chunk_scene);
+        }
+        if let Some(points) = &self.point_cloud_mesh {
+            let mut point_cloud_scene = SceneNode::from_mesh(points, glm::vec3(0., 0., 0.));
+            point_cloud_scene.point_size = 2.;
+            point_cloud_scene.point_attenuation = Some(50.);
+            root_scene.add_child(&point_cloud_scene);
+        }
+
+        let mut helicopters: Vec<HelicopterStruct> = Vec::new();
+        let helicopter_count = if self.has_helicopters() { HELICOPTER_COUNT } else { 0 };
+        for _ in 0..helicopter_count {
+            let heli = self.helicopter();
+            root_scene.add_child(&heli.body);
+            helicopters.push(heli);
+        }
+
+        for (i, heli) in helicopters.iter_mut().enumerate() {
+            let pos_var = 0.6 * elapsed + (2. * PI / (HELICOPTER_COUNT as f32)) * (i as f32);
+            let (x, z) = (40. * pos_var.cos(), -40. * pos_var.sin());
+            // Fly at a bobbing height above whatever ground is below
+            let ground = lunar_surface.height_at(x, z).unwrap_or(0.);
+            heli.body.set_position(glm::vec3(
+                x,
+                ground + 10. + 4. * (2. * pos_var + 0.841 * elapsed).sin(),
+                z,
+            ));
+            heli.body.set_rotation(glm::vec3(0., pos_var, 0.));
+            spin_rotors(heli, i, elapsed);
+
+            // Swap in the simpler versions of the parts as the helicopter gets further away
+            let distance = glm::distance(camera_position, &heli.body.position);
+            let level = self.assets.helicopter_lods.level_for(distance);
+            let mut parts = [
+                &mut heli.body,
+                &mut heli.main_rotor,
+                &mut heli.tail_rotor,
+                &mut heli.door,
+            ];
+            for (part, node) in parts.iter_mut().enumerate() {
+                node.mesh = &helicopter_lod_meshes[part][level];
+            }
+        }
+        update_node_transformations(
+            &mut root_scene,
+            &(glm::translation(&glm::vec3(0., 0., 0.))),
+        );
+        root_scene
+    }
+
+    /// Clears the bound framebuffer and draws the scene into it, lights, shadows, sky and all
+    pub unsafe fn draw(&self, root: &SceneNode, camera: &Camera) {
+        let program_id = self.shader.program_id;
+        let location = |name: &str| self.shader.get_uniform_location(name);
+
+        let mut lights = Vec::new();
+        light::collect_lights(root, &mut lights);
+        light::select(&mut lights, &camera.position, self.max_lights);
+        // Only one directional light gets shadow maps, the first that asks for them
+        let shadow_light = lights
+            .iter()
+            .position(|l| l.light.casts_shadows && l.light.kind == LightKind::Directional);
+
+        gl::UseProgram(program_id);
+        self.lights.upload(&lights, location("LightCount"));
+        gl::Uniform3fv(location("AmbientLight"), 1, AMBIENT_LIGHT.as_ptr());
+        self.environment.bind(&self.shader);
+        self.skybox.bind(&self.shader);
+        gl::Uniform1i(location("ShadowLight"), shadow_light.map_or(-1, |i| i as i32));
+        // The shadow sampler must never share a unit with the normal map sampler, even unused
+        gl::Uniform1i(location("ShadowMap"), SHADOW_MAP_UNIT as i32);
+        gl::Uniform1i(location("UseShadows"), 0);
+        gl::Uniform1i(location("LinearOutput"), self.linear_output as i32);
+        if let (Some(shadows), Some(i)) = (&self.shadows, shadow_light) {
+            // The shadow pass draws elsewhere, so remember where to come back to
+            let mut framebuffer = 0;
+            let mut viewport = [0; 4];
+            gl::GetIntegerv(gl::DRAW_FRAMEBUFFER_BINDING, &mut framebuffer);
+            gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
+
+            let towards_light = -lights[i].direction;
+            let matrices = shadows.fit(&camera.view, camera.aspect, FIELD_OF_VIEW, NEAR_PLANE, &towards_light);
+            shadows.render(&matrices, root, &camera.position, self.shadow_shader.program_id);
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer as u32);
+            gl::Viewport(viewport[0], viewport[1], viewport[2], viewport[3]);
+            gl::UseProgram(program_id);
+            shadows.bind(&matrices, &self.shader, SHADOW_MAP_UNIT);
+        }
+
+        gl::ClearColor(0.163, 0.163, 0.163, 1.0);
+        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        draw_scene(
+            root,
+            &(camera.projection() * camera.view),
+            &camera.position,
+            &program_id,
+        );
+        self.skybox.draw(camera, self.linear_output);
+    }
+}
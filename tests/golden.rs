@@ -0,0 +1,17 @@
+use std::process::Command;
+
+// Renders the reference scenes headlessly, which needs an EGL driver (Mesa's llvmpipe will do), so
+// it only runs when asked for with `cargo test -- --ignored`
+#[test]
+#[ignore]
+fn golden_images_match() {
+    let output = std::env::temp_dir().join(format!("gloom-golden-{}", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_gloom-rs"))
+        .args(["--golden", "--output"])
+        .arg(&output)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("failed to start gloom-rs");
+    assert!(status.success(), "golden image tests failed, see {}", output.join("golden").display());
+    std::fs::remove_dir_all(&output).ok();
+}